@@ -3,14 +3,15 @@ use std::{
     fmt::Display,
     fs,
     io::{BufRead, BufReader, Read, Write},
-    os::unix::net::UnixStream,
+    os::unix::{io::AsRawFd, net::UnixStream},
     path::Path,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
 fn main() {
-    let mut socket = "/tmp/d26run-socket".to_owned();
+    let mut socket = "/run/d26run/socket".to_owned();
+    let mut check_owner = true;
     let mut mode = None;
     let args: Vec<_> = {
         let mut args = std::env::args().skip(1);
@@ -22,6 +23,7 @@ fn main() {
                             .next()
                             .expect("--socket-path must be followed by another argument")
                     }
+                    "--skip-owner-check" => check_owner = false,
                     "--mode" => {
                         mode = Some(
                             match args
@@ -49,40 +51,149 @@ fn main() {
             }
         }
     };
-    if let Some(cmd) = args.get(0) {
+    if let Some(cmd) = args.first() {
+        let con = || match Con::init(&socket, check_owner) {
+            Ok(con) => con,
+            Err(e @ (ConInitErr::CouldNotGetPeerCredentials(_) | ConInitErr::ServerNotRoot(_))) => {
+                eprintln!("refusing to use socket '{socket}': {e:?}");
+                std::process::exit(5);
+            }
+            Err(e) => panic!("{e:?}"),
+        };
         let cmd = cmd.as_str();
         match cmd {
-            "run" => Con::init(socket)
-                .unwrap()
-                .run(
-                    args.get(1)
-                        .expect("run requires a second argument")
-                        .as_str(),
-                    args.iter().skip(2).filter_map(|v| v.split_once('=')),
-                    mode,
-                )
-                .unwrap(),
-            "reload" => Con::init(socket).unwrap().reload_configs(),
+            "run" => match con().run(
+                args.get(1)
+                    .expect("run requires a second argument")
+                    .as_str(),
+                args.iter().skip(2).filter_map(|v| v.split_once('=')),
+                mode,
+            ) {
+                Ok(()) => (),
+                Err(ConRunErr::LimitReached(limit)) => {
+                    eprintln!("the server refused to run this: {limit:?} reached.");
+                    std::process::exit(7);
+                }
+                Err(ConRunErr::SingletonRunning(session)) => {
+                    eprintln!("the server refused to run this: it is already running (session {session}).");
+                    std::process::exit(7);
+                }
+                Err(ConRunErr::AuthDenied(reason)) => {
+                    eprintln!("authentication failed: {reason}");
+                    std::process::exit(7);
+                }
+                Err(ConRunErr::ApprovalDenied) => {
+                    eprintln!("the server refused to run this: an admin denied the request.");
+                    std::process::exit(7);
+                }
+                Err(ConRunErr::ApprovalTimeout) => {
+                    eprintln!(
+                        "the server refused to run this: nobody approved the request in time."
+                    );
+                    std::process::exit(7);
+                }
+                Err(e) => panic!("{e:?}"),
+            },
+            "reload" => con().reload_configs(),
             "list" => {
-                let cfgs = Con::init(socket).unwrap().list();
+                let cfgs = con().list();
                 println!("configs: {}", cfgs.len());
                 for cfg in cfgs {
                     println!("{} ({})", cfg.0, cfg.1);
                 }
             }
+            "sessions" => {
+                let sessions = con().list_sessions();
+                println!("sessions: {}", sessions.len());
+                println!("id config uid pid connection");
+                for session in sessions {
+                    println!("{session}");
+                }
+            }
+            "kill" => {
+                let response = con().kill_session(
+                    args.get(1)
+                        .expect("kill requires a session id (see 'sessions')")
+                        .as_str(),
+                );
+                println!("{response}");
+            }
+            "pending" => {
+                let pending = con().list_pending();
+                println!("pending: {}", pending.len());
+                println!("id config uid connection");
+                for request in pending {
+                    println!("{request}");
+                }
+            }
+            cmd @ ("approve" | "deny") => {
+                let response = con().decide(
+                    cmd,
+                    args.get(1)
+                        .expect("approve/deny require a request id (see 'pending')")
+                        .as_str(),
+                );
+                println!("{response}");
+            }
+            "metrics" => {
+                for line in con().metrics() {
+                    println!("{line}");
+                }
+            }
+            "test" => {
+                let (result, messages) = con().test_config(
+                    args.get(1)
+                        .expect("test requires the name of a config")
+                        .as_str(),
+                );
+                for message in messages {
+                    println!("{message}");
+                }
+                println!("{result}");
+            }
             _ => eprintln!("unknown command, run without arguments for tldr."),
         }
     } else {
         eprintln!(
             "d26run-client tldr:
+    options: --socket <path> (default /run/d26run/socket), --skip-owner-check (allow servers not running as root), --mode <wait|detach|output|interactive>
     run <name> => run a config if permissions are sufficient (/etc/d26run/configs/<name>)
     reload => request that the server reloads all configurations. might not have an effect immedeately (rate limit)
     list => lists all available configs that can be used with 'run'.
+    sessions => lists running sessions (admin)
+    kill <id> => stops a running session (admin)
+    test <name> => loads and checks a config, showing all errors (admin)
+    pending => lists run requests waiting for approval (admin)
+    approve <id> / deny <id> => decides a pending run request (admin)
+    metrics => shows the server's metrics in the prometheus text format (admin)
 "
         )
     }
 }
 
+/// asks the user on the terminal (for PAM). if `echo` is false, the input isn't shown.
+fn prompt_user(text: &str, echo: bool) -> String {
+    eprint!("{text}");
+    _ = std::io::stderr().flush();
+    let stty = |arg| {
+        _ = std::process::Command::new("stty")
+            .arg(arg)
+            .stdin(std::process::Stdio::inherit())
+            .stderr(std::process::Stdio::null())
+            .status();
+    };
+    if !echo {
+        stty("-echo");
+    }
+    let mut input = String::new();
+    _ = std::io::stdin().read_line(&mut input);
+    if !echo {
+        stty("echo");
+        eprintln!();
+    }
+    input.trim_end_matches(['\n', '\r']).to_owned()
+}
+
 pub enum RunMode {
     Detach,
     Wait,
@@ -93,10 +204,55 @@ pub enum RunMode {
 #[derive(Debug)]
 pub enum ConInitErr {
     CouldNotConnectToSocket(std::io::Error),
+    CouldNotGetPeerCredentials(std::io::Error),
+    /// the server runs as root, so the process on the other end of the socket must be root.
+    /// otherwise, someone else could have created the socket to impersonate the server.
+    ServerNotRoot(u32),
 }
 #[derive(Debug)]
 pub enum ConRunErr {
     FailedToEditFileForAuth(String, std::io::Error),
+    /// `auth deny <reason>`, for example when PAM authentication failed
+    AuthDenied(String),
+    /// the server refused to start another session because of a limit in its config
+    LimitReached(Limit),
+    /// the config can only run once (at the same time), and this session is still running
+    SingletonRunning(String),
+    /// the config has `require-approval`, and an admin denied the request
+    ApprovalDenied,
+    /// the config has `require-approval`, and nobody decided in time
+    ApprovalTimeout,
+}
+/// the limits which can cause a `run error_limit_reached` (or `error_limit_reached` instead of the connection id)
+#[derive(Debug)]
+pub enum Limit {
+    /// max-sessions (server.conf)
+    Sessions,
+    /// max-sessions-per-user (server.conf)
+    SessionsPerUser,
+    /// max-instances (config)
+    Instances,
+    /// max-per-user (config)
+    InstancesPerUser,
+    /// max-connections (server.conf)
+    Connections,
+    /// max-runs-per-minute (server.conf)
+    RunsPerMinute,
+    /// a limit this client doesn't know about
+    Other(String),
+}
+impl Limit {
+    fn parse(s: &str) -> Self {
+        match s {
+            "max-sessions" => Self::Sessions,
+            "max-sessions-per-user" => Self::SessionsPerUser,
+            "max-instances" => Self::Instances,
+            "max-per-user" => Self::InstancesPerUser,
+            "max-connections" => Self::Connections,
+            "max-runs-per-minute" => Self::RunsPerMinute,
+            other => Self::Other(other.to_owned()),
+        }
+    }
 }
 
 impl Display for RunMode {
@@ -116,11 +272,42 @@ pub struct Con {
     client_dir: String,
 }
 impl Con {
-    pub fn init<P: AsRef<Path>>(addr: P) -> Result<Self, ConInitErr> {
+    /// the uid of the process that created the socket's listener, from SO_PEERCRED.
+    /// unlike the owner of the socket file, this can't change between checking and connecting.
+    fn peer_uid(stream: &UnixStream) -> Result<u32, ConInitErr> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            return Err(ConInitErr::CouldNotGetPeerCredentials(
+                std::io::Error::last_os_error(),
+            ));
+        }
+        Ok(cred.uid)
+    }
+    pub fn init<P: AsRef<Path>>(addr: P, check_owner: bool) -> Result<Self, ConInitErr> {
         let stream = match std::os::unix::net::UnixStream::connect(addr) {
             Ok(v) => v,
             Err(e) => return Err(ConInitErr::CouldNotConnectToSocket(e)),
         };
+        if check_owner {
+            let uid = Self::peer_uid(&stream)?;
+            if uid != 0 {
+                return Err(ConInitErr::ServerNotRoot(uid));
+            }
+        }
         let mut o = Self {
             stream: Arc::new(Mutex::new(BufReader::new(stream))),
             id: 0,
@@ -130,13 +317,21 @@ impl Con {
         Ok(o)
     }
     fn init_(&mut self) {
-        self.id = self.read_line().parse().unwrap();
+        let id = self.read_line();
+        if let Some(limit) = id.strip_prefix("error_limit_reached ") {
+            eprintln!(
+                "the server refused the connection: {:?} reached.",
+                Limit::parse(limit)
+            );
+            std::process::exit(7);
+        }
+        self.id = id.parse().unwrap();
         self.client_dir = format!("/tmp/d26run-client-{}/", self.id);
         eprintln!("{} -> {}", self.id, self.client_dir);
         // fs::create_dir(&self.client_dir).expect("failed: can't create client dir.");
     }
     /// write
-    fn w(&self) -> std::sync::MutexGuard<BufReader<UnixStream>> {
+    fn w(&self) -> std::sync::MutexGuard<'_, BufReader<UnixStream>> {
         let o = self.stream.lock().unwrap();
         o
     }
@@ -148,9 +343,18 @@ impl Con {
         }
         buf
     }
+    /// like read_line, but exits if the response says that the request isn't permitted on this socket.
+    fn read_response(&mut self) -> String {
+        let response = self.read_line();
+        if let Some(command) = response.strip_suffix(" error_not_permitted") {
+            eprintln!("'{command}' is not permitted on this socket.");
+            std::process::exit(6);
+        }
+        response
+    }
     pub fn list(&mut self) -> Vec<(String, String)> {
         writeln!(self.w().get_mut(), "list-configs").unwrap();
-        let response = self.read_line();
+        let response = self.read_response();
         assert!(response.starts_with("listing configs; count: "));
         let count = response["listing configs; count: ".len()..]
             .trim()
@@ -167,7 +371,77 @@ impl Con {
     pub fn reload_configs(&mut self) {
         // ask to reload
         writeln!(self.w().get_mut(), "reload-configs").unwrap();
-        assert_eq!("reload-configs requested", self.read_line().as_str());
+        assert_eq!("reload-configs requested", self.read_response().as_str());
+    }
+    /// one line per session: `id config uid pid connection`
+    pub fn list_sessions(&mut self) -> Vec<String> {
+        writeln!(self.w().get_mut(), "list-sessions").unwrap();
+        let response = self.read_response();
+        let count = response
+            .strip_prefix("listing sessions; count: ")
+            .and_then(|v| v.trim().parse().ok())
+            .expect("failed: list-sessions: unexpected response from server");
+        (0..count).map(|_| self.read_line()).collect()
+    }
+    pub fn kill_session(&mut self, id: &str) -> String {
+        writeln!(self.w().get_mut(), "kill-session {id}").unwrap();
+        let response = self.read_response();
+        match response.strip_prefix("kill-session ") {
+            Some("ok") => format!("killed session {id}."),
+            Some("unknown") => format!("there is no session {id}."),
+            Some("not_started") => format!("session {id} hasn't spawned its command yet."),
+            Some(err) => format!("couldn't kill session {id}: {err}"),
+            None => panic!("failed: kill-session: unexpected response from server: {response}"),
+        }
+    }
+    /// one line per request: `id config uid connection`
+    pub fn list_pending(&mut self) -> Vec<String> {
+        writeln!(self.w().get_mut(), "list-pending").unwrap();
+        let response = self.read_response();
+        let count = response
+            .strip_prefix("listing pending; count: ")
+            .and_then(|v| v.trim().parse().ok())
+            .expect("failed: list-pending: unexpected response from server");
+        (0..count).map(|_| self.read_line()).collect()
+    }
+    /// `command` is `approve` or `deny`
+    pub fn decide(&mut self, command: &str, id: &str) -> String {
+        writeln!(self.w().get_mut(), "{command} {id}").unwrap();
+        let response = self.read_response();
+        match response.strip_prefix(command).map(str::trim_start) {
+            Some("ok") if command == "approve" => format!("approved request {id}."),
+            Some("ok") => format!("denied request {id}."),
+            Some("unknown") => format!("there is no pending request {id}."),
+            _ => panic!("failed: {command}: unexpected response from server: {response}"),
+        }
+    }
+    pub fn metrics(&mut self) -> Vec<String> {
+        writeln!(self.w().get_mut(), "metrics").unwrap();
+        let response = self.read_response();
+        let count = response
+            .strip_prefix("metrics; lines: ")
+            .and_then(|v| v.trim().parse().ok())
+            .expect("failed: metrics: unexpected response from server");
+        (0..count).map(|_| self.read_line()).collect()
+    }
+    /// returns the result (ok/fatal/...) and all messages
+    pub fn test_config(&mut self, name: &str) -> (String, Vec<String>) {
+        writeln!(self.w().get_mut(), "test-config {name}").unwrap();
+        let response = self.read_response();
+        let response = response
+            .strip_prefix("test-config ")
+            .expect("failed: test-config: unexpected response from server");
+        if let Some((result, count)) = response.split_once("; count: ") {
+            let count = count.trim().parse().expect(
+                "failed: test-config: server returned count that couldn't be parsed to an int...",
+            );
+            (
+                result.to_owned(),
+                (0..count).map(|_| self.read_line()).collect(),
+            )
+        } else {
+            (response.to_owned(), vec![])
+        }
     }
     pub fn run<'a, V>(
         &mut self,
@@ -190,28 +464,56 @@ impl Con {
         } else {
             writeln!(self.w().get_mut(), "run {config}").unwrap();
         }
-        // wait until auth is ready
-        let auth_line_start = "auth wait ";
-        let auth_line = self.read_line();
-        let auth_line = auth_line.as_str();
-        if !auth_line.starts_with(auth_line_start) {
-            panic!("expected 'auth wait <file>', got '{auth_line}'");
+        // authenticate (via file permissions and/or PAM), until the server accepts or denies
+        let mut auth_line = self.read_response();
+        loop {
+            if let Some(path) = auth_line.strip_prefix("auth wait ") {
+                match fs::write(path, "auth") {
+                    Ok(_) => (),
+                    Err(e) => return Err(ConRunErr::FailedToEditFileForAuth(path.to_owned(), e)),
+                };
+                writeln!(self.w().get_mut(), "auth done").unwrap();
+            } else if let Some(prompt) = auth_line.strip_prefix("pam-prompt ") {
+                let (echo, text) = prompt.split_once(' ').unwrap_or((prompt, ""));
+                let response = prompt_user(&text.replace("\\n", "\n"), echo != "echo-off");
+                writeln!(self.w().get_mut(), "pam-response {response}").unwrap();
+            } else if let Some(text) = auth_line
+                .strip_prefix("pam-info ")
+                .or_else(|| auth_line.strip_prefix("pam-error "))
+            {
+                eprintln!("{}", text.replace("\\n", "\n"));
+            } else if auth_line == "auth accept" {
+                break;
+            } else if let Some(reason) = auth_line.strip_prefix("auth deny ") {
+                return Err(ConRunErr::AuthDenied(reason.replace("\\n", "\n")));
+            } else {
+                panic!(
+                    "expected 'auth wait <file>', 'pam-...' or 'auth accept', got '{auth_line}'"
+                );
+            }
+            auth_line = self.read_line();
         }
-        let path = &auth_line[auth_line_start.len()..];
-        // authenticate (via file permissions)
-        match fs::write(path, "auth") {
-            Ok(_) => (),
-            Err(e) => return Err(ConRunErr::FailedToEditFileForAuth(path.to_owned(), e)),
-        };
-        writeln!(self.w().get_mut(), "auth done").unwrap();
-        // wait for confirmation
-        assert_eq!("auth accept", self.read_line().as_str());
         // confirm that it was started
-        match self.read_line().as_str() {
+        let mut line = self.read_line();
+        if let Some(request) = line.strip_prefix("run pending ") {
+            eprintln!("waiting for an admin to approve this (request {request})...");
+            line = self.read_line();
+        }
+        match line.as_str() {
             "run start" => (),
+            // a singleton config which is already running
+            line if line.starts_with("run attached ") => {
+                eprintln!(
+                    "attached to running session {}.",
+                    &line["run attached ".len()..]
+                );
+            }
+            line if line.starts_with("run reused ") => {
+                eprintln!("reused running session {}.", &line["run reused ".len()..]);
+            }
             err => {
-                if err.starts_with("run error_invalid_config: ") {
-                    let err_count = err["run error_invalid_config: ".len()..]
+                if let Some(err_count) = err.strip_prefix("run error_invalid_config: ") {
+                    let err_count = err_count
                         .trim()
                         .parse()
                         .expect("failed: error_invalid_config: server returned count that couldn't be parsed to an int...");
@@ -222,15 +524,19 @@ impl Con {
                         }
                     }
                     panic!("couldn't run - there were {} errors.", err_count);
+                } else if let Some(limit) = err.strip_prefix("run error_limit_reached ") {
+                    return Err(ConRunErr::LimitReached(Limit::parse(limit)));
+                } else if let Some(session) = err.strip_prefix("run error_singleton_running ") {
+                    return Err(ConRunErr::SingletonRunning(session.to_owned()));
+                } else if err == "run error_approval_denied" {
+                    return Err(ConRunErr::ApprovalDenied);
+                } else if err == "run error_approval_timeout" {
+                    return Err(ConRunErr::ApprovalTimeout);
                 }
             }
         }
         // forward stdin
-        let fwd_stdin = if let Some(RunMode::ForwardInputOutput) = &mode {
-            true
-        } else {
-            false
-        };
+        let fwd_stdin = matches!(&mode, Some(RunMode::ForwardInputOutput));
         std::thread::scope(move |s| {
             if fwd_stdin {
                 s.spawn(|| {
@@ -270,9 +576,9 @@ impl Con {
                     self.w().read_exact(&mut buf[..]).unwrap();
                 }
                 if stderr {
-                    std::io::stderr().write(&buf[..]).unwrap();
+                    std::io::stderr().write_all(&buf[..]).unwrap();
                 } else {
-                    std::io::stdout().write(&buf[..]).unwrap();
+                    std::io::stdout().write_all(&buf[..]).unwrap();
                 }
             }
             self.w()
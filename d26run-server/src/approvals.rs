@@ -0,0 +1,167 @@
+//! run requests for configs with `require-approval` wait here until an admin approves or denies them
+//! (`approve <id>` / `deny <id>`), or until they time out.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::logging::debug;
+
+pub struct Pending {
+    pub id: u64,
+    /// the id of the connection which is waiting
+    pub con_id: u128,
+    pub config: String,
+    /// uid of the client, if known
+    pub uid: Option<u32>,
+    /// set by `decide`
+    decision: Option<bool>,
+}
+
+#[derive(Default)]
+pub struct Approvals {
+    pending: Mutex<Vec<Pending>>,
+    /// notified whenever a decision is made
+    decided: Condvar,
+    next_id: AtomicU64,
+}
+
+pub enum Decision {
+    Approved,
+    Denied,
+    TimedOut,
+}
+
+impl Approvals {
+    /// Registers a pending request and returns its id. Must be followed by `wait`.
+    pub fn request(&self, con_id: u128, config: &str, uid: Option<u32>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!(con = con_id, request = id; "waiting for approval ({config}).");
+        self.pending.lock().unwrap().push(Pending {
+            id,
+            con_id,
+            config: config.to_owned(),
+            uid,
+            decision: None,
+        });
+        id
+    }
+    /// Blocks until the request was approved or denied, or `timeout` has passed. Then removes the request.
+    pub fn wait(&self, id: u64, timeout: Duration) -> Decision {
+        let end = Instant::now() + timeout;
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let i = pending.iter().position(|p| p.id == id);
+            let decision = i.and_then(|i| pending[i].decision);
+            let now = Instant::now();
+            if decision.is_some() || now >= end {
+                if let Some(i) = i {
+                    pending.remove(i);
+                }
+                return match decision {
+                    Some(true) => Decision::Approved,
+                    Some(false) => Decision::Denied,
+                    None => Decision::TimedOut,
+                };
+            }
+            pending = self.decided.wait_timeout(pending, end - now).unwrap().0;
+        }
+    }
+    /// Approves or denies a pending request. Returns false if there is no such request.
+    pub fn decide(&self, id: u64, approve: bool) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending
+            .iter_mut()
+            .find(|p| p.id == id && p.decision.is_none())
+        {
+            Some(p) => {
+                p.decision = Some(approve);
+                self.decided.notify_all();
+                true
+            }
+            None => false,
+        }
+    }
+    /// calls `f` for each request which is still waiting for a decision
+    pub fn for_each(&self, mut f: impl FnMut(&Pending)) {
+        for p in self.pending.lock().unwrap().iter() {
+            if p.decision.is_none() {
+                f(p);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+
+    fn count(approvals: &Approvals) -> usize {
+        let mut count = 0;
+        approvals.for_each(|_| count += 1);
+        count
+    }
+
+    #[test]
+    fn decisions_wake_the_waiting_request() {
+        let approvals = Arc::new(Approvals::default());
+        for approve in [true, false] {
+            let id = approvals.request(0, "a", Some(1000));
+            let waiter = {
+                let approvals = Arc::clone(&approvals);
+                thread::spawn(move || approvals.wait(id, Duration::from_secs(60)))
+            };
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(count(&approvals), 1);
+            assert!(approvals.decide(id, approve));
+            // a request can only be decided once
+            assert!(!approvals.decide(id, !approve));
+            let decision = waiter.join().unwrap();
+            assert!(match decision {
+                Decision::Approved => approve,
+                Decision::Denied => !approve,
+                Decision::TimedOut => false,
+            });
+            assert_eq!(count(&approvals), 0);
+            assert!(!approvals.decide(id, approve));
+        }
+    }
+
+    #[test]
+    fn requests_time_out() {
+        let approvals = Approvals::default();
+        let id = approvals.request(0, "a", None);
+        let other = approvals.request(0, "b", None);
+        let start = Instant::now();
+        assert!(matches!(
+            approvals.wait(id, Duration::from_millis(100)),
+            Decision::TimedOut
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // deciding after the timeout doesn't do anything
+        assert!(!approvals.decide(id, true));
+        // deciding another request doesn't end the wait early
+        let approvals = Arc::new(approvals);
+        let decider = {
+            let approvals = Arc::clone(&approvals);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                approvals.decide(other, true)
+            })
+        };
+        let id = approvals.request(0, "a", None);
+        let start = Instant::now();
+        assert!(matches!(
+            approvals.wait(id, Duration::from_millis(100)),
+            Decision::TimedOut
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(decider.join().unwrap());
+    }
+}
@@ -0,0 +1,251 @@
+//! the audit log: one line per request, like `seq=3 prev=<hash> time=... command=run ... hash=<hash>`.
+//! each line's hash covers everything before ` hash=`, including the previous line's hash,
+//! so removing or editing a record breaks the chain (see `verify`).
+//! removing the last records doesn't, so each record's seq and hash also go to the server's log,
+//! which `verify`'s result can be compared with.
+
+use std::{
+    fmt::{Display, Write as _},
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    process::ExitStatus,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::logging::{error, info};
+
+/// `prev` of the first record
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub struct AuditLog {
+    /// file, seq and hash of the last record
+    state: Mutex<(fs::File, u64, String)>,
+}
+
+impl AuditLog {
+    /// Opens (or creates) the audit log. If it already contains records, the chain is continued.
+    pub fn open(path: &str) -> io::Result<Self> {
+        let (seq, prev) = match fs::read_to_string(path) {
+            Ok(file) => match file.lines().rev().find(|l| !l.is_empty()) {
+                Some(last) => {
+                    let (seq, _, hash) = split_record(last).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("the last line of {path} is not a valid record"),
+                        )
+                    })?;
+                    (seq, hash.to_owned())
+                }
+                None => (0, GENESIS.to_owned()),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (0, GENESIS.to_owned()),
+            Err(e) => return Err(e),
+        };
+        // the records contain var values, other users shouldn't be able to read them
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Self {
+            state: Mutex::new((file, seq, prev)),
+        })
+    }
+    fn append(&self, fields: &[(String, String)]) {
+        let mut state = self.state.lock().unwrap();
+        let (file, seq, prev) = &mut *state;
+        let mut line = format!("seq={} prev={prev}", *seq + 1);
+        for (key, val) in fields {
+            _ = write!(line, " {}={}", quote_key(key), quote(val));
+        }
+        let hash = hash(&line);
+        if let Err(e) = writeln!(file, "{line} hash={hash}") {
+            error!("couldn't write to the audit log: {e}");
+            return;
+        }
+        *seq += 1;
+        info!("audit: seq={seq} hash={hash}");
+        *prev = hash;
+    }
+}
+
+/// Collects the fields of one request, which are appended to the audit log once this is dropped.
+pub struct Record {
+    log: Option<Arc<AuditLog>>,
+    fields: Vec<(String, String)>,
+}
+impl Record {
+    /// if `log` is None, nothing will be recorded
+    pub fn new(log: Option<&Arc<AuditLog>>) -> Self {
+        let mut record = Self {
+            log: log.cloned(),
+            fields: vec![],
+        };
+        record.set("time", now());
+        record
+    }
+    pub fn set(&mut self, key: impl Into<String>, val: impl Display) {
+        if self.log.is_some() {
+            self.fields.push((key.into(), val.to_string()));
+        }
+    }
+    pub fn set_exit(&mut self, status: Option<ExitStatus>) {
+        use std::os::unix::process::ExitStatusExt;
+        match status {
+            Some(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => self.set("exit", code),
+                (None, Some(signal)) => self.set("signal", signal),
+                (None, None) => self.set("exit", "-"),
+            },
+            None => self.set("exit", "-"),
+        }
+    }
+}
+impl Drop for Record {
+    fn drop(&mut self) {
+        if self.log.is_some() {
+            self.set("end", now());
+        }
+        if let Some(log) = &self.log {
+            log.append(&self.fields);
+        }
+    }
+}
+
+/// Checks the hash chain of an audit log. Returns the number of records and the last hash, or a description of the first problem.
+pub fn verify(path: &str) -> Result<(u64, String), String> {
+    let file = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let mut expected_seq = 1;
+    let mut prev_hash = GENESIS.to_owned();
+    for (line_nr, line) in file.lines().enumerate() {
+        let line_nr = line_nr + 1;
+        if line.is_empty() {
+            continue;
+        }
+        let (seq, prev, stored_hash) =
+            split_record(line).ok_or_else(|| format!("line {line_nr}: not a valid record"))?;
+        if seq != expected_seq {
+            return Err(format!(
+                "line {line_nr}: expected record {expected_seq}, found {seq} (records were removed or reordered)"
+            ));
+        }
+        if prev != prev_hash {
+            return Err(format!(
+                "line {line_nr}: prev doesn't match the hash of the previous record (records were removed or edited)"
+            ));
+        }
+        let body = &line[..line.len() - " hash=".len() - stored_hash.len()];
+        if hash(body) != stored_hash {
+            return Err(format!(
+                "line {line_nr}: hash doesn't match (this record was edited)"
+            ));
+        }
+        expected_seq += 1;
+        prev_hash = stored_hash.to_owned();
+    }
+    Ok((expected_seq - 1, prev_hash))
+}
+
+/// (seq, prev, hash)
+fn split_record(line: &str) -> Option<(u64, &str, &str)> {
+    let (body, hash) = line.rsplit_once(" hash=")?;
+    let mut words = body.split(' ');
+    let seq = words.next()?.strip_prefix("seq=")?.parse().ok()?;
+    let prev = words.next()?.strip_prefix("prev=")?;
+    Some((seq, prev, hash))
+}
+
+fn hash(s: &str) -> String {
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, b| {
+            _ = write!(hex, "{b:02x}");
+            hex
+        })
+}
+
+/// values which are empty or contain spaces, quotes, backslashes or newlines are quoted, like `"a b\n"`.
+fn quote(val: &str) -> String {
+    if !val.is_empty()
+        && !val
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\\')
+    {
+        val.to_owned()
+    } else {
+        quoted(val)
+    }
+}
+/// like values, and also if they contain `=` (`var.<name>` comes from the client)
+fn quote_key(key: &str) -> String {
+    if key.contains('=') {
+        quoted(key)
+    } else {
+        quote(key)
+    }
+}
+fn quoted(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// unix time, with milliseconds
+fn now() -> String {
+    let t = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!("{}.{:03}", t.as_secs(), t.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn keys_cant_add_fields() {
+        assert_eq!(quote_key("var.URL"), "var.URL");
+        assert_eq!(quote_key("var.a=1"), "\"var.a=1\"");
+        assert_eq!(quote_key("var.a\tresult=ok"), "\"var.a\\tresult=ok\"");
+        assert_eq!(quote("x result=ok"), "\"x result=ok\"");
+    }
+
+    #[test]
+    fn verify_returns_the_last_hash() {
+        let path = std::env::temp_dir().join(format!("d26run-audit-test-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        _ = fs::remove_file(path);
+        let log = Arc::new(AuditLog::open(path).unwrap());
+        for name in ["a", "b=c d"] {
+            let mut record = Record::new(Some(&log));
+            record.set(format!("var.{name}"), "x");
+        }
+        let file = fs::read_to_string(path).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        let result = verify(path);
+        fs::remove_file(path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+        let last = file.lines().last().unwrap();
+        assert!(last.contains(" \"var.b=c d\"=x "), "{last}");
+        let (count, hash) = result.unwrap();
+        assert_eq!(count, 2);
+        assert!(last.ends_with(&format!(" hash={hash}")));
+    }
+}
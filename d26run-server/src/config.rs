@@ -1,235 +1,827 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, path::Path, time::Duration};
 
 use crate::{
-    run::{RunCmdBuilder, ToRunCmdInfo, VarValue},
-    DIR_CONFIGS,
+    diagnostics::{has_errors, Diagnostic, Position, Severity},
+    formats::{self, Format},
+    input::InputType,
+    logging::{info, warn},
+    run::{
+        CmdOptions, Condition, Conditional, GroupSpec, RunCmdBuilder, Singleton, ToRunCmdInfo,
+        VarSyntax, VarValue,
+    },
+    settings::Settings,
 };
 
+/// for `require-approval` without a timeout
+const DEFAULT_APPROVAL_TIMEOUT: Duration = Duration::from_secs(300);
+/// statements which can be used in `if` blocks (the others can't depend on the client's input)
+const ALLOWED_IN_IF: &[&str] = &[
+    "config",
+    "use",
+    "template",
+    "param",
+    "if",
+    "cmd-prep",
+    "cmd-clean",
+    "cmd-reuse",
+    "command",
+    "arg",
+    "args",
+    "uid",
+    "user",
+    "gid",
+    "group",
+    "g+gid",
+    "g+group",
+    "g+from-user",
+    "env+set",
+    "env+inherit",
+    "working-dir",
+];
+
 pub struct Config {
-    /// a set of configs loaded from DIR_CONFIGS
+    /// a set of configs loaded from the configs-dir
     pub run_cmds: HashMap<String, RunCmdBuilder>,
+    /// how many files were skipped due to errors
+    pub skipped: usize,
 }
-pub fn init() -> Config {
-    Config {
-        run_cmds: if let Ok(dir) = fs::read_dir(DIR_CONFIGS) {
-            eprintln!("[INFO] now loading run_cmds from '{DIR_CONFIGS}'.");
-            let mut run_cmds = HashMap::new();
-            for entry in dir {
-                if let Ok(e) = entry {
-                    let file_name = e.file_name();
-                    if let Ok(file_type) = e.file_type() {
-                        if file_type.is_file() {
-                            if let Some(name) = file_name.to_str() {
-                                eprintln!("[INFO] Now parsing {name}.");
-                                let mut runcmd = RunCmdBuilder::default();
-                                if let Err(err) = runcmd_from_rel_file(name, &mut runcmd) {
-                                    eprintln!(
-                                        "[WARN] Skipping file '{}' due to parse error: {err:?}",
-                                        name
-                                    )
-                                } else {
-                                    let (non_fatal, out) =
-                                        runcmd.verify(&ToRunCmdInfo { con_id: 0 });
-                                    for e in non_fatal {
-                                        eprintln!("[INFO]     non-fatal: {e}");
+pub fn init(settings: &Settings) -> Config {
+    let configs_dir = settings.configs_dir.as_str();
+    let mut skipped = 0;
+    let run_cmds = if let Ok(dir) = fs::read_dir(configs_dir) {
+        info!("now loading run_cmds from '{configs_dir}'.");
+        let mut run_cmds = HashMap::new();
+        // `firefox` before `firefox.toml` before `firefox.json` (like `formats::find`), the others are skipped
+        let mut entries: Vec<_> = dir.collect();
+        entries.sort_by_key(|e| {
+            e.as_ref().ok().map(|e| {
+                let file_name = e.file_name().to_string_lossy().into_owned();
+                let format = Format::of(Path::new(&file_name));
+                (config_name(&file_name).to_owned(), format)
+            })
+        });
+        for entry in entries {
+            if let Ok(e) = entry {
+                let file_name = e.file_name();
+                if let Ok(file_type) = e.file_type() {
+                    if file_type.is_file() {
+                        if let Some(file_name) = file_name.to_str() {
+                            info!("Now parsing {file_name}.");
+                            let name = config_name(file_name);
+                            if run_cmds.contains_key(name) {
+                                warn!("Skipping file '{file_name}', there already is a config named '{name}'.");
+                                skipped += 1;
+                                continue;
+                            }
+                            let mut runcmd = RunCmdBuilder::default();
+                            let diagnostics =
+                                runcmd_from_rel_file(configs_dir, file_name, &mut runcmd);
+                            for d in &diagnostics {
+                                match d.severity {
+                                    Severity::Error => warn!("    {}", d.summary()),
+                                    Severity::Warning => info!("    {}", d.summary()),
+                                }
+                            }
+                            if has_errors(&diagnostics) {
+                                warn!("Skipping file '{}' due to parse errors.", name);
+                                skipped += 1;
+                            } else if runcmd.include_only {
+                                info!("   - {name} is include-only");
+                            } else {
+                                let (non_fatal, out) = runcmd.verify(&ToRunCmdInfo {
+                                    con_id: 0,
+                                    var_syntax: settings.default_var_syntax,
+                                    client_uid: None,
+                                    client_pid: None,
+                                });
+                                for e in non_fatal {
+                                    info!("    non-fatal: {e}");
+                                }
+                                match out {
+                                    Ok(()) => {
+                                        info!("   + added run_cmd {name}");
+                                        run_cmds.insert(name.to_owned(), runcmd);
                                     }
-                                    match out {
-                                        Ok(()) => {
-                                            eprintln!("[INFO]    + added run_cmd {name}");
-                                            run_cmds.insert(name.to_owned(), runcmd);
-                                        }
-                                        Err(err) => {
-                                            for e in err {
-                                                eprintln!("[INFO]     ! fatal !: {e}");
-                                            }
-                                            eprintln!(
-                                                "[WARN] Skipping file '{}' due to error.",
-                                                name
-                                            )
+                                    Err(err) => {
+                                        for e in err {
+                                            info!("    ! fatal !: {e}");
                                         }
+                                        warn!("Skipping file '{}' due to error.", name);
+                                        skipped += 1;
                                     }
                                 }
-                            } else {
-                                eprintln!(
-                                    "[WARN] Skipping file with invalid name: '{}'.",
-                                    e.file_name().to_string_lossy()
-                                );
                             }
+                        } else {
+                            warn!(
+                                "Skipping file with invalid name: '{}'.",
+                                e.file_name().to_string_lossy()
+                            );
                         }
-                    } else {
-                        eprintln!(
-                            "[WARN] Couldn't get file type for DIR_CONFIGS entry '{}', skipping.",
-                            file_name.to_string_lossy()
-                        );
                     }
                 } else {
-                    eprintln!("[WARN] Couldn't read an entry in DIR_CONFIGS (might skip a file?).",);
+                    warn!(
+                        "Couldn't get file type for configs-dir entry '{}', skipping.",
+                        file_name.to_string_lossy()
+                    );
                 }
+            } else {
+                warn!("Couldn't read an entry in configs-dir (might skip a file?).",);
             }
-            eprintln!("[INFO] Loaded {} run_cmds.", run_cmds.len());
-            run_cmds
-        } else {
-            eprintln!(
-                "[WARN] couldn't read directory '{DIR_CONFIGS}', so no configs will be loaded!"
-            );
-            HashMap::new()
-        },
+        }
+        info!("Loaded {} run_cmds.", run_cmds.len());
+        run_cmds
+    } else {
+        warn!("couldn't read directory '{configs_dir}', so no configs will be loaded!");
+        HashMap::new()
+    };
+    Config { run_cmds, skipped }
+}
+
+/// `firefox.toml` is run as `firefox`
+fn config_name(file_name: &str) -> &str {
+    match Format::of(Path::new(file_name)) {
+        Format::Lines => file_name,
+        _ => file_name.rsplit_once('.').map_or(file_name, |(n, _)| n),
     }
 }
 
+/// a file directly in the configs-dir (what clients may ask for): not empty, no `/`, not `.` or `..`
+pub fn is_config_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && name != "." && name != ".."
+}
+
+/// `config <name>` statements are relative to the file containing them.
+/// The config is only usable if none of the returned diagnostics are errors.
 pub fn runcmd_from_rel_file(
+    configs_dir: &str,
     name: &str,
     config: &mut RunCmdBuilder,
-) -> Result<(), ConfigFromFileError> {
-    let file = std::fs::read_to_string(format!("{DIR_CONFIGS}{name}"))?;
-    runcmd_from_lines(config, &mut file.lines().map(|v| v.to_owned()))
+) -> Vec<Diagnostic> {
+    runcmd_from_abs_file(&formats::find(Path::new(configs_dir).join(name)), config)
 }
 pub fn runcmd_from_abs_file(
-    path: &impl AsRef<std::path::Path>,
+    path: &impl AsRef<Path>,
     config: &mut RunCmdBuilder,
-) -> Result<(), ConfigFromFileError> {
-    let file = std::fs::read_to_string(path)?;
-    runcmd_from_lines(config, &mut file.lines().map(|v| v.to_owned()))
+) -> Vec<Diagnostic> {
+    let mut parser = Parser {
+        path: path.as_ref().to_string_lossy().into_owned(),
+        statements: vec![],
+        included_from: vec![],
+        params: None,
+        in_if: 0,
+        diagnostics: vec![],
+    };
+    let file = fs::read_to_string(path)
+        .map_err(ConfigFromFileError::IoError)
+        .and_then(|file| {
+            formats::to_lines(&file, Format::of(path.as_ref()))
+                .map_err(ConfigFromFileError::InvalidFormat)
+        });
+    match file {
+        Ok((file, statements)) => {
+            parser.statements = statements;
+            let end = runcmd_from_lines(config, &mut file.lines().enumerate(), &mut parser);
+            outside_if(end, &mut parser);
+        }
+        Err(e) => parser.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            message: e.to_string(),
+            path: parser.path.clone(),
+            position: None,
+            included_from: vec![],
+        }),
+    }
+    parser.diagnostics
+}
+
+/// the file which is being parsed, and what was found so far
+struct Parser {
+    path: String,
+    /// TOML/JSON: which statement each line came from (see `formats::to_lines`)
+    statements: Vec<String>,
+    /// the `config` statements which led to `path` (path, where in it), innermost first
+    included_from: Vec<(String, String)>,
+    /// Some if `path` is included by `use`
+    params: Option<Params>,
+    /// how many `if` blocks the current line is in (not counting those outside of the current cmd-prep/...)
+    in_if: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// the parameters of a template included by `use <name> a=b ...`
+struct Params {
+    /// from the `use` statement
+    given: HashMap<String, String>,
+    /// the `param`s declared so far, with their values
+    values: HashMap<String, String>,
+    /// declared without a default, but not given
+    missing: Vec<String>,
+    /// the file contains `template` (otherwise `@{...}` isn't replaced)
+    is_template: bool,
+}
+impl Params {
+    /// replaces `@{name}` with the parameter's value. Err: (byte offset, error)
+    fn substitute(&self, line: &str) -> Result<String, (usize, ConfigFromFileError)> {
+        let mut out = String::new();
+        let mut rest = line;
+        while let Some(start) = rest.find("@{") {
+            let offset = line.len() - rest.len() + start;
+            let Some(len) = rest[start..].find('}') else {
+                return Err((
+                    offset,
+                    ConfigFromFileError::UnknownParameter(rest[start..].to_owned()),
+                ));
+            };
+            let name = &rest[start + 2..start + len];
+            let value = self.values.get(name).ok_or_else(|| {
+                (
+                    offset,
+                    ConfigFromFileError::UnknownParameter(name.to_owned()),
+                )
+            })?;
+            out.push_str(&rest[..start]);
+            out.push_str(value);
+            rest = &rest[start + len + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
 }
-pub fn runcmd_from_lines<L: Iterator<Item = String>>(
+
+impl Parser {
+    /// `offset` is the byte offset of the problem in `source`
+    fn push(
+        &mut self,
+        severity: Severity,
+        message: String,
+        line_nr: usize,
+        source: &str,
+        offset: usize,
+    ) {
+        let (message, position) = match self.statements.get(line_nr - 1) {
+            Some(statement) => (format!("{message} (in {statement})"), None),
+            None => (message, Some(Position::new(line_nr, source, offset))),
+        };
+        self.diagnostics.push(Diagnostic {
+            severity,
+            code: None,
+            message,
+            path: self.path.clone(),
+            position,
+            included_from: self
+                .included_from
+                .iter()
+                .map(|(path, location)| format!("{path}{location}"))
+                .collect(),
+        });
+    }
+    /// `:<line>`, or ` (<statement>)` for TOML/JSON
+    fn location(&self, line_nr: usize) -> String {
+        match self.statements.get(line_nr - 1) {
+            Some(statement) => format!(" ({statement})"),
+            None => format!(":{line_nr}"),
+        }
+    }
+}
+
+/// parses statements until the end of the file or an `end` (of a cmd-prep/... block), `elif`, `else` or `endif`.
+/// Returns the line number and line which ended it (None at the end of the file).
+fn runcmd_from_lines<'l, L: Iterator<Item = (usize, &'l str)>>(
     config: &mut RunCmdBuilder,
     lines: &mut L,
-) -> Result<(), ConfigFromFileError> {
-    loop {
-        let line = if let Some(l) = lines.next() {
-            l
-        } else {
-            break;
-        };
-        let (left, right) = if let Some((left, right)) = line.split_once(' ') {
-            (left, right)
-        } else {
-            (line.as_str(), "")
+    parser: &mut Parser,
+) -> Option<(usize, String)> {
+    while let Some((line_nr, line)) = lines.next() {
+        let line_nr = line_nr + 1;
+        // the diagnostics show the line with the parameters replaced, that's what is parsed
+        let substituted;
+        let line = match parser.params.as_ref().filter(|p| p.is_template) {
+            Some(params) => match params.substitute(line) {
+                Ok(l) => {
+                    substituted = l;
+                    substituted.as_str()
+                }
+                Err((offset, e)) => {
+                    parser.push(Severity::Error, e.to_string(), line_nr, line, offset);
+                    continue;
+                }
+            },
+            None => line,
         };
+        // statements can be indented (offsets are still into the whole line)
+        let indent = indent(line);
+        let (left, right, right_offset) =
+            if let Some((left, right)) = line[indent..].split_once(' ') {
+                (left, right, indent + left.len() + 1)
+            } else {
+                (&line[indent..], "", line.len())
+            };
         match left {
-            // comments or empty lines
-            s if s.is_empty() || s.starts_with('#') || s.starts_with("//") => (),
-            "end" => break,
-            "config" => {
-                runcmd_from_rel_file(right, config)?;
-            }
-            "var" => {
-                if let Some((name, value)) = right.split_once(' ') {
-                    fn get_var_val(name: &str, value: &str) -> Option<VarValue> {
-                        let (mode, value) = if let Some(v) = value.split_once(' ') {
-                            v
-                        } else {
-                            (value, "")
-                        };
-                        match mode {
-                            "set" => Some(VarValue::Val(value.to_owned())),
-                            "from-cmd" => Some(VarValue::OutputOf(value.to_owned(), vec![])),
-                            "from-cmd-sh" => Some(VarValue::OutputOf(
+            "end" => return Some((line_nr, line.to_owned())),
+            "elif" | "else" | "endif" if parser.in_if > 0 => {
+                return Some((line_nr, line.to_owned()))
+            }
+            "elif" | "else" | "endif" => {
+                let e = ConfigFromFileError::OutsideIf(left.to_owned());
+                parser.push(Severity::Error, e.to_string(), line_nr, line, indent);
+                continue;
+            }
+            _ => (),
+        }
+        let mut warnings = vec![];
+        if let Err(e) = statement(config, left, right, lines, parser, line_nr, &mut warnings) {
+            let offset = match &e {
+                ConfigFromFileError::UnknownStatement(_)
+                | ConfigFromFileError::NotAllowedInIf(_)
+                | ConfigFromFileError::MissingEndif => indent,
+                // the quoted text is always the end of the line
+                ConfigFromFileError::InvalidQuoting(s, e) => {
+                    right_offset + right.len() - s.len() + e.pos
+                }
+                _ if right.is_empty() => indent,
+                _ => right_offset,
+            };
+            parser.push(Severity::Error, e.to_string(), line_nr, line, offset);
+        }
+        for (offset, warning) in warnings {
+            parser.push(Severity::Warning, warning, line_nr, line, indent + offset);
+        }
+    }
+    None
+}
+
+/// the length of the whitespace before a statement
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// after a file or cmd-prep/... block: `end` is fine, `elif`/`else`/`endif` belong to an `if` which isn't there
+fn outside_if(end: Option<(usize, String)>, parser: &mut Parser) {
+    if let Some((line_nr, line)) = end {
+        let indent = indent(&line);
+        let keyword = line[indent..].split(' ').next().unwrap_or_default();
+        if keyword != "end" {
+            let e = ConfigFromFileError::OutsideIf(keyword.to_owned());
+            parser.push(Severity::Error, e.to_string(), line_nr, &line, indent);
+        }
+    }
+}
+
+/// a cmd-prep/cmd-clean/cmd-reuse block, until `end`
+fn block<'l, L: Iterator<Item = (usize, &'l str)>>(
+    lines: &mut L,
+    parser: &mut Parser,
+) -> RunCmdBuilder {
+    let mut cfg = RunCmdBuilder::default();
+    // the block is a separate config, so everything is allowed again
+    let in_if = std::mem::take(&mut parser.in_if);
+    let end = runcmd_from_lines(&mut cfg, lines, parser);
+    parser.in_if = in_if;
+    outside_if(end, parser);
+    cfg
+}
+
+/// `config <name>` or `use <name> ...`: parses `name` (relative to the current file) into `config`.
+/// Returns `params` after the file was parsed.
+fn include(
+    config: &mut RunCmdBuilder,
+    name: &str,
+    parser: &mut Parser,
+    line_nr: usize,
+    params: Option<Params>,
+) -> Result<Option<Params>, ConfigFromFileError> {
+    let path = formats::find(
+        Path::new(&parser.path)
+            .parent()
+            .unwrap_or(Path::new(""))
+            .join(name),
+    );
+    let real = fs::canonicalize(&path)
+        .map_err(|e| ConfigFromFileError::IncludeFailed(path.to_string_lossy().into_owned(), e))?;
+    let path = path.to_string_lossy().into_owned();
+    let including =
+        std::iter::once(&parser.path).chain(parser.included_from.iter().map(|(path, _)| path));
+    let mut cycle = vec![];
+    for p in including {
+        cycle.push(p.clone());
+        if fs::canonicalize(p).is_ok_and(|p| p == real) {
+            cycle.reverse();
+            cycle.push(path);
+            return Err(ConfigFromFileError::IncludeCycle(cycle));
+        }
+    }
+    let file = fs::read_to_string(&real)
+        .map_err(|e| ConfigFromFileError::IncludeFailed(path.clone(), e))?;
+    let (file, statements) = formats::to_lines(&file, Format::of(&real))
+        .map_err(|e| ConfigFromFileError::InvalidFormat(format!("{path}: {e}")))?;
+    let location = parser.location(line_nr);
+    let including = std::mem::replace(&mut parser.path, path);
+    let outer_statements = std::mem::replace(&mut parser.statements, statements);
+    let outer_params = std::mem::replace(&mut parser.params, params);
+    parser.included_from.insert(0, (including, location));
+    let end = runcmd_from_lines(config, &mut file.lines().enumerate(), parser);
+    outside_if(end, parser);
+    parser.path = parser.included_from.remove(0).0;
+    parser.statements = outer_statements;
+    Ok(std::mem::replace(&mut parser.params, outer_params))
+}
+
+/// one line. `warnings` are (byte offset in the line, message).
+fn statement<'l, L: Iterator<Item = (usize, &'l str)>>(
+    config: &mut RunCmdBuilder,
+    left: &str,
+    right: &str,
+    lines: &mut L,
+    parser: &mut Parser,
+    line_nr: usize,
+    warnings: &mut Vec<(usize, String)>,
+) -> Result<(), ConfigFromFileError> {
+    let comment = left.is_empty() || left.starts_with('#') || left.starts_with("//");
+    if parser.in_if > 0 && !comment && !ALLOWED_IN_IF.contains(&left) {
+        return Err(ConfigFromFileError::NotAllowedInIf(left.to_owned()));
+    }
+    match left {
+        // comments or empty lines
+        _ if comment => (),
+        "config" => {
+            include(config, right, parser, line_nr, None)?;
+        }
+        "use" => {
+            let words = tokenize(right)
+                .map_err(|e| ConfigFromFileError::InvalidQuoting(right.to_owned(), e))?;
+            let Some((name, args)) = words.split_first() else {
+                return Err(ConfigFromFileError::MissingTemplateName);
+            };
+            let mut given = HashMap::new();
+            for arg in args {
+                let (param, value) = arg
+                    .split_once('=')
+                    .ok_or_else(|| ConfigFromFileError::InvalidParameter(arg.to_owned()))?;
+                given.insert(param.to_owned(), value.to_owned());
+            }
+            let params = Params {
+                given,
+                values: HashMap::new(),
+                missing: vec![],
+                is_template: false,
+            };
+            let params = include(config, name, parser, line_nr, Some(params))?.unwrap();
+            if !params.is_template {
+                return Err(ConfigFromFileError::NotATemplate(name.to_owned()));
+            }
+            let mut unknown: Vec<_> = params
+                .given
+                .into_keys()
+                .filter(|name| !params.values.contains_key(name))
+                .collect();
+            if !params.missing.is_empty() || !unknown.is_empty() {
+                unknown.sort();
+                return Err(ConfigFromFileError::WrongParameters(
+                    params.missing,
+                    unknown,
+                ));
+            }
+        }
+        "template" => match &mut parser.params {
+            Some(params) => params.is_template = true,
+            // checking the template itself: without values for the parameters,
+            // the rest can't be parsed, it's checked where it's used
+            None if parser.included_from.is_empty() => {
+                config.include_only = true;
+                for _ in lines.by_ref() {}
+            }
+            None => {
+                // the rest would only cause more errors
+                for _ in lines.by_ref() {}
+                return Err(ConfigFromFileError::TemplateNeedsUse);
+            }
+        },
+        "param" => {
+            let params = parser
+                .params
+                .as_mut()
+                .filter(|p| p.is_template)
+                .ok_or(ConfigFromFileError::InvalidParam)?;
+            let (name, default) = match right.split_once(' ') {
+                Some((name, default)) => (name, Some(default)),
+                None => (right, None),
+            };
+            if name.is_empty() {
+                return Err(ConfigFromFileError::InvalidParam);
+            }
+            let value = match params.given.get(name) {
+                Some(value) => value.clone(),
+                None => default.map(|v| v.to_owned()).unwrap_or_else(|| {
+                    params.missing.push(name.to_owned());
+                    String::new()
+                }),
+            };
+            params.values.insert(name.to_owned(), value);
+        }
+        "var" => {
+            if let Some((name, value)) = right.split_once(' ') {
+                fn get_var_val(
+                    name: &str,
+                    value: &str,
+                    input_types: &mut Vec<(String, InputType)>,
+                    warnings: &mut Vec<String>,
+                ) -> Result<Option<VarValue>, ConfigFromFileError> {
+                    let (mode, value) = if let Some(v) = value.split_once(' ') {
+                        v
+                    } else {
+                        (value, "")
+                    };
+                    Ok(match mode {
+                        "set" => Some(VarValue::Val(value.to_owned())),
+                        "from-cmd" => {
+                            // the program and its args, quoted like `args`
+                            let (options, cmd) = cmd_options(value)?;
+                            let words = tokenize(cmd).map_err(|e| {
+                                ConfigFromFileError::InvalidQuoting(cmd.to_owned(), e)
+                            })?;
+                            let Some((exec, args)) = words.split_first() else {
+                                return Err(ConfigFromFileError::MissingCommand);
+                            };
+                            Some(VarValue::OutputOf(exec.clone(), args.to_vec(), options))
+                        }
+                        "from-cmd-sh" => {
+                            let (options, cmd) = cmd_options(value)?;
+                            Some(VarValue::OutputOf(
                                 "sh".to_owned(),
-                                vec!["-c".to_owned(), value.to_owned()],
-                            )),
-                            "from-input" => Some(VarValue::Input(value.to_owned())),
-                            "from-input-or" => {
-                                if let Some((input, default)) = value.split_once(' ') {
-                                    Some(VarValue::InputOrDefault(
-                                        input.to_owned(),
-                                        Box::new(VarValue::Val(default.to_owned())),
-                                    ))
-                                } else {
-                                    eprintln!(
-                                        "[WARN] Ignoring var from-input-or without default value)"
-                                    );
-                                    None
-                                }
-                            }
-                            "from-input-or-else" => {
-                                if let Some((input, default)) = value.split_once(' ') {
-                                    Some(VarValue::InputOrDefault(
-                                        input.to_owned(),
-                                        Box::new(get_var_val(
-                                            &(name.to_owned() + " / default"),
-                                            default,
-                                        )?),
-                                    ))
-                                } else {
-                                    eprintln!(
-                                        "[WARN] Ignoring var from-input-or without default value)"
-                                    );
-                                    None
-                                }
+                                vec!["-c".to_owned(), cmd.to_owned()],
+                                options,
+                            ))
+                        }
+                        "from-input" => {
+                            // `from-input <input> [<type>]`
+                            let input = if let Some((input, ty)) = value.split_once(' ') {
+                                input_types.push((
+                                    input.to_owned(),
+                                    InputType::parse(ty)
+                                        .map_err(ConfigFromFileError::InvalidInputType)?,
+                                ));
+                                input
+                            } else {
+                                value
+                            };
+                            Some(VarValue::Input(input.to_owned()))
+                        }
+                        "from-input-or" => {
+                            if let Some((input, default)) = value.split_once(' ') {
+                                Some(VarValue::InputOrDefault(
+                                    input.to_owned(),
+                                    Box::new(VarValue::Val(default.to_owned())),
+                                ))
+                            } else {
+                                warnings.push(format!(
+                                    "Ignoring var {name} from-input-or without default value"
+                                ));
+                                None
                             }
-                            "con-id" => Some(VarValue::ConId),
-                            mode => {
-                                eprintln!(
-                                    "[WARN] Ignoring var statement with unknown mode '{mode}'."
-                                );
+                        }
+                        "from-input-or-else" => {
+                            if let Some((input, default)) = value.split_once(' ') {
+                                get_var_val(
+                                    &(name.to_owned() + " / default"),
+                                    default,
+                                    input_types,
+                                    warnings,
+                                )?
+                                .map(|default| {
+                                    VarValue::InputOrDefault(input.to_owned(), Box::new(default))
+                                })
+                            } else {
+                                warnings.push(format!(
+                                    "Ignoring var {name} from-input-or-else without default value"
+                                ));
                                 None
                             }
                         }
-                    }
-                    if let Some(val) = get_var_val(name, value) {
-                        // create variable
-                        config.vars.push((name.to_owned(), val));
-                    }
-                } else {
-                    eprintln!("[WARN] Ignoring bare 'var' statement.");
+                        "con-id" => Some(VarValue::ConId),
+                        "from-file" => Some(VarValue::FromFile(value.to_owned())),
+                        "from-server-env" => Some(VarValue::ServerEnv(value.to_owned())),
+                        "client-uid" => Some(VarValue::ClientUid),
+                        "client-user" => Some(VarValue::ClientUser),
+                        "client-home" => Some(VarValue::ClientHome),
+                        "client-pid" => Some(VarValue::ClientPid),
+                        "timestamp" => Some(VarValue::Timestamp(value.to_owned())),
+                        "random-hex" => Some(VarValue::RandomHex(value.parse().map_err(|_| {
+                            ConfigFromFileError::CouldNotParseNumber(value.to_owned())
+                        })?)),
+                        mode => {
+                            warnings
+                                .push(format!("Ignoring var {name} with unknown mode '{mode}'"));
+                            None
+                        }
+                    })
+                }
+                let mut var_warnings = vec![];
+                let val = get_var_val(name, value, &mut config.input_types, &mut var_warnings);
+                // point at the mode
+                let offset = left.len() + 1 + name.len() + 1;
+                warnings.extend(var_warnings.into_iter().map(|w| (offset, w)));
+                if let Some(val) = val? {
+                    // create variable
+                    config.vars.push((name.to_owned(), val));
                 }
+            } else {
+                warnings.push((0, "Ignoring bare 'var' statement".to_owned()));
             }
-            "allow" => config.allow = Some(right.to_owned()),
-            "cmd-prep" => config.command_prep.push({
-                let mut cfg = RunCmdBuilder::default();
-                runcmd_from_lines(&mut cfg, lines)?;
-                cfg
-            }),
-            "cmd-clean" => config.command_clean.push({
-                let mut cfg = RunCmdBuilder::default();
-                runcmd_from_lines(&mut cfg, lines)?;
-                cfg
-            }),
-            "command" => config.command = Some(right.to_owned()),
-            "args-clear" => config.args.clear(),
-            "arg" => config.args.push(right.to_owned()),
-            "uid" => {
-                config.user = Some(Ok(if let Ok(v) = right.parse() {
-                    v
-                } else {
-                    return Err(ConfigFromFileError::CouldNotParseId(right.to_owned()));
-                }))
+        }
+        "input" => match right.split_once(' ') {
+            Some((input, ty)) => config.input_types.push((
+                input.to_owned(),
+                InputType::parse(ty).map_err(ConfigFromFileError::InvalidInputType)?,
+            )),
+            None => {
+                return Err(ConfigFromFileError::InvalidInputType(format!(
+                    "expected 'input <name> <type>', got 'input {right}'"
+                )))
             }
-            "user" => config.user = Some(Err(right.to_owned())),
-            "gid" => {
-                config.group = Some(Ok(if let Ok(v) = right.parse() {
-                    v
-                } else {
-                    return Err(ConfigFromFileError::CouldNotParseId(right.to_owned()));
-                }))
+        },
+        "var-syntax" => {
+            config.var_syntax = Some(
+                VarSyntax::parse(right)
+                    .ok_or_else(|| ConfigFromFileError::UnknownVarSyntax(right.to_owned()))?,
+            )
+        }
+        "allow" => config.allow = Some(right.to_owned()),
+        // only when this file is loaded itself, not for configs including it
+        "include-only" if parser.included_from.is_empty() => config.include_only = true,
+        "include-only" => (),
+        "auth" => match right.split_once(' ') {
+            Some(("pam", service)) => config.pam_service = Some(service.to_owned()),
+            _ => return Err(ConfigFromFileError::UnknownAuthMethod(right.to_owned())),
+        },
+        "require-approval" => {
+            config.require_approval = Some(if right.is_empty() {
+                DEFAULT_APPROVAL_TIMEOUT
+            } else {
+                Duration::from_secs(
+                    right
+                        .parse()
+                        .map_err(|_| ConfigFromFileError::CouldNotParseNumber(right.to_owned()))?,
+                )
+            })
+        }
+        "max-instances" => {
+            config.max_instances = Some(
+                right
+                    .parse()
+                    .map_err(|_| ConfigFromFileError::CouldNotParseNumber(right.to_owned()))?,
+            )
+        }
+        "max-per-user" => {
+            config.max_per_user = Some(
+                right
+                    .parse()
+                    .map_err(|_| ConfigFromFileError::CouldNotParseNumber(right.to_owned()))?,
+            )
+        }
+        "singleton" | "singleton-per-user" => {
+            config.singleton = Some(
+                Singleton::parse(right)
+                    .ok_or_else(|| ConfigFromFileError::UnknownSingletonMode(right.to_owned()))?,
+            );
+            config.singleton_per_user = left == "singleton-per-user";
+        }
+        "cmd-prep" => config.command_prep.push(block(lines, parser)),
+        "cmd-clean" => config.command_clean.push(block(lines, parser)),
+        "cmd-reuse" => config.command_reuse.push(block(lines, parser)),
+        "if" => {
+            let mut conditional = Conditional {
+                branches: vec![],
+                otherwise: None,
+                position: (config.args.len(), config.groups.len(), config.env.len()),
+            };
+            // the block is parsed even if the condition is invalid, so its lines aren't seen as part of the config
+            let first = Condition::parse(right);
+            let invalid = first.as_ref().err().cloned();
+            let mut condition = Some(first);
+            parser.in_if += 1;
+            let end = loop {
+                let mut branch = RunCmdBuilder::default();
+                let end = runcmd_from_lines(&mut branch, lines, parser);
+                match condition.take() {
+                    Some(Ok(condition)) => conditional.branches.push((condition, branch)),
+                    Some(Err(_)) => (),
+                    None => conditional.otherwise = Some(branch),
+                }
+                let Some((end_nr, end_line)) = end else {
+                    break Err(ConfigFromFileError::MissingEndif);
+                };
+                let end_indent = indent(&end_line);
+                let (keyword, end_right) = end_line[end_indent..]
+                    .split_once(' ')
+                    .unwrap_or((&end_line[end_indent..], ""));
+                let unexpected = |parser: &mut Parser, e: ConfigFromFileError, offset| {
+                    parser.push(
+                        Severity::Error,
+                        e.to_string(),
+                        end_nr,
+                        &end_line,
+                        end_indent + offset,
+                    );
+                };
+                match keyword {
+                    "endif" => break Ok(()),
+                    "elif" | "else" if conditional.otherwise.is_some() => {
+                        unexpected(
+                            parser,
+                            ConfigFromFileError::AfterElse(keyword.to_owned()),
+                            0,
+                        );
+                    }
+                    "elif" => {
+                        let c = Condition::parse(end_right);
+                        if let Err(e) = &c {
+                            let e = ConfigFromFileError::InvalidCondition(e.clone());
+                            unexpected(parser, e, keyword.len() + 1);
+                        }
+                        condition = Some(c);
+                    }
+                    "else" => (),
+                    // `end` of a cmd-prep/... block around the `if`
+                    _ => {
+                        unexpected(parser, ConfigFromFileError::EndInIf, 0);
+                        break Ok(());
+                    }
+                }
+            };
+            parser.in_if -= 1;
+            end?;
+            if let Some(e) = invalid {
+                return Err(ConfigFromFileError::InvalidCondition(e));
             }
-            "group" => config.group = Some(Err(right.to_owned())),
-            "g-clear" => config.groups.clear(),
-            "g+gid" => config.groups.push(if let Ok(v) = right.parse() {
-                Ok(v)
+            config.conditionals.push(conditional);
+        }
+        "command" => config.command = Some(right.to_owned()),
+        // the `if` blocks' args/groups/env are inserted at positions which a clear would remove
+        "args-clear" | "g-clear" | "env-clear" if !config.conditionals.is_empty() => {
+            return Err(ConfigFromFileError::ClearAfterIf(left.to_owned()));
+        }
+        "args-clear" => config.args.clear(),
+        "arg" => config.args.push(right.to_owned()),
+        "args" => config.args.extend(
+            tokenize(right)
+                .map_err(|e| ConfigFromFileError::InvalidQuoting(right.to_owned(), e))?,
+        ),
+        "uid" => {
+            config.user = Some(Ok(if let Ok(v) = right.parse() {
+                v
             } else {
                 return Err(ConfigFromFileError::CouldNotParseId(right.to_owned()));
-            }),
-            "g+group" => config.groups.push(Err(right.to_owned())),
-            "env-clear" => config.env.clear(),
-            "env+set" => config
-                .env
-                .push(if let Some((name, value)) = right.split_once("=") {
-                    (name.into(), Ok(value.into()))
-                } else {
-                    return Err(ConfigFromFileError::EnvSetWrongSyntax(right.to_owned()));
-                }),
-            "env+inherit" => {
-                if let Some((right, default)) = right.split_once("=") {
-                    config.env.push((right.into(), Err(Some(default.into()))));
-                } else {
-                    config.env.push((right.into(), Err(None)));
+            }))
+        }
+        "user" => config.user = Some(Err(right.to_owned())),
+        "gid" => {
+            config.group = Some(GroupSpec::Id(if let Ok(v) = right.parse() {
+                v
+            } else {
+                return Err(ConfigFromFileError::CouldNotParseId(right.to_owned()));
+            }))
+        }
+        "group" if right == "from-user" => config.group = Some(GroupSpec::FromUser),
+        "group" => config.group = Some(group_name(right)?),
+        "g-clear" => config.groups.clear(),
+        "g+gid" => config.groups.push(if let Ok(v) = right.parse() {
+            GroupSpec::Id(v)
+        } else {
+            return Err(ConfigFromFileError::CouldNotParseId(right.to_owned()));
+        }),
+        "g+group" => config.groups.push(group_name(right)?),
+        "g+from-user" => config.groups.push(GroupSpec::FromUser),
+        "env-clear" => config.env.clear(),
+        "env+set" => config.env.push(match right.split_once('=') {
+            // `NAME=value`, where value is taken literally
+            Some((name, value))
+                if !name.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') =>
+            {
+                (name.into(), Ok(value.into()))
+            }
+            // `NAME "value"` or `"NAME=value"`
+            _ => {
+                let tokens = tokenize(right)
+                    .map_err(|e| ConfigFromFileError::InvalidQuoting(right.to_owned(), e))?;
+                match <[String; 2]>::try_from(tokens) {
+                    Ok([name, value]) => (name, Ok(value)),
+                    Err(tokens) => match tokens.first().and_then(|t| t.split_once('=')) {
+                        Some((name, value)) if tokens.len() == 1 => (name.into(), Ok(value.into())),
+                        _ => return Err(ConfigFromFileError::EnvSetWrongSyntax(right.to_owned())),
+                    },
                 }
             }
-            "working-dir" => config.working_dir = Some(right.to_owned()),
-            v => return Err(ConfigFromFileError::UnknownStatement(v.to_owned())),
+        }),
+        "env+inherit" => {
+            if let Some((right, default)) = right.split_once("=") {
+                config.env.push((right.into(), Err(Some(default.into()))));
+            } else {
+                config.env.push((right.into(), Err(None)));
+            }
         }
+        "working-dir" => config.working_dir = Some(right.to_owned()),
+        v => return Err(ConfigFromFileError::UnknownStatement(v.to_owned())),
     }
     Ok(())
 }
@@ -237,17 +829,110 @@ pub fn runcmd_from_lines<L: Iterator<Item = String>>(
 #[derive(Debug)]
 pub enum ConfigFromFileError {
     IoError(std::io::Error),
+    /// a TOML/JSON config which couldn't be read
+    InvalidFormat(String),
+    /// `config <name>`: path, error
+    IncludeFailed(String, std::io::Error),
+    /// the files, from the one which is included again to the `config` statement including it
+    IncludeCycle(Vec<String>),
     UnknownStatement(String),
     CouldNotParseId(String),
+    CouldNotParseNumber(String),
+    UnknownSingletonMode(String),
+    UnknownAuthMethod(String),
+    InvalidInputType(String),
+    UnknownVarSyntax(String),
+    /// the text and what's wrong with it
+    InvalidQuoting(String, TokenizeError),
     EnvSetWrongSyntax(String),
+    GroupWrongSyntax(String),
+    /// `config` for a file containing `template`
+    TemplateNeedsUse,
+    NotATemplate(String),
+    InvalidParam,
+    MissingTemplateName,
+    /// `use`: not name=value
+    InvalidParameter(String),
+    /// not declared by a `param`
+    UnknownParameter(String),
+    /// `elif`/`else`/`endif` without `if`
+    OutsideIf(String),
+    /// `elif`/`else` after `else`
+    AfterElse(String),
+    MissingEndif,
+    /// `end` (of a cmd-prep/... block) before `endif`
+    EndInIf,
+    InvalidCondition(String),
+    NotAllowedInIf(String),
+    /// `args-clear`/`g-clear`/`env-clear` after an `if` block
+    ClearAfterIf(String),
+    /// `use`: (missing, unknown)
+    WrongParameters(Vec<String>, Vec<String>),
+    InvalidCmdOption(String),
+    /// `from-cmd` without a program
+    MissingCommand,
 }
 impl std::fmt::Display for ConfigFromFileError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::IoError(e) => write!(f, "IoError: {e}"),
+            Self::InvalidFormat(e) => write!(f, "Invalid config: {e}"),
+            Self::IncludeFailed(path, e) => {
+                write!(f, "Couldn't read included config '{path}': {e}")
+            }
+            Self::IncludeCycle(paths) => write!(f, "Include cycle: {}", paths.join(" -> ")),
             Self::UnknownStatement(e) => write!(f, "Unknown Statement: '{e}'"),
             Self::CouldNotParseId(e) => write!(f, "Could not parse ID: '{e}'"),
+            Self::CouldNotParseNumber(e) => write!(f, "Could not parse number: '{e}'"),
+            Self::UnknownSingletonMode(e) => write!(
+                f,
+                "Unknown singleton mode '{e}' (expected refuse, attach or reuse)"
+            ),
+            Self::UnknownAuthMethod(e) => {
+                write!(f, "Unknown auth method '{e}' (expected pam <service>)")
+            }
+            Self::InvalidQuoting(s, e) => write!(f, "Invalid quoting in '{s}': {e}"),
+            Self::UnknownVarSyntax(e) => {
+                write!(f, "Unknown var-syntax '{e}' (expected legacy or braced)")
+            }
+            Self::InvalidInputType(e) => write!(f, "Invalid input type: {e}"),
             Self::EnvSetWrongSyntax(e) => write!(f, "env+set: wrong syntax: '{e}'"),
+            Self::GroupWrongSyntax(e) => {
+                write!(f, "Expected a group name, quoted or not, and 'optional', got '{e}'")
+            }
+            Self::TemplateNeedsUse => write!(f, "This is a template, include it with 'use'"),
+            Self::NotATemplate(e) => write!(
+                f,
+                "'{e}' is not a template (include it with 'config', or add 'template' to it)"
+            ),
+            Self::InvalidParam => {
+                write!(f, "Expected 'param <name> [default]' (only in templates)")
+            }
+            Self::MissingTemplateName => write!(f, "Expected 'use <template> [name=value...]'"),
+            Self::InvalidParameter(e) => write!(f, "Expected name=value, got '{e}'"),
+            Self::OutsideIf(e) => write!(f, "'{e}' without 'if'"),
+            Self::AfterElse(e) => write!(f, "'{e}' after 'else'"),
+            Self::MissingEndif => write!(f, "'if' without 'endif'"),
+            Self::EndInIf => write!(f, "'end' inside 'if' (missing 'endif'?)"),
+            Self::InvalidCondition(e) => write!(f, "Invalid condition: {e}"),
+            Self::NotAllowedInIf(e) => write!(f, "'{e}' can't be used in 'if' blocks"),
+            Self::ClearAfterIf(e) => write!(f, "'{e}' can't be used after an 'if' block"),
+            Self::UnknownParameter(e) => write!(f, "Unknown parameter '{e}'"),
+            Self::WrongParameters(missing, unknown) => {
+                let mut problems = vec![];
+                if !missing.is_empty() {
+                    problems.push(format!("missing parameter '{}'", missing.join("', '")));
+                }
+                if !unknown.is_empty() {
+                    problems.push(format!("unknown parameter '{}'", unknown.join("', '")));
+                }
+                write!(f, "Wrong parameters: {}", problems.join(", "))
+            }
+            Self::InvalidCmdOption(option) => write!(
+                f,
+                "Invalid option '{option}' (expected --user <name>, --timeout <seconds> or --keep-newline)"
+            ),
+            Self::MissingCommand => write!(f, "Expected a program to run"),
         }
     }
 }
@@ -256,3 +941,1252 @@ impl From<std::io::Error> for ConfigFromFileError {
         Self::IoError(value)
     }
 }
+
+#[derive(Debug)]
+pub struct TokenizeError {
+    /// byte offset in the tokenized string
+    pub pos: usize,
+    pub kind: TokenizeErrorKind,
+}
+#[derive(Debug)]
+pub enum TokenizeErrorKind {
+    UnterminatedQuote(char),
+    TrailingBackslash,
+    UnknownEscape(char),
+}
+impl std::fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            TokenizeErrorKind::UnterminatedQuote(q) => write!(f, "missing closing {q}")?,
+            TokenizeErrorKind::TrailingBackslash => write!(f, "\\ at the end of the line")?,
+            TokenizeErrorKind::UnknownEscape(c) => write!(f, "unknown escape \\{c}")?,
+        }
+        write!(f, " (at {})", self.pos + 1)
+    }
+}
+
+/// the inverse of `tokenize`: `s` as a single word
+pub fn quote(s: &str) -> String {
+    let plain = !s.is_empty()
+        && !s.starts_with('#')
+        && !s.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c));
+    if plain {
+        s.to_owned()
+    } else {
+        quoted(s)
+    }
+}
+/// `s` in double quotes, as `tokenize` reads it
+pub fn quoted(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            '\\' | '"' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `arg <a>`, or `args "<a>"` if it can't be written literally
+pub fn write_arg(f: &mut std::fmt::Formatter<'_>, a: &str) -> std::fmt::Result {
+    if a.contains(['\n', '\r']) {
+        writeln!(f, "args {}", quote(a))
+    } else {
+        writeln!(f, "arg {a}")
+    }
+}
+
+/// `env+set NAME=value`, or `env+set "NAME" "value"` if it can't be written like that
+pub fn write_env(f: &mut std::fmt::Formatter<'_>, name: &str, value: &str) -> std::fmt::Result {
+    if name.contains(|c: char| c.is_whitespace() || "\"'=".contains(c))
+        || value.contains(['\n', '\r'])
+    {
+        writeln!(f, "env+set {} {}", quoted(name), quote(value))
+    } else {
+        writeln!(f, "env+set {name}={value}")
+    }
+}
+
+/// `group`/`g+group`: `<name> [optional]`, or with the name quoted like `args`
+/// (for names like `from-user`, or which end in ` optional`, see `write_group`)
+fn group_name(right: &str) -> Result<GroupSpec, ConfigFromFileError> {
+    if !right.starts_with(['"', '\'']) {
+        return Ok(match right.strip_suffix(" optional") {
+            Some(name) => GroupSpec::OptionalName(name.to_owned()),
+            None => GroupSpec::Name(right.to_owned()),
+        });
+    }
+    let tokens =
+        tokenize(right).map_err(|e| ConfigFromFileError::InvalidQuoting(right.to_owned(), e))?;
+    match <[String; 2]>::try_from(tokens) {
+        Ok([name, optional]) if optional == "optional" => Ok(GroupSpec::OptionalName(name)),
+        Err(tokens) if tokens.len() == 1 => Ok(GroupSpec::Name(tokens[0].clone())),
+        _ => Err(ConfigFromFileError::GroupWrongSyntax(right.to_owned())),
+    }
+}
+
+/// `group <name> [optional]`, with the name quoted if `group_name` would read it as something else
+fn write_group(
+    f: &mut std::fmt::Formatter<'_>,
+    statement: &str,
+    name: &str,
+    optional: bool,
+) -> std::fmt::Result {
+    let optional = if optional { " optional" } else { "" };
+    if name.is_empty()
+        || name == "from-user"
+        || name.ends_with(" optional")
+        || name.starts_with(['"', '\''])
+        || name.contains(['\n', '\r'])
+    {
+        writeln!(f, "{statement} {}{optional}", quoted(name))
+    } else {
+        writeln!(f, "{statement} {name}{optional}")
+    }
+}
+
+/// `[--user <name>] [--timeout <seconds>] [--keep-newline] <command>` -> (options, command)
+fn cmd_options(mut value: &str) -> Result<(CmdOptions, &str), ConfigFromFileError> {
+    let mut options = CmdOptions::default();
+    while let Some(rest) = value.strip_prefix("--") {
+        let (option, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        value = rest;
+        match option {
+            "keep-newline" => options.keep_newline = true,
+            "user" | "timeout" => {
+                let (arg, rest) = value.split_once(' ').unwrap_or((value, ""));
+                value = rest;
+                if arg.is_empty() {
+                    return Err(ConfigFromFileError::InvalidCmdOption(format!("--{option}")));
+                }
+                if option == "user" {
+                    options.user = Some(arg.to_owned());
+                } else {
+                    options.timeout =
+                        Some(Duration::from_secs(arg.parse().map_err(|_| {
+                            ConfigFromFileError::CouldNotParseNumber(arg.to_owned())
+                        })?));
+                }
+            }
+            _ => return Err(ConfigFromFileError::InvalidCmdOption(format!("--{option}"))),
+        }
+    }
+    Ok((options, value))
+}
+
+/// config statements which are parsed to the same config again (includes and templates are expanded)
+impl std::fmt::Display for RunCmdBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn var_mode(value: &VarValue) -> String {
+            match value {
+                VarValue::Val(v) => format!("set {v}"),
+                // anything after the options which starts with `--` would be read as another option
+                VarValue::OutputOf(sh, args, options)
+                    if sh == "sh"
+                        && args.len() == 2
+                        && args[0] == "-c"
+                        && !args[1].starts_with("--")
+                        && !args[1].contains(['\n', '\r']) =>
+                {
+                    format!("from-cmd-sh {options}{}", args[1])
+                }
+                VarValue::OutputOf(cmd, args, options) => {
+                    let cmd = if cmd.starts_with("--") {
+                        quoted(cmd)
+                    } else {
+                        quote(cmd)
+                    };
+                    let args = args.iter().map(|a| format!(" {}", quote(a)));
+                    format!("from-cmd {options}{cmd}{}", args.collect::<String>())
+                }
+                VarValue::Input(input) => format!("from-input {input}"),
+                VarValue::InputOrDefault(input, default) => match default.as_ref() {
+                    VarValue::Val(v) => format!("from-input-or {input} {v}"),
+                    default => format!("from-input-or-else {input} {}", var_mode(default)),
+                },
+                VarValue::ConId => "con-id".to_owned(),
+                VarValue::FromFile(path) => format!("from-file {path}"),
+                VarValue::ServerEnv(name) => format!("from-server-env {name}"),
+                VarValue::ClientUid => "client-uid".to_owned(),
+                VarValue::ClientUser => "client-user".to_owned(),
+                VarValue::ClientHome => "client-home".to_owned(),
+                VarValue::ClientPid => "client-pid".to_owned(),
+                VarValue::Timestamp(format) => format!("timestamp {format}"),
+                VarValue::RandomHex(len) => format!("random-hex {len}"),
+            }
+        }
+        fn id_or_name(
+            f: &mut std::fmt::Formatter<'_>,
+            statements: (&str, &str),
+            v: &Result<u32, String>,
+        ) -> std::fmt::Result {
+            match v {
+                Ok(id) => writeln!(f, "{} {id}", statements.0),
+                Err(name) => writeln!(f, "{} {name}", statements.1),
+            }
+        }
+        if self.include_only {
+            writeln!(f, "include-only")?;
+        }
+        if let Some(syntax) = self.var_syntax {
+            writeln!(f, "var-syntax {syntax}")?;
+        }
+        for (name, value) in &self.vars {
+            writeln!(f, "var {name} {}", var_mode(value))?;
+        }
+        for (input, ty) in &self.input_types {
+            writeln!(f, "input {input} {ty}")?;
+        }
+        if let Some(allow) = &self.allow {
+            writeln!(f, "allow {allow}")?;
+        }
+        if let Some(service) = &self.pam_service {
+            writeln!(f, "auth pam {service}")?;
+        }
+        if let Some(timeout) = self.require_approval {
+            writeln!(f, "require-approval {}", timeout.as_secs())?;
+        }
+        if let Some(max) = self.max_instances {
+            writeln!(f, "max-instances {max}")?;
+        }
+        if let Some(max) = self.max_per_user {
+            writeln!(f, "max-per-user {max}")?;
+        }
+        match (self.singleton, self.singleton_per_user) {
+            (Some(mode), false) => writeln!(f, "singleton {mode}")?,
+            (Some(mode), true) => writeln!(f, "singleton-per-user {mode}")?,
+            (None, _) => (),
+        }
+        if let Some(command) = &self.command {
+            writeln!(f, "command {command}")?;
+        }
+        if let Some(user) = &self.user {
+            id_or_name(f, ("uid", "user"), user)?;
+        }
+        match &self.group {
+            Some(GroupSpec::Id(id)) => writeln!(f, "gid {id}")?,
+            Some(GroupSpec::Name(name)) => write_group(f, "group", name, false)?,
+            Some(GroupSpec::OptionalName(name)) => write_group(f, "group", name, true)?,
+            Some(GroupSpec::FromUser) => writeln!(f, "group from-user")?,
+            None => (),
+        }
+        if let Some(dir) = &self.working_dir {
+            writeln!(f, "working-dir {dir}")?;
+        }
+        // args, groups and env, with the `if` blocks where they were
+        let mut done = (0, 0, 0);
+        let positions = self.conditionals.iter().map(|c| (c.position, Some(c)));
+        let end = (self.args.len(), self.groups.len(), self.env.len());
+        for ((args, groups, env), conditional) in positions.chain([(end, None)]) {
+            for a in &self.args[done.0..args] {
+                write_arg(f, a)?;
+            }
+            for g in &self.groups[done.1..groups] {
+                match g {
+                    GroupSpec::Id(id) => writeln!(f, "g+gid {id}")?,
+                    GroupSpec::Name(name) => write_group(f, "g+group", name, false)?,
+                    GroupSpec::OptionalName(name) => write_group(f, "g+group", name, true)?,
+                    GroupSpec::FromUser => writeln!(f, "g+from-user")?,
+                }
+            }
+            for (name, value) in &self.env[done.2..env] {
+                match value {
+                    Ok(value) => write_env(f, name, value)?,
+                    Err(None) => writeln!(f, "env+inherit {name}")?,
+                    Err(Some(default)) => writeln!(f, "env+inherit {name}={default}")?,
+                }
+            }
+            done = (args, groups, env);
+            if let Some(c) = conditional {
+                for (i, (condition, branch)) in c.branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { "elif" };
+                    write!(f, "{keyword} {condition}\n{branch}")?;
+                }
+                if let Some(otherwise) = &c.otherwise {
+                    write!(f, "else\n{otherwise}")?;
+                }
+                writeln!(f, "endif")?;
+            }
+        }
+        for cmd in &self.command_prep {
+            write!(f, "cmd-prep\n{cmd}end\n")?;
+        }
+        for cmd in &self.command_clean {
+            write!(f, "cmd-clean\n{cmd}end\n")?;
+        }
+        for cmd in &self.command_reuse {
+            write!(f, "cmd-reuse\n{cmd}end\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits `s` into words, like a shell: words are separated by whitespace, `'...'` is taken literally,
+/// `"..."` and unquoted text can contain escapes (`\n`, `\t`, `\r`, `\\`, `\"`, `\'`, `\ `, `\#`),
+/// and an unquoted `#` at the start of a word starts a comment. `a"b c"` is one word (`ab c`).
+pub fn tokenize(s: &str) -> Result<Vec<String>, TokenizeError> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut chars = s.char_indices();
+    let err = |pos, kind| TokenizeError { pos, kind };
+    let escape = |pos, c: Option<char>| match c {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some(c @ ('\\' | '"' | '\'' | ' ' | '#')) => Ok(c),
+        Some(c) => Err(err(pos, TokenizeErrorKind::UnknownEscape(c))),
+        None => Err(err(pos, TokenizeErrorKind::TrailingBackslash)),
+    };
+    while let Some((pos, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '#' if word.is_none() => break,
+            '\\' => {
+                let c = escape(pos, chars.next().map(|(_, c)| c))?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            '\'' | '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((pos, '\\')) if c == '"' => {
+                            word.push(escape(pos, chars.next().map(|(_, c)| c))?)
+                        }
+                        Some((_, ch)) => word.push(ch),
+                        None => return Err(err(pos, TokenizeErrorKind::UnterminatedQuote(c))),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::run::{RunCmd, ToRunCmdError};
+
+    fn parse(text: &str) -> (RunCmdBuilder, Vec<Diagnostic>) {
+        parse_file("cfg", text)
+    }
+    /// parses `text` as the config file `name` (written to a new temporary directory)
+    fn parse_file(name: &str, text: &str) -> (RunCmdBuilder, Vec<Diagnostic>) {
+        parse_files(&[(name, text)])
+    }
+    /// writes the files (name, text) to a new temporary directory and parses the first one
+    fn parse_files(files: &[(&str, &str)]) -> (RunCmdBuilder, Vec<Diagnostic>) {
+        let dir = write_files(files);
+        let mut config = RunCmdBuilder::default();
+        let diagnostics = runcmd_from_abs_file(&dir.join(files[0].0), &mut config);
+        fs::remove_dir_all(&dir).unwrap();
+        (config, diagnostics)
+    }
+    /// writes the files (name, text) to a new temporary directory and returns it
+    fn write_files(files: &[(&str, &str)]) -> std::path::PathBuf {
+        static N: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "d26run-config-test-{}-{}",
+            std::process::id(),
+            N.fetch_add(1, Ordering::Relaxed)
+        ));
+        for (name, text) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, text).unwrap();
+        }
+        dir
+    }
+
+    fn errors(diagnostics: &[Diagnostic]) -> Vec<String> {
+        diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| d.message.clone())
+            .collect()
+    }
+
+    #[test]
+    fn config_names_stay_in_the_configs_dir() {
+        assert!(is_config_name("firefox"));
+        assert!(is_config_name("firefox.toml"));
+        for name in [
+            "",
+            ".",
+            "..",
+            "safe/../../etc/shadow",
+            "/etc/shadow",
+            "sub/cfg",
+        ] {
+            assert!(!is_config_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn statements_can_be_indented() {
+        let (config, diagnostics) = parse(
+            "command /bin/true\n  arg a\ncmd-prep\n\tcommand /bin/true\n\tuid 0\n  end\nif input X\n    arg b\n  endif\n",
+        );
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert_eq!(config.args, ["a"]);
+        assert_eq!(config.command_prep.len(), 1);
+        assert_eq!(config.conditionals.len(), 1);
+        // the column still points into the line as it is in the file
+        let (_, diagnostics) = parse("  nope a\n  endif\n");
+        let columns: Vec<_> = diagnostics
+            .iter()
+            .map(|d| d.position.as_ref().unwrap().column)
+            .collect();
+        assert_eq!(columns, [3, 3]);
+    }
+
+    #[test]
+    fn clear_after_if_is_rejected() {
+        for clear in ["args-clear", "g-clear", "env-clear"] {
+            let (_, diagnostics) = parse(&format!(
+                "command /bin/true\nuid 0\ngid 0\narg a\nif input X\narg c\nendif\n{clear}\n"
+            ));
+            assert_eq!(
+                errors(&diagnostics),
+                [format!("'{clear}' can't be used after an 'if' block")]
+            );
+        }
+    }
+
+    #[test]
+    fn clear_before_if_is_allowed() {
+        let (config, diagnostics) = parse(
+            "command /bin/true\nuid 0\ngid 0\narg a\nargs-clear\narg b\nif input X\narg c\nendif\narg d\n",
+        );
+        assert!(errors(&diagnostics).is_empty());
+        let info = ToRunCmdInfo {
+            con_id: 0,
+            var_syntax: VarSyntax::Legacy,
+            client_uid: None,
+            client_pid: None,
+        };
+        let vars = HashMap::from([("X".to_owned(), "1".to_owned())]);
+        let cmd = config.to_runcmd_check(&vars, &info).unwrap();
+        assert_eq!(cmd.args, ["b", "c", "d"]);
+        let cmd = config.to_runcmd_check(&HashMap::new(), &info).unwrap();
+        assert_eq!(cmd.args, ["b", "d"]);
+    }
+
+    #[test]
+    fn from_cmd_takes_quoted_args() {
+        let (config, diagnostics) = parse(
+            "var A from-cmd --timeout 3 printf \"%s\\n\" 'a b'\nvar B from-cmd-sh echo \"a b\"\n",
+        );
+        assert!(errors(&diagnostics).is_empty());
+        let [(_, a), (_, b)] = &config.vars[..] else {
+            panic!("{:?}", config.vars);
+        };
+        assert!(
+            matches!(a, VarValue::OutputOf(exec, args, _) if exec == "printf" && args == &["%s\n", "a b"])
+        );
+        assert!(
+            matches!(b, VarValue::OutputOf(exec, args, _) if exec == "sh" && args == &["-c", "echo \"a b\""])
+        );
+        let (_, diagnostics) = parse("var A from-cmd --keep-newline\n");
+        assert_eq!(errors(&diagnostics), ["Expected a program to run"]);
+    }
+
+    #[test]
+    fn invalid_input_runs_no_from_cmd() {
+        let marker = std::env::temp_dir().join(format!(
+            "d26run-config-test-from-cmd-{}",
+            std::process::id()
+        ));
+        _ = fs::remove_file(&marker);
+        let (config, diagnostics) = parse(&format!(
+            "command /bin/true\nuid 0\ngid 0\nvar %T from-cmd /bin/touch {}\nvar %N from-input N int 1..10\n",
+            marker.display()
+        ));
+        assert!(errors(&diagnostics).is_empty());
+        let info = ToRunCmdInfo {
+            con_id: 0,
+            var_syntax: VarSyntax::Legacy,
+            client_uid: None,
+            client_pid: None,
+        };
+        let run =
+            |n: &str| config.to_runcmd(&HashMap::from([("N".to_owned(), n.to_owned())]), &info);
+        let errors = run("11").unwrap_err();
+        assert!(matches!(&errors[..], [ToRunCmdError::VarInvalidInput(name, ..)] if name == "N"));
+        assert!(!marker.exists());
+        // the same config with valid input does run it
+        assert!(run("5").is_ok());
+        assert!(marker.exists());
+        fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn dry_run_runs_nothing() {
+        let marker = |what: &str| {
+            std::env::temp_dir().join(format!(
+                "d26run-config-test-dry-run-{what}-{}",
+                std::process::id()
+            ))
+        };
+        let markers = [marker("var"), marker("var-sh"), marker("prep")];
+        for marker in &markers {
+            _ = fs::remove_file(marker);
+        }
+        let [var, var_sh, prep] = markers.each_ref().map(|m| m.display().to_string());
+        let (config, diagnostics) = parse(&format!(
+            "command /bin/touch\nuid 0\ngid 0\nvar %T from-cmd /bin/touch {var}\nvar %S from-cmd-sh touch {var_sh}\n\
+             arg %T\narg %S\ncmd-prep\ncommand /bin/touch\narg {prep}\nuid 0\ngid 0\nend\n"
+        ));
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        let info = ToRunCmdInfo {
+            con_id: 0,
+            var_syntax: VarSyntax::Legacy,
+            client_uid: None,
+            client_pid: None,
+        };
+        let (cmd, dry_run) = config.dry_run(&HashMap::new(), &info).unwrap();
+        for marker in &markers {
+            assert!(!marker.exists(), "{}", marker.display());
+        }
+        // but it shows what would run
+        let names: Vec<_> = dry_run.var_cmds.iter().map(|(name, ..)| name).collect();
+        assert_eq!(names, ["%T", "%S"]);
+        assert_eq!(cmd.args, ["<output of %T>", "<output of %S>"]);
+        assert_eq!(dry_run.prep.len(), 1);
+        assert_eq!(dry_run.prep[0].args, [prep]);
+        let printed = dry_run.to_string();
+        assert!(
+            printed.contains(&var) && printed.contains(&var_sh),
+            "{printed}"
+        );
+    }
+
+    #[test]
+    fn optional_primary_group_is_printed_as_optional() {
+        let (config, _) = parse("group nonexistent optional\n");
+        assert!(
+            matches!(&config.group, Some(GroupSpec::OptionalName(name)) if name == "nonexistent")
+        );
+        assert_eq!(config.to_string(), "group nonexistent optional\n");
+    }
+
+    #[test]
+    fn group_names_which_look_like_keywords_are_quoted() {
+        for (group, printed) in [
+            (
+                GroupSpec::Name("from-user".to_owned()),
+                "group \"from-user\"\n",
+            ),
+            (GroupSpec::FromUser, "group from-user\n"),
+            (
+                GroupSpec::Name("a optional".to_owned()),
+                "group \"a optional\"\n",
+            ),
+            (
+                GroupSpec::OptionalName("a optional".to_owned()),
+                "group \"a optional\" optional\n",
+            ),
+            (GroupSpec::Name("'a'".to_owned()), "group \"'a'\"\n"),
+            (GroupSpec::Name("a b".to_owned()), "group a b\n"),
+        ] {
+            let config = RunCmdBuilder {
+                group: Some(group),
+                ..Default::default()
+            };
+            assert_eq!(config.to_string(), printed);
+            let (parsed, diagnostics) = parse(printed);
+            assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+            assert_eq!(format!("{:?}", parsed.group), format!("{:?}", config.group));
+        }
+        let (config, _) = parse("g+group 'from-user' optional\n");
+        assert!(
+            matches!(&config.groups[..], [GroupSpec::OptionalName(name)] if name == "from-user")
+        );
+        let (_, diagnostics) = parse("group 'a' b\n");
+        assert_eq!(
+            errors(&diagnostics),
+            ["Expected a group name, quoted or not, and 'optional', got ''a' b'"]
+        );
+    }
+
+    #[test]
+    fn toml_diagnostics_name_the_statement() {
+        let (_, diagnostics) = parse_file(
+            "cfg.toml",
+            "[[statements]]\narg = [\"a\", \"b\"]\n[[statements]]\nif = \"input X\"\nthen = [{ gid = \"x\" }]\n",
+        );
+        let [d] = &diagnostics[..] else {
+            panic!("{diagnostics:?}");
+        };
+        assert!(d.position.is_none());
+        assert_eq!(
+            d.message,
+            "Could not parse ID: 'x' (in statements[1].then[0].gid)"
+        );
+    }
+
+    fn tokens(s: &str) -> Vec<String> {
+        tokenize(s).unwrap_or_else(|e| panic!("{s}: {e}"))
+    }
+
+    #[test]
+    fn tokenize_splits_on_whitespace() {
+        assert_eq!(tokens("a b"), ["a", "b"]);
+        assert_eq!(tokens("  a \t  b  "), ["a", "b"]);
+        assert!(tokens("").is_empty());
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_quotes() {
+        assert_eq!(tokens("\"a b\" c"), ["a b", "c"]);
+        assert_eq!(tokens("'a b' c"), ["a b", "c"]);
+        // adjacent parts are one word
+        assert_eq!(tokens("a\"b c\"'d'e"), ["ab cde"]);
+        // the other quote is a normal char
+        assert_eq!(tokens("\"it's\" 'say \"hi\"'"), ["it's", "say \"hi\""]);
+    }
+
+    #[test]
+    fn tokenize_escapes() {
+        assert_eq!(tokens("a\\ b"), ["a b"]);
+        assert_eq!(tokens("\"a\\\"b\\\\\\n\\t\\r\""), ["a\"b\\\n\t\r"]);
+        assert_eq!(tokens("\\'a\\\""), ["'a\""]);
+        // single quotes don't have escapes
+        assert_eq!(tokens("'a\\nb'"), ["a\\nb"]);
+    }
+
+    #[test]
+    fn tokenize_empty_tokens() {
+        assert_eq!(tokens("\"\""), [""]);
+        assert_eq!(tokens("''"), [""]);
+        assert_eq!(tokens("a \"\" '' b"), ["a", "", "", "b"]);
+    }
+
+    #[test]
+    fn tokenize_comments() {
+        assert_eq!(tokens("a # b"), ["a"]);
+        assert!(tokens("# a").is_empty());
+        // only at the start of a word
+        assert_eq!(tokens("a#b"), ["a#b"]);
+        assert_eq!(tokens("\\#a \"#b\" '#c'"), ["#a", "#b", "#c"]);
+    }
+
+    #[test]
+    fn tokenize_errors() {
+        let err = |s: &str| tokenize(s).unwrap_err();
+        for (s, pos, quote) in [("\"abc", 0, '"'), ("a 'b", 2, '\''), ("'a\"", 0, '\'')] {
+            let e = err(s);
+            assert!(
+                matches!(e.kind, TokenizeErrorKind::UnterminatedQuote(q) if q == quote),
+                "{s}: {e}"
+            );
+            assert_eq!(e.pos, pos, "{s}");
+        }
+        assert_eq!(err("\"abc").to_string(), "missing closing \" (at 1)");
+        let e = err("ab\\");
+        assert!(matches!(e.kind, TokenizeErrorKind::TrailingBackslash));
+        assert_eq!(e.pos, 2);
+        let e = err("a \"b\\x\"");
+        assert!(matches!(e.kind, TokenizeErrorKind::UnknownEscape('x')));
+        assert_eq!(e.to_string(), "unknown escape \\x (at 5)");
+    }
+
+    #[test]
+    fn quote_is_read_back_by_tokenize() {
+        for (s, quoted) in [
+            ("a", "a"),
+            ("", "\"\""),
+            ("a b", "\"a b\""),
+            ("#a", "\"#a\""),
+            ("a#", "a#"),
+            ("it's", "\"it's\""),
+            ("a\\b", "\"a\\\\b\""),
+            ("a\nb\t", "\"a\\nb\\t\""),
+        ] {
+            assert_eq!(quote(s), quoted);
+            assert_eq!(tokens(quoted), [s]);
+        }
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let (config, diagnostics) = parse_files(&[
+            ("main", "command /bin/true\nconfig sub/a\narg c\n"),
+            ("sub/a", "arg a\nconfig b\n"),
+            ("sub/b", "arg b\n"),
+            ("b", "arg wrong\n"),
+        ]);
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert_eq!(config.args, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let (_, diagnostics) = parse_files(&[("a", "config a\n")]);
+        let [error] = &errors(&diagnostics)[..] else {
+            panic!("{diagnostics:?}");
+        };
+        let paths: Vec<_> = error
+            .strip_prefix("Include cycle: ")
+            .unwrap()
+            .split(" -> ")
+            .collect();
+        assert!(
+            matches!(&paths[..], [a, b] if a.ends_with("/a") && a == b),
+            "{error}"
+        );
+        let (_, diagnostics) = parse_files(&[("a", "config sub/b\n"), ("sub/b", "config ../a\n")]);
+        let [error] = &errors(&diagnostics)[..] else {
+            panic!("{diagnostics:?}");
+        };
+        let paths: Vec<_> = error
+            .strip_prefix("Include cycle: ")
+            .unwrap()
+            .split(" -> ")
+            .collect();
+        assert!(
+            matches!(&paths[..], [a, b, c] if a.ends_with("/a") && b.ends_with("/sub/b") && c.ends_with("/sub/../a")),
+            "{error}"
+        );
+        // the cycle is reported where it is closed
+        assert!(diagnostics[0].path.ends_with("/sub/b"));
+        assert_eq!(diagnostics[0].included_from.len(), 1);
+    }
+
+    #[test]
+    fn including_a_file_twice_isnt_a_cycle() {
+        let (config, diagnostics) = parse_files(&[
+            ("main", "config x\nconfig y\n"),
+            ("x", "config common\n"),
+            ("y", "config common\n"),
+            ("common", "arg a\n"),
+        ]);
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert_eq!(config.args, ["a", "a"]);
+    }
+
+    #[test]
+    fn include_only_files_are_only_loaded_when_included() {
+        let (config, _) = parse_file("_gui", "include-only\narg a\n");
+        assert!(config.include_only);
+        let (config, diagnostics) =
+            parse_files(&[("main", "config _gui\n"), ("_gui", "include-only\narg a\n")]);
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert!(!config.include_only);
+        assert_eq!(config.args, ["a"]);
+        let dir = write_files(&[
+            ("firefox", "command /bin/true\nuid 0\ngid 0\nconfig _gui\n"),
+            ("_gui", "include-only\narg a\n"),
+        ]);
+        let config = init(&Settings {
+            configs_dir: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        });
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(config.skipped, 0);
+        let names: Vec<_> = config.run_cmds.keys().collect();
+        assert_eq!(names, ["firefox"]);
+    }
+
+    const GUI_TEMPLATE: &str = "template\nparam display\nparam home /tmp/h\nenv+set DISPLAY=@{display}\nenv+set HOME=@{home}\narg @{display}@{home}\n";
+
+    #[test]
+    fn template_parameters_are_substituted() {
+        let (config, diagnostics) = parse_files(&[
+            ("main", "use _gui 'display=:1 a'\n"),
+            ("_gui", GUI_TEMPLATE),
+        ]);
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert_eq!(
+            config.env,
+            [
+                ("DISPLAY".to_owned(), Ok(":1 a".to_owned())),
+                ("HOME".to_owned(), Ok("/tmp/h".to_owned()))
+            ]
+        );
+        assert_eq!(config.args, [":1 a/tmp/h"]);
+        // only in templates
+        let (config, diagnostics) =
+            parse_files(&[("main", "config x\n"), ("x", "arg @{display}\n")]);
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert_eq!(config.args, ["@{display}"]);
+    }
+
+    #[test]
+    fn template_parameters_must_match() {
+        let use_gui = |use_statement: &str| {
+            let (_, diagnostics) = parse_files(&[
+                ("main", &format!("{use_statement}\n")),
+                ("_gui", GUI_TEMPLATE),
+            ]);
+            errors(&diagnostics)
+        };
+        assert_eq!(
+            use_gui("use _gui"),
+            ["Wrong parameters: missing parameter 'display'"]
+        );
+        assert_eq!(
+            use_gui("use _gui display=:1 b=1 a=2"),
+            ["Wrong parameters: unknown parameter 'a', 'b'"]
+        );
+        assert_eq!(
+            use_gui("use _gui home=/h a=1"),
+            ["Wrong parameters: missing parameter 'display', unknown parameter 'a'"]
+        );
+        assert_eq!(
+            use_gui("use _gui display"),
+            ["Expected name=value, got 'display'"]
+        );
+        assert_eq!(
+            use_gui("use"),
+            ["Expected 'use <template> [name=value...]'"]
+        );
+    }
+
+    #[test]
+    fn undeclared_parameters_are_errors() {
+        for (line, name) in [("arg @{nope}", "nope"), ("arg @{display", "@{display")] {
+            let (_, diagnostics) = parse_files(&[
+                ("main", "use t display=:1\n"),
+                ("t", &format!("template\nparam display\n{line}\n")),
+            ]);
+            assert_eq!(
+                errors(&diagnostics),
+                [format!("Unknown parameter '{name}'")],
+                "{line}"
+            );
+        }
+        // used before it is declared
+        let (_, diagnostics) = parse_files(&[
+            ("main", "use t display=:1\n"),
+            ("t", "template\narg @{display}\nparam display\n"),
+        ]);
+        assert_eq!(errors(&diagnostics), ["Unknown parameter 'display'"]);
+    }
+
+    #[test]
+    fn templates_are_only_used_with_use() {
+        let (config, diagnostics) = parse_file("_gui", GUI_TEMPLATE);
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        assert!(config.include_only);
+        let (_, diagnostics) = parse_files(&[("main", "config _gui\n"), ("_gui", GUI_TEMPLATE)]);
+        assert_eq!(
+            errors(&diagnostics),
+            ["This is a template, include it with 'use'"]
+        );
+        let (_, diagnostics) = parse_files(&[("main", "use x\n"), ("x", "arg a\n")]);
+        assert_eq!(
+            errors(&diagnostics),
+            ["'x' is not a template (include it with 'config', or add 'template' to it)"]
+        );
+        let (_, diagnostics) = parse("param a\n");
+        assert_eq!(
+            errors(&diagnostics),
+            ["Expected 'param <name> [default]' (only in templates)"]
+        );
+    }
+
+    /// `text` (after a `command`) as a config, run (without running anything) with no input
+    fn resolve(text: &str) -> Result<RunCmd, Vec<ToRunCmdError>> {
+        resolve_with(text, VarSyntax::Legacy)
+    }
+    /// like `resolve`, `default` is the server's default-var-syntax
+    fn resolve_with(text: &str, default: VarSyntax) -> Result<RunCmd, Vec<ToRunCmdError>> {
+        let (config, diagnostics) = parse(&format!("command /bin/true\n{text}"));
+        assert!(errors(&diagnostics).is_empty(), "{diagnostics:?}");
+        let info = ToRunCmdInfo {
+            con_id: 0,
+            var_syntax: default,
+            client_uid: None,
+            client_pid: None,
+        };
+        config.to_runcmd_check(&HashMap::new(), &info)
+    }
+    const MISSING_GROUP: &str = "d26run-test-no-such-group";
+
+    /// a user (not root, if there is another one) and its primary group, as (name, id),
+    /// looked up when the test runs so it doesn't depend on which accounts exist
+    fn some_user() -> ((String, u32), (String, u32)) {
+        let mut all: Vec<_> = unsafe { users::all_users() }.collect();
+        all.sort_by_key(|u| (u.uid() == 0, u.uid()));
+        all.into_iter()
+            .find_map(|u| {
+                let name = u.name().to_str()?.to_owned();
+                let group = users::get_group_by_gid(u.primary_group_id())?;
+                let group = group.name().to_str()?.to_owned();
+                // usable in a config as they are
+                (quote(&name) == name && quote(&group) == group)
+                    .then(|| ((name, u.uid()), (group, u.primary_group_id())))
+            })
+            .expect("no user with a primary group")
+    }
+    /// a group which isn't `gid`
+    fn other_group(gid: u32) -> (String, u32) {
+        (0..1000)
+            .filter(|g| *g != gid)
+            .find_map(|g| {
+                let name = users::get_group_by_gid(g)?.name().to_str()?.to_owned();
+                (quote(&name) == name).then_some((name, g))
+            })
+            .expect("only one group")
+    }
+    /// the gids of the user's groups, as `id -G` (not using getgrouplist directly) prints them
+    fn id_groups(user: &str) -> Vec<u32> {
+        let out = std::process::Command::new("id")
+            .args(["-G", user])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let mut gids: Vec<u32> = String::from_utf8(out.stdout)
+            .unwrap()
+            .split_whitespace()
+            .map(|g| g.parse().unwrap())
+            .collect();
+        gids.sort();
+        gids
+    }
+
+    #[test]
+    fn groups_by_id_and_name() {
+        let ((user, uid), (group, gid)) = some_user();
+        let cmd = resolve(&format!("user {user}\ngid 7\ng+group {group}\ng+gid 5\n")).unwrap();
+        assert_eq!((cmd.user, cmd.group, cmd.groups), (uid, 7, vec![gid, 5]));
+        let cmd = resolve(&format!("uid 0\ngroup {group}\n")).unwrap();
+        assert_eq!(cmd.group, gid);
+    }
+
+    #[test]
+    fn groups_from_user() {
+        let ((user, _), (_, gid)) = some_user();
+        let mut cmd = resolve(&format!("user {user}\ngroup from-user\ng+from-user\n")).unwrap();
+        cmd.groups.sort();
+        assert_eq!((cmd.group, cmd.groups), (gid, id_groups(&user)));
+        let errors = resolve("uid 3999999999\ngroup from-user\n").unwrap_err();
+        assert!(matches!(&errors[..], [ToRunCmdError::UnknownUser(uid)] if uid == "3999999999"));
+    }
+
+    #[test]
+    fn optional_groups() {
+        let ((user, _), (_, gid)) = some_user();
+        let (other, other_gid) = other_group(gid);
+        let cmd = resolve(&format!(
+            "user {user}\ngroup {MISSING_GROUP} optional\ng+group {MISSING_GROUP} optional\ng+group {other} optional\n"
+        ))
+        .unwrap();
+        // the missing primary group falls back to the user's
+        assert_eq!((cmd.group, cmd.groups), (gid, vec![other_gid]));
+        let cmd = resolve(&format!("user {user}\ngroup {other} optional\n")).unwrap();
+        assert_eq!(cmd.group, other_gid);
+    }
+
+    #[test]
+    fn missing_groups() {
+        let errors = resolve(&format!("uid 0\ngroup {MISSING_GROUP}\n")).unwrap_err();
+        assert!(matches!(&errors[..], [ToRunCmdError::UnknownGroup(g)] if g == MISSING_GROUP));
+        let errors = resolve(&format!("uid 0\ngid 0\ng+group {MISSING_GROUP}\n")).unwrap_err();
+        assert!(matches!(&errors[..], [ToRunCmdError::UnknownGroup(g)] if g == MISSING_GROUP));
+        let errors = resolve("uid 0\n").unwrap_err();
+        assert!(matches!(&errors[..], [ToRunCmdError::MissingFieldGroup]));
+    }
+
+    #[test]
+    fn var_syntax_is_chosen_by_the_config_or_the_server() {
+        let text = "uid 0\ngid 0\nvar %A set a\nvar B set b\narg %A\narg ${B}\narg ${C:-c}\n";
+        // legacy replaces every occurrence of a var's name, also the B in ${B}
+        let legacy = ["a", "${b}", "${C:-c}"];
+        let braced = ["%A", "b", "c"];
+        for (config, default, expected) in [
+            ("", VarSyntax::Legacy, legacy),
+            ("", VarSyntax::Braced, braced),
+            ("var-syntax braced\n", VarSyntax::Legacy, braced),
+            ("var-syntax legacy\n", VarSyntax::Braced, legacy),
+        ] {
+            let cmd = resolve_with(&format!("{config}{text}"), default).unwrap();
+            assert_eq!(
+                cmd.args,
+                expected,
+                "{config:?} {}",
+                matches!(default, VarSyntax::Braced)
+            );
+        }
+    }
+
+    #[test]
+    fn braced_vars_are_replaced_everywhere() {
+        let cmd = resolve(
+            "var-syntax braced\nuid 0\ngid 0\nvar EMPTY set\nvar A set ${UNSET:-a}\nvar B set ${A}-${EMPTY:-e}\n\
+             arg ${B}\narg ${UNSET:-${A:-x}}\narg $${A}\nenv+set X=${A:-y}\nworking-dir ${UNSET:-/tmp}/${A}\n",
+        )
+        .unwrap();
+        assert_eq!(cmd.args, ["a-e", "a", "${A}"]);
+        assert!(matches!(&cmd.env[..], [(name, Ok(v))] if name == "X" && v == "a"));
+        assert_eq!(cmd.working_dir.as_deref(), Some("/tmp/a"));
+    }
+
+    #[test]
+    fn undefined_braced_vars_are_fatal() {
+        let errors =
+            resolve("var-syntax braced\nuid 0\ngid 0\narg ${A}\narg ${B:-${C}}\narg ${D\n")
+                .unwrap_err();
+        assert!(
+            matches!(&errors[..], [
+                ToRunCmdError::VarUndefined(a),
+                ToRunCmdError::VarUndefined(c),
+                ToRunCmdError::VarUnterminated(d),
+            ] if a == "A" && c == "C" && d == "${D"),
+            "{errors:?}"
+        );
+        let (config, _) = parse("var-syntax braced\ncommand /bin/true\nuid 0\ngid 0\narg ${A}\n");
+        let (non_fatal, fatal) = config.verify(&ToRunCmdInfo {
+            con_id: 0,
+            var_syntax: VarSyntax::Legacy,
+            client_uid: None,
+            client_pid: None,
+        });
+        assert!(non_fatal.is_empty());
+        assert!(matches!(&fatal.unwrap_err()[..], [ToRunCmdError::VarUndefined(a)] if a == "A"));
+        // legacy vars which don't exist are just text
+        let cmd = resolve("uid 0\ngid 0\narg %A\n").unwrap();
+        assert_eq!(cmd.args, ["%A"]);
+    }
+
+    mod round_trip {
+        use proptest::{collection::vec, option, prelude::*};
+
+        use super::*;
+        use crate::input::InputType;
+
+        /// anything which fits on one line
+        fn line() -> impl Strategy<Value = String> {
+            "[^\n\r]{0,10}"
+        }
+        fn word() -> impl Strategy<Value = String> {
+            "[a-zA-Z0-9_%.:/-]{1,8}"
+        }
+        /// for what is quoted if necessary
+        fn any_text() -> impl Strategy<Value = String> {
+            "(?s).{0,10}"
+        }
+
+        fn cmd_options() -> impl Strategy<Value = CmdOptions> {
+            (option::of(word()), option::of(0..1000u64), any::<bool>()).prop_map(
+                |(user, timeout, keep_newline)| CmdOptions {
+                    user,
+                    timeout: timeout.map(Duration::from_secs),
+                    keep_newline,
+                },
+            )
+        }
+        fn var_value() -> impl Strategy<Value = VarValue> {
+            let value = prop_oneof![
+                line().prop_map(VarValue::Val),
+                (any_text(), vec(any_text(), 0..3), cmd_options())
+                    .prop_map(|(exec, args, options)| VarValue::OutputOf(exec, args, options)),
+                (line(), cmd_options()).prop_map(|(cmd, options)| VarValue::OutputOf(
+                    "sh".to_owned(),
+                    vec!["-c".to_owned(), cmd],
+                    options
+                )),
+                word().prop_map(VarValue::Input),
+                Just(VarValue::ConId),
+                line().prop_map(VarValue::FromFile),
+                line().prop_map(VarValue::ServerEnv),
+                Just(VarValue::ClientUid),
+                Just(VarValue::ClientUser),
+                Just(VarValue::ClientHome),
+                Just(VarValue::ClientPid),
+                line().prop_map(VarValue::Timestamp),
+                any::<usize>().prop_map(VarValue::RandomHex),
+            ];
+            value.prop_recursive(2, 4, 1, |default| {
+                (word(), default)
+                    .prop_map(|(input, default)| VarValue::InputOrDefault(input, Box::new(default)))
+            })
+        }
+        fn input_type() -> impl Strategy<Value = InputType> {
+            prop_oneof![
+                Just(InputType::String),
+                vec(word(), 0..3).prop_map(InputType::Url),
+                (option::of(any::<i64>()), option::of(any::<i64>()))
+                    .prop_map(|(min, max)| InputType::Int(min, max)),
+                prop_oneof![Just("[a-z]+"), Just("a|b c"), Just("")]
+                    .prop_map(|r| InputType::parse(&format!("regex {r}")).unwrap()),
+                vec(word(), 1..3).prop_map(InputType::Enum),
+                "[^\n\r]{1,10}".prop_map(InputType::PathUnder),
+            ]
+        }
+        /// also names which look like something else
+        fn group_name() -> impl Strategy<Value = String> {
+            prop_oneof![
+                word(),
+                any_text(),
+                Just("from-user".to_owned()),
+                "[a-z ]{0,3} optional",
+                "[\"'][a-z\"' ]{0,4}",
+            ]
+        }
+        fn group() -> impl Strategy<Value = GroupSpec> {
+            prop_oneof![
+                any::<u32>().prop_map(GroupSpec::Id),
+                group_name().prop_map(GroupSpec::Name),
+                group_name().prop_map(GroupSpec::OptionalName),
+                Just(GroupSpec::FromUser),
+            ]
+        }
+        fn env() -> impl Strategy<Value = (String, Result<String, Option<String>>)> {
+            prop_oneof![
+                (any_text(), any_text()).prop_map(|(name, value)| (name, Ok(value))),
+                ("[^\n\r=]{0,8}", option::of(line()))
+                    .prop_map(|(name, default)| (name, Err(default))),
+            ]
+        }
+        fn condition() -> impl Strategy<Value = Condition> {
+            let condition = prop_oneof![
+                word().prop_map(Condition::Input),
+                (word(), word(), any::<bool>())
+                    .prop_map(|(name, value, eq)| Condition::Var(name, value, eq)),
+                "[^\n\r]{1,10}".prop_map(Condition::Exists),
+                word().prop_map(Condition::UserInGroup),
+            ];
+            condition.prop_recursive(2, 2, 1, |c| c.prop_map(|c| Condition::Not(Box::new(c))))
+        }
+
+        /// what can be set in `if` blocks
+        fn what_to_run() -> impl Strategy<Value = RunCmdBuilder> {
+            (
+                option::of(line()),
+                vec(any_text(), 0..3),
+                option::of(prop_oneof![any::<u32>().prop_map(Ok), line().prop_map(Err)]),
+                option::of(group()),
+                vec(group(), 0..3),
+                vec(env(), 0..3),
+                option::of(line()),
+            )
+                .prop_map(|(command, args, user, group, groups, env, working_dir)| {
+                    RunCmdBuilder {
+                        command,
+                        args,
+                        user,
+                        group,
+                        groups,
+                        env,
+                        working_dir,
+                        ..Default::default()
+                    }
+                })
+        }
+        /// `blocks` levels of nested cmd-prep/cmd-clean/cmd-reuse blocks
+        fn config(blocks: u32) -> BoxedStrategy<RunCmdBuilder> {
+            let settings = (
+                any::<bool>(),
+                option::of(prop_oneof![
+                    Just(VarSyntax::Legacy),
+                    Just(VarSyntax::Braced)
+                ]),
+                option::of(line()),
+                option::of(line()),
+                option::of(0..10000u64),
+                option::of(any::<usize>()),
+                option::of(any::<usize>()),
+                option::of((
+                    prop_oneof![
+                        Just(Singleton::Refuse),
+                        Just(Singleton::Attach),
+                        Just(Singleton::Reuse)
+                    ],
+                    any::<bool>(),
+                )),
+            );
+            let vars = (
+                vec((word(), var_value()), 0..4),
+                vec((word(), input_type()), 0..3),
+            );
+            let nested = if blocks == 0 {
+                Just((vec![], vec![], vec![])).boxed()
+            } else {
+                let block = || vec(config(blocks - 1), 0..2);
+                (block(), block(), block()).boxed()
+            };
+            (what_to_run(), settings, vars, nested)
+                .prop_map(|(config, settings, (vars, input_types), nested)| {
+                    let (include_only, var_syntax, allow, pam_service, approval) =
+                        (settings.0, settings.1, settings.2, settings.3, settings.4);
+                    RunCmdBuilder {
+                        include_only,
+                        vars,
+                        var_syntax,
+                        input_types,
+                        allow,
+                        pam_service,
+                        require_approval: approval.map(Duration::from_secs),
+                        max_instances: settings.5,
+                        max_per_user: settings.6,
+                        singleton: settings.7.map(|(mode, _)| mode),
+                        singleton_per_user: settings.7.is_some_and(|(_, per_user)| per_user),
+                        command_prep: nested.0,
+                        command_clean: nested.1,
+                        command_reuse: nested.2,
+                        ..config
+                    }
+                })
+                .prop_flat_map(|config| {
+                    // `if` blocks between the args/groups/env, in order
+                    let conditional = (
+                        0..=config.args.len(),
+                        0..=config.groups.len(),
+                        0..=config.env.len(),
+                        vec((condition(), what_to_run()), 1..3),
+                        option::of(what_to_run()),
+                    );
+                    (Just(config), vec(conditional, 0..3))
+                })
+                .prop_map(|(mut config, conditionals)| {
+                    let mut positions: Vec<_> = conditionals
+                        .iter()
+                        .map(|(args, groups, env, ..)| (*args, *groups, *env))
+                        .collect();
+                    positions.sort_by_key(|p| p.0);
+                    let mut groups: Vec<_> = positions.iter().map(|p| p.1).collect();
+                    let mut env: Vec<_> = positions.iter().map(|p| p.2).collect();
+                    groups.sort();
+                    env.sort();
+                    config.conditionals = conditionals
+                        .into_iter()
+                        .zip(positions)
+                        .zip(groups.into_iter().zip(env))
+                        .map(|((c, (args, ..)), (groups, env))| Conditional {
+                            branches: c.3,
+                            otherwise: c.4,
+                            position: (args, groups, env),
+                        })
+                        .collect();
+                    config
+                })
+                .boxed()
+        }
+
+        proptest! {
+            #[test]
+            fn printed_configs_parse_to_the_same_config(config in config(1)) {
+                let text = config.to_string();
+                let (parsed, diagnostics) = parse(&text);
+                prop_assert!(errors(&diagnostics).is_empty(), "{text}\n{:?}", errors(&diagnostics));
+                prop_assert_eq!(format!("{parsed:?}"), format!("{config:?}"), "{}", text);
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! problems found while parsing configs, with the file, line and column they refer to.
+//! rendered like
+//! ```text
+//! error: Unknown Statement: 'comand'
+//!   --> /etc/d26run/configs/firefox:7:1
+//!    |
+//!  7 | comand firefox
+//!    | ^^^^^^
+//!    = included from /etc/d26run/configs/firefox-private:2
+//! ```
+
+use std::fmt::Display;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// which lint rule found it (None for problems found while loading the config)
+    pub code: Option<&'static str>,
+    pub message: String,
+    pub path: String,
+    /// None if the problem isn't in a specific line (the file couldn't be read, ...)
+    pub position: Option<Position>,
+    /// where the `config` statements which included `path` are (`path:line`), innermost first
+    pub included_from: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Position {
+    /// 1-based
+    pub line: usize,
+    /// 1-based, in characters
+    pub column: usize,
+    /// how many characters to underline
+    pub len: usize,
+    /// the line itself
+    pub source: String,
+}
+
+impl Position {
+    /// `offset` is a byte offset into `source`. underlines the word starting there.
+    pub fn new(line: usize, source: &str, offset: usize) -> Self {
+        let offset = offset.min(source.len());
+        let len = source[offset..]
+            .chars()
+            .take_while(|c| !c.is_whitespace())
+            .count();
+        Self {
+            line,
+            column: source[..offset].chars().count() + 1,
+            len: len.max(1),
+            source: source.to_owned(),
+        }
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+impl Diagnostic {
+    /// `path:line:column: error: message`, for the log
+    pub fn summary(&self) -> String {
+        match &self.position {
+            Some(p) => format!("{}:{}:{}: {}", self.path, p.line, p.column, self.head()),
+            None => format!("{}: {}", self.path, self.head()),
+        }
+    }
+    fn head(&self) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.code {
+            Some(code) => format!("{severity}[{code}]: {}", self.message),
+            None => format!("{severity}: {}", self.message),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.head())?;
+        let Some(p) = &self.position else {
+            write!(f, "  --> {}", self.path)?;
+            for location in &self.included_from {
+                write!(f, "\n   = included from {location}")?;
+            }
+            return Ok(());
+        };
+        let width = p.line.to_string().len();
+        let pad = " ".repeat(width);
+        writeln!(f, "{pad}--> {}:{}:{}", self.path, p.line, p.column)?;
+        writeln!(f, "{pad} |")?;
+        // tabs would move the ^s
+        writeln!(f, "{} | {}", p.line, p.source.replace('\t', " "))?;
+        write!(
+            f,
+            "{pad} | {}{}",
+            " ".repeat(p.column - 1),
+            "^".repeat(p.len)
+        )?;
+        for location in &self.included_from {
+            write!(f, "\n{pad} = included from {location}")?;
+        }
+        Ok(())
+    }
+}
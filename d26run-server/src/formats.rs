@@ -0,0 +1,409 @@
+//! configs in TOML or JSON (`<name>.toml`, `<name>.json`), for tools which generate them.
+//! they are turned into the line format and parsed like any other config, so everything means the same:
+//! ```toml
+//! [[statements]]
+//! allow = "anyone"
+//! [[statements]]
+//! arg = ["--new-tab", "%URL"]
+//! [[statements]]
+//! [[statements.cmd-prep]]
+//! user = "root"
+//! [[statements]]
+//! if = "input OPEN"
+//! [[statements.then]]
+//! arg = "%OPEN"
+//! ```
+//! every key in a statement table is a statement (`keyword = "rest of the line"`, `true` for just the keyword,
+//! numbers as they are, and arrays repeat the statement). `cmd-prep`/`cmd-clean`/`cmd-reuse` contain statement tables,
+//! and `if = "<condition>"` goes with `then`, optionally `elif` (tables with `if` and `then`) and `else`.
+
+use std::path::{Path, PathBuf};
+
+use serde_json::{Map, Value};
+
+/// in the order of precedence, if there are several files for one config
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Format {
+    Lines,
+    Toml,
+    Json,
+}
+impl Format {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::Toml,
+            Some("json") => Self::Json,
+            _ => Self::Lines,
+        }
+    }
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "lines" => Self::Lines,
+            "toml" => Self::Toml,
+            "json" => Self::Json,
+            _ => return None,
+        })
+    }
+}
+
+const BLOCKS: &[&str] = &["cmd-prep", "cmd-clean", "cmd-reuse"];
+/// only allowed where the structure of the file puts them
+const STRUCTURE: &[&str] = &["end", "if", "elif", "else", "endif"];
+
+/// `path`, or if it doesn't exist, `path.toml` or `path.json` (`config _gui` can refer to `_gui.toml`)
+pub fn find(path: PathBuf) -> PathBuf {
+    if path.exists() {
+        return path;
+    }
+    for ext in ["toml", "json"] {
+        let mut other = path.clone().into_os_string();
+        other.push(".");
+        other.push(ext);
+        let other = PathBuf::from(other);
+        if other.exists() {
+            return other;
+        }
+    }
+    path
+}
+
+/// the config's statements in the line format, and for TOML/JSON, which statement each line came from
+/// (like `statements[2].arg[1]`, the lines don't exist in the file, so diagnostics name that instead)
+pub fn to_lines(text: &str, format: Format) -> Result<(String, Vec<String>), String> {
+    let doc: Value = match format {
+        Format::Lines => return Ok((text.to_owned(), vec![])),
+        Format::Toml => text
+            .parse::<toml::Table>()
+            .map_err(|e| toml_error(text, e))
+            .and_then(|t| serde_json::to_value(t).map_err(|e| e.to_string()))?,
+        Format::Json => serde_json::from_str(text).map_err(|e| e.to_string())?,
+    };
+    let Value::Object(mut doc) = doc else {
+        return Err("expected a table with 'statements'".to_owned());
+    };
+    let statements = doc.remove("statements").unwrap_or(Value::Array(vec![]));
+    if let Some(key) = doc.keys().next() {
+        return Err(format!("unknown key '{key}' (expected only 'statements')"));
+    }
+    let mut lines = vec![];
+    statements_to_lines(&statements, "statements", &mut lines)?;
+    Ok(lines.into_iter().map(|(l, path)| (l + "\n", path)).unzip())
+}
+
+/// one line, like serde_json's errors (the default is a multi-line snippet)
+fn toml_error(text: &str, e: toml::de::Error) -> String {
+    let message = e.message().trim_end().replace('\n', ", ");
+    match e.span() {
+        Some(span) => {
+            let before = &text[..span.start.min(text.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before
+                .rsplit('\n')
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .count()
+                + 1;
+            format!("{message} at line {line} column {column}")
+        }
+        None => message.to_owned(),
+    }
+}
+
+/// `lines`: (line, the statement's path)
+fn statements_to_lines(
+    value: &Value,
+    path: &str,
+    lines: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    let Value::Array(statements) = value else {
+        return Err(format!("{path}: expected an array of statement tables"));
+    };
+    for (i, statement) in statements.iter().enumerate() {
+        let path = format!("{path}[{i}]");
+        let Value::Object(statement) = statement else {
+            return Err(format!("{path}: expected a statement table"));
+        };
+        if statement.contains_key("if") {
+            conditional_to_lines(statement, &path, lines)?;
+            continue;
+        }
+        for (keyword, value) in statement {
+            let path = format!("{path}.{keyword}");
+            if keyword.is_empty() || keyword.contains(char::is_whitespace) {
+                return Err(format!("{path}: invalid statement"));
+            }
+            if STRUCTURE.contains(&keyword.as_str()) {
+                return Err(format!("{path}: '{keyword}' can't be used here"));
+            }
+            if BLOCKS.contains(&keyword.as_str()) {
+                lines.push((keyword.to_owned(), path.clone()));
+                statements_to_lines(value, &path, lines)?;
+                lines.push(("end".to_owned(), path));
+            } else if let Value::Array(values) = value {
+                for (i, value) in values.iter().enumerate() {
+                    let path = format!("{path}[{i}]");
+                    lines.push((line(keyword, value, &path)?, path));
+                }
+            } else {
+                lines.push((line(keyword, value, &path)?, path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `{ if = "...", then = [...], elif = [{ if = "...", then = [...] }], else = [...] }`
+fn conditional_to_lines(
+    statement: &Map<String, Value>,
+    path: &str,
+    lines: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    let empty = Value::Array(vec![]);
+    if let Some(key) = statement
+        .keys()
+        .find(|k| !["if", "then", "elif", "else"].contains(&k.as_str()))
+    {
+        return Err(format!(
+            "{path}.{key}: expected only if, then, elif and else"
+        ));
+    }
+    let if_path = format!("{path}.if");
+    lines.push((line("if", &statement["if"], &if_path)?, if_path));
+    let then = statement.get("then").unwrap_or(&empty);
+    statements_to_lines(then, &format!("{path}.then"), lines)?;
+    let Value::Array(elifs) = statement.get("elif").unwrap_or(&empty) else {
+        return Err(format!("{path}.elif: expected an array of tables"));
+    };
+    for (i, elif) in elifs.iter().enumerate() {
+        let path = format!("{path}.elif[{i}]");
+        let (Some(condition), then) = (elif.get("if"), elif.get("then").unwrap_or(&empty)) else {
+            return Err(format!("{path}: expected a table with 'if' and 'then'"));
+        };
+        let if_path = format!("{path}.if");
+        lines.push((line("elif", condition, &if_path)?, if_path));
+        statements_to_lines(then, &format!("{path}.then"), lines)?;
+    }
+    if let Some(otherwise) = statement.get("else") {
+        let else_path = format!("{path}.else");
+        lines.push(("else".to_owned(), else_path.clone()));
+        statements_to_lines(otherwise, &else_path, lines)?;
+    }
+    lines.push(("endif".to_owned(), path.to_owned()));
+    Ok(())
+}
+
+fn line(keyword: &str, value: &Value, path: &str) -> Result<String, String> {
+    let rest = match value {
+        Value::Bool(true) => return Ok(keyword.to_owned()),
+        Value::String(s) => s.to_owned(),
+        Value::Number(n) => n.to_string(),
+        _ => {
+            return Err(format!(
+                "{path}: expected a string, number, true or an array of these"
+            ))
+        }
+    };
+    if rest.contains(['\n', '\r']) {
+        return Err(format!("{path}: values can't contain line breaks"));
+    }
+    Ok(format!("{keyword} {rest}"))
+}
+
+/// converts a config between formats (keeping comments, but not empty lines or indentation)
+pub fn convert(text: &str, from: Format, to: Format) -> Result<String, String> {
+    from_lines(&to_lines(text, from)?.0, to)
+}
+
+fn from_lines(text: &str, format: Format) -> Result<String, String> {
+    let mut lines = text.lines().enumerate();
+    let (statements, end) = lines_to_statements(&mut lines)?;
+    if let Some((line_nr, keyword, _)) = end.filter(|(_, keyword, _)| *keyword != "end") {
+        return Err(format!("line {line_nr}: '{keyword}' without 'if'"));
+    }
+    let mut doc = Map::new();
+    doc.insert("statements".to_owned(), Value::Array(statements));
+    match format {
+        Format::Lines => Ok(text.to_owned()),
+        Format::Toml => toml::to_string(&doc).map_err(|e| e.to_string()),
+        Format::Json => serde_json::to_string_pretty(&doc).map_err(|e| e.to_string()),
+    }
+}
+
+/// (line number, keyword, rest) of the line ending a block
+type End<'l> = Option<(usize, &'l str, &'l str)>;
+
+/// until the end of the file, `end`, `elif`, `else` or `endif`
+fn lines_to_statements<'l>(
+    lines: &mut impl Iterator<Item = (usize, &'l str)>,
+) -> Result<(Vec<Value>, End<'l>), String> {
+    let mut statements = vec![];
+    while let Some((line_nr, line)) = lines.next() {
+        let line_nr = line_nr + 1;
+        // like the config parser, ignores indentation
+        let line = line.trim_start();
+        let (left, right) = match line.split_once(' ') {
+            Some((left, right)) => (left, Some(right)),
+            None => (line, None),
+        };
+        if left.is_empty() {
+            continue;
+        }
+        if matches!(left, "end" | "elif" | "else" | "endif") {
+            return Ok((statements, Some((line_nr, left, right.unwrap_or_default()))));
+        }
+        let mut statement = Map::new();
+        if BLOCKS.contains(&left) {
+            let (block, end) = lines_to_statements(lines)?;
+            if let Some((line_nr, keyword, _)) = end.filter(|(_, keyword, _)| *keyword != "end") {
+                return Err(format!("line {line_nr}: '{keyword}' inside '{left}'"));
+            }
+            statement.insert(left.to_owned(), Value::Array(block));
+        } else if left == "if" {
+            statement.insert("if".to_owned(), right.unwrap_or_default().into());
+            let (then, mut end) = lines_to_statements(lines)?;
+            statement.insert("then".to_owned(), Value::Array(then));
+            let mut elifs = vec![];
+            let mut otherwise = None;
+            loop {
+                match end {
+                    Some((_, "endif", _)) => break,
+                    Some((_, "elif", condition)) if otherwise.is_none() => {
+                        let (then, next) = lines_to_statements(lines)?;
+                        let mut elif = Map::new();
+                        elif.insert("if".to_owned(), condition.into());
+                        elif.insert("then".to_owned(), Value::Array(then));
+                        elifs.push(Value::Object(elif));
+                        end = next;
+                    }
+                    Some((_, "else", _)) if otherwise.is_none() => {
+                        let (block, next) = lines_to_statements(lines)?;
+                        otherwise = Some(Value::Array(block));
+                        end = next;
+                    }
+                    Some((line_nr, keyword, _)) => {
+                        return Err(format!("line {line_nr}: unexpected '{keyword}'"))
+                    }
+                    None => return Err(format!("line {line_nr}: 'if' without 'endif'")),
+                }
+            }
+            if !elifs.is_empty() {
+                statement.insert("elif".to_owned(), Value::Array(elifs));
+            }
+            if let Some(otherwise) = otherwise {
+                statement.insert("else".to_owned(), otherwise);
+            }
+        } else {
+            statement.insert(
+                left.to_owned(),
+                right.map_or(Value::Bool(true), |r| r.into()),
+            );
+        }
+        statements.push(Value::Object(statement));
+    }
+    Ok((statements, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "# firefox
+allow anyone
+include-only
+arg --new-tab
+arg %URL
+cmd-prep
+  // runs first
+  command mkdir
+  args -p '/tmp/a b'
+end
+if input OPEN
+    arg %OPEN
+elif user-in-group video
+  if input X
+    arg x
+  endif
+else
+  arg none
+endif
+
+  \t
+uid 0
+";
+
+    /// what converting back to lines keeps
+    fn without_indentation(text: &str) -> String {
+        text.lines()
+            .map(str::trim_start)
+            .filter(|l| !l.is_empty())
+            .map(|l| format!("{l}\n"))
+            .collect()
+    }
+
+    #[test]
+    fn lines_round_trip() {
+        for format in [Format::Toml, Format::Json] {
+            let converted = convert(CONFIG, Format::Lines, format).unwrap();
+            let (lines, statements) = to_lines(&converted, format).unwrap();
+            assert_eq!(lines, without_indentation(CONFIG), "{converted}");
+            assert_eq!(statements.len(), lines.lines().count());
+            // and converting that again doesn't change anything
+            assert_eq!(convert(&converted, format, format).unwrap(), converted);
+        }
+    }
+
+    #[test]
+    fn indented_statements_are_kept() {
+        let json = convert(CONFIG, Format::Lines, Format::Json).unwrap();
+        let doc: Value = serde_json::from_str(&json).unwrap();
+        let statements = &doc["statements"];
+        assert_eq!(
+            statements[5]["cmd-prep"][1],
+            serde_json::json!({ "command": "mkdir" })
+        );
+        assert_eq!(
+            statements[6]["then"][0],
+            serde_json::json!({ "arg": "%OPEN" })
+        );
+        assert_eq!(
+            statements[6]["elif"][0]["then"][0]["then"][0],
+            serde_json::json!({ "arg": "x" })
+        );
+        assert_eq!(statements[7], serde_json::json!({ "uid": "0" }));
+    }
+
+    #[test]
+    fn comments_are_statements() {
+        let json = convert(CONFIG, Format::Lines, Format::Json).unwrap();
+        let doc: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(doc["statements"][0], serde_json::json!({ "#": "firefox" }));
+        assert_eq!(
+            doc["statements"][5]["cmd-prep"][0],
+            serde_json::json!({ "//": "runs first" })
+        );
+        assert_eq!(
+            convert("#a\n", Format::Lines, Format::Toml).unwrap(),
+            "[[statements]]\n\"#a\" = true\n"
+        );
+    }
+
+    #[test]
+    fn structure_errors() {
+        for (text, error) in [
+            ("arg a\nendif\n", "line 2: 'endif' without 'if'"),
+            (
+                "if input A\nelse\nelse\nendif\n",
+                "line 3: unexpected 'else'",
+            ),
+            ("if input A\narg a\n", "line 1: 'if' without 'endif'"),
+            ("cmd-prep\nelse\nend\n", "line 2: 'else' inside 'cmd-prep'"),
+        ] {
+            assert_eq!(
+                convert(text, Format::Lines, Format::Toml).unwrap_err(),
+                error,
+                "{text}"
+            );
+        }
+    }
+}
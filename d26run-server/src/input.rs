@@ -0,0 +1,289 @@
+//! types for input variables (`var %N from-input N int 1..100`, `input N int 1..100`).
+//! values sent by the client via `set-var` are checked against these before anything runs.
+
+use std::path::{Component, Path};
+
+#[derive(Clone, Debug)]
+pub enum InputType {
+    /// anything (the default)
+    String,
+    /// `scheme://...`, optionally only with one of these schemes
+    Url(Vec<String>),
+    /// an integer in this (inclusive) range
+    Int(Option<i64>, Option<i64>),
+    /// the whole value must match
+    Regex(regex_lite::Regex),
+    /// one of these
+    Enum(Vec<String>),
+    /// an absolute path inside this directory (may contain variables)
+    PathUnder(String),
+}
+
+impl std::fmt::Display for InputType {
+    /// the same syntax `parse` accepts
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::String => write!(f, "string"),
+            Self::Url(schemes) if schemes.is_empty() => write!(f, "url"),
+            Self::Url(schemes) => write!(f, "url {}", schemes.join(" ")),
+            Self::Int(None, None) => write!(f, "int"),
+            Self::Int(min, max) => write!(
+                f,
+                "int {}..{}",
+                min.map(|v| v.to_string()).unwrap_or_default(),
+                max.map(|v| v.to_string()).unwrap_or_default()
+            ),
+            // remove the ^(?: )$ added by parse
+            Self::Regex(regex) => {
+                write!(f, "regex {}", &regex.as_str()[4..regex.as_str().len() - 2])
+            }
+            Self::Enum(values) => write!(f, "enum {}", values.join("|")),
+            Self::PathUnder(dir) => write!(f, "path-under {dir}"),
+        }
+    }
+}
+
+impl InputType {
+    /// `url [schemes...]`, `int [A..B]`, `regex <regex>`, `enum a|b|c`, `path-under <dir>` or `string`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (ty, args) = s.split_once(' ').unwrap_or((s, ""));
+        Ok(match ty {
+            "string" => Self::String,
+            "url" => Self::Url(args.split_whitespace().map(|v| v.to_owned()).collect()),
+            "int" if args.is_empty() => Self::Int(None, None),
+            "int" => {
+                let (min, max) = args
+                    .split_once("..")
+                    .ok_or_else(|| format!("int: expected a range like 1..100, got '{args}'"))?;
+                let bound = |v: &str| {
+                    if v.is_empty() {
+                        Ok(None)
+                    } else {
+                        v.parse()
+                            .map(Some)
+                            .map_err(|_| format!("int: couldn't parse '{v}'"))
+                    }
+                };
+                Self::Int(bound(min)?, bound(max)?)
+            }
+            "regex" => Self::Regex(
+                regex_lite::Regex::new(&format!("^(?:{args})$"))
+                    .map_err(|e| format!("regex: {e}"))?,
+            ),
+            "enum" if !args.is_empty() => {
+                Self::Enum(args.split('|').map(|v| v.to_owned()).collect())
+            }
+            "path-under" if !args.is_empty() => Self::PathUnder(args.to_owned()),
+            "enum" => return Err("enum: expected values like a|b|c".to_owned()),
+            "path-under" => return Err("path-under: expected a directory".to_owned()),
+            ty => return Err(format!("unknown input type '{ty}'")),
+        })
+    }
+    /// `Err` describes what was expected. `replace_variables` is used for path-under's directory.
+    pub fn check(
+        &self,
+        value: &str,
+        replace_variables: impl Fn(&str) -> String,
+    ) -> Result<(), String> {
+        match self {
+            Self::String => Ok(()),
+            Self::Url(schemes) => {
+                let scheme = value
+                    .split_once("://")
+                    .filter(|(scheme, rest)| {
+                        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                            && scheme
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+                            && !rest.is_empty()
+                            && !value.chars().any(|c| c.is_whitespace() || c.is_control())
+                    })
+                    .map(|(scheme, _)| scheme)
+                    .ok_or("a url")?;
+                if schemes.is_empty() || schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)) {
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "a url starting with {}",
+                        schemes.join("://, ") + "://"
+                    ))
+                }
+            }
+            Self::Int(min, max) => {
+                let expected = || {
+                    format!(
+                        "an integer{}{}",
+                        min.map(|v| format!(" >= {v}")).unwrap_or_default(),
+                        max.map(|v| format!(" <= {v}")).unwrap_or_default()
+                    )
+                };
+                let v: i64 = value.parse().map_err(|_| expected())?;
+                if min.is_some_and(|min| v < min) || max.is_some_and(|max| v > max) {
+                    Err(expected())
+                } else {
+                    Ok(())
+                }
+            }
+            Self::Regex(regex) => {
+                if regex.is_match(value) {
+                    Ok(())
+                } else {
+                    // remove the ^(?: )$ added by parse
+                    let regex = regex.as_str();
+                    Err(format!("a value matching {}", &regex[4..regex.len() - 2]))
+                }
+            }
+            Self::Enum(values) => {
+                if values.iter().any(|v| v == value) {
+                    Ok(())
+                } else {
+                    Err(format!("one of {}", values.join("|")))
+                }
+            }
+            Self::PathUnder(dir) => {
+                let dir = replace_variables(dir);
+                let expected = || format!("an absolute path under {dir}");
+                let path = Path::new(value);
+                // no `..`, so the path can be checked without resolving it
+                if !path.is_absolute()
+                    || !Path::new(&dir).is_absolute()
+                    || path.components().any(|c| c == Component::ParentDir)
+                    || !path.starts_with(&dir)
+                {
+                    return Err(expected());
+                }
+                // symlinks could still point somewhere else (the path itself doesn't have to exist yet)
+                if let Ok(dir_real) = Path::new(&dir).canonicalize() {
+                    let real = path
+                        .ancestors()
+                        .take_while(|p| p.starts_with(&dir))
+                        .find_map(|p| p.canonicalize().ok());
+                    if real.is_some_and(|real| !real.starts_with(dir_real)) {
+                        return Err(expected());
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn check(ty: &str, value: &str) -> Result<(), String> {
+        InputType::parse(ty).unwrap().check(value, |s| s.to_owned())
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for ty in [
+            "string",
+            "url",
+            "url http https",
+            "int",
+            "int 1..100",
+            "int ..0",
+            "int -5..",
+            "regex [a-z]+",
+            "enum a|b|c",
+            "path-under /srv",
+        ] {
+            assert_eq!(InputType::parse(ty).unwrap().to_string(), ty);
+        }
+        for ty in [
+            "int 1",
+            "int a..b",
+            "regex (",
+            "enum",
+            "path-under",
+            "float",
+        ] {
+            assert!(InputType::parse(ty).is_err(), "{ty}");
+        }
+    }
+
+    #[test]
+    fn regex_must_match_the_whole_value() {
+        assert!(check("regex [a-z]+", "abc").is_ok());
+        for value in ["abc1", "1abc", "a\nb", ""] {
+            assert!(check("regex [a-z]+", value).is_err(), "{value:?}");
+        }
+        // an alternative can't escape the anchors
+        assert!(check("regex a|b", "a").is_ok());
+        assert!(check("regex a|b", "ab").is_err());
+        assert!(check("regex a|b", "xb").is_err());
+        assert_eq!(check("regex a|b", "c").unwrap_err(), "a value matching a|b");
+    }
+
+    #[test]
+    fn int_ranges_are_inclusive() {
+        for (value, ok) in [("1", true), ("100", true), ("0", false), ("101", false)] {
+            assert_eq!(check("int 1..100", value).is_ok(), ok, "{value}");
+        }
+        assert!(check("int ..0", "-9223372036854775808").is_ok());
+        assert!(check("int ..0", "1").is_err());
+        assert!(check("int -5..", "-5").is_ok());
+        assert!(check("int -5..", "-6").is_err());
+        for value in ["", "1.5", " 1", "1e3", "99999999999999999999"] {
+            assert!(check("int", value).is_err(), "{value:?}");
+        }
+        assert_eq!(
+            check("int 1..100", "0").unwrap_err(),
+            "an integer >= 1 <= 100"
+        );
+    }
+
+    #[test]
+    fn url_schemes() {
+        assert!(check("url", "ftp://example.org").is_ok());
+        assert!(check("url http https", "HTTPS://example.org").is_ok());
+        for value in ["file:///etc/shadow", "example.org", "http://", "http://a b"] {
+            assert!(check("url http https", value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn path_under_stays_in_the_directory() {
+        let dir = std::env::temp_dir().join(format!("d26run-input-test-{}", std::process::id()));
+        let under = dir.join("under");
+        fs::create_dir_all(&under).unwrap();
+        std::os::unix::fs::symlink("/etc", under.join("etc")).unwrap();
+        std::os::unix::fs::symlink(".", under.join("self")).unwrap();
+        let ty = InputType::parse(&format!("path-under {}", under.display())).unwrap();
+        let check =
+            |value: &str| ty.check(&format!("{}/{value}", under.display()), |s| s.to_owned());
+        let results = [
+            check("a"),
+            check("new/file"),
+            check("self/a"),
+            check("../a"),
+            check("a/../../a"),
+            check("etc"),
+            check("etc/shadow"),
+            check("etc/does-not-exist"),
+        ];
+        // not under, only starts with the same string
+        let sibling = ty.check(&format!("{}-x/a", under.display()), |s| s.to_owned());
+        let relative = ty.check("under/a", |s| s.to_owned());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(results[..3].iter().all(|r| r.is_ok()), "{results:?}");
+        assert!(results[3..].iter().all(|r| r.is_err()), "{results:?}");
+        assert!(sibling.is_err());
+        assert!(relative.is_err());
+    }
+
+    #[test]
+    fn path_under_replaces_variables_in_the_directory() {
+        let ty = InputType::parse("path-under /home/%USER").unwrap();
+        let replace = |s: &str| s.replace("%USER", "a");
+        assert!(ty.check("/home/a/x", replace).is_ok());
+        assert_eq!(
+            ty.check("/home/b/x", replace).unwrap_err(),
+            "an absolute path under /home/a"
+        );
+    }
+}
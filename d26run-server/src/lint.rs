@@ -0,0 +1,691 @@
+//! `d26run-server --lint [dir]`: loads (and verifies) every config like the server would, and also looks for risky patterns.
+//! include-only configs (fragments, templates) are only checked as part of the configs including them.
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+use crate::{
+    config,
+    diagnostics::{Diagnostic, Position, Severity},
+    formats::{self, Format},
+    input::InputType,
+    run::{Condition, RunCmdBuilder, ToRunCmdInfo, VarSyntax, VarValue},
+    settings::Settings,
+};
+
+/// inherited from the server, these change what the command (or the shell, interpreter, ...) runs
+const DANGEROUS_ENV: &[&str] = &[
+    "GCONV_PATH",
+    "PYTHONPATH",
+    "PYTHONSTARTUP",
+    "PERL5LIB",
+    "PERL5OPT",
+    "RUBYOPT",
+    "NODE_OPTIONS",
+    "BASH_ENV",
+    "ENV",
+    "IFS",
+];
+
+/// every file in `dir`, sorted by name
+pub fn lint(settings: &Settings, dir: &str) -> Vec<Diagnostic> {
+    let mut files: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+            .filter_map(|e| e.file_name().to_str().map(|n| n.to_owned()))
+            .collect(),
+        Err(e) => {
+            return vec![Diagnostic {
+                severity: Severity::Error,
+                code: None,
+                message: format!("Couldn't read the directory: {e}"),
+                path: dir.to_owned(),
+                position: None,
+                included_from: vec![],
+            }]
+        }
+    };
+    files.sort();
+    let mut out = vec![];
+    for name in files {
+        let mut cfg = RunCmdBuilder::default();
+        let diagnostics = config::runcmd_from_rel_file(dir, &name, &mut cfg);
+        let ok = !crate::diagnostics::has_errors(&diagnostics);
+        out.extend(diagnostics);
+        if ok && !cfg.include_only {
+            let path = Path::new(dir).join(&name);
+            // the configs the server would skip. the non-fatal problems (missing input, ...) can change until it runs
+            let (_, fatal) = cfg.verify(&ToRunCmdInfo {
+                con_id: 0,
+                var_syntax: settings.default_var_syntax,
+                client_uid: None,
+                client_pid: None,
+            });
+            out.extend(
+                fatal
+                    .err()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|e| Diagnostic {
+                        severity: Severity::Error,
+                        code: None,
+                        message: e.to_string(),
+                        path: path.to_string_lossy().into_owned(),
+                        position: None,
+                        included_from: vec![],
+                    }),
+            );
+            let (lines, statements) = fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| formats::to_lines(&text, Format::of(&path)).ok())
+                .unwrap_or_default();
+            let mut linter = Linter {
+                settings,
+                path: path.to_string_lossy().into_owned(),
+                lines: &lines,
+                statements: &statements,
+                found: vec![],
+            };
+            linter.config(
+                &cfg,
+                &[],
+                cfg.var_syntax.unwrap_or(settings.default_var_syntax),
+                Some("command"),
+            );
+            linter
+                .found
+                .sort_by_key(|d| d.position.as_ref().map(|p| p.line));
+            out.extend(linter.found);
+        }
+    }
+    out
+}
+
+struct Linter<'a> {
+    settings: &'a Settings,
+    path: String,
+    /// the config in the line format, to find the line a problem is in
+    lines: &'a str,
+    /// TOML/JSON: which statement each line came from (see `formats::to_lines`)
+    statements: &'a [String],
+    found: Vec<Diagnostic>,
+}
+impl Linter<'_> {
+    /// `needle` is searched in the config's own lines (not in included ones) to show where the problem is
+    fn push(&mut self, severity: Severity, code: &'static str, message: String, needle: &str) {
+        self.push_nth(severity, code, message, needle, 0);
+    }
+    /// like `push`, but points at the `nth` line containing `needle` (or the last one)
+    fn push_nth(
+        &mut self,
+        severity: Severity,
+        code: &'static str,
+        message: String,
+        needle: &str,
+        nth: usize,
+    ) {
+        let mut found: Vec<_> = self
+            .lines
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| line.find(needle).map(|offset| (i, line, offset)))
+            .collect();
+        let found = (!found.is_empty()).then(|| found.swap_remove(nth.min(found.len() - 1)));
+        let (message, position) = match found {
+            Some((i, _, _)) if i < self.statements.len() => {
+                (format!("{message} (in {})", self.statements[i]), None)
+            }
+            Some((i, line, offset)) => (message, Some(Position::new(i + 1, line, offset))),
+            None => (message, None),
+        };
+        self.found.push(Diagnostic {
+            severity,
+            code: Some(code),
+            message,
+            path: self.path.clone(),
+            position,
+            included_from: vec![],
+        });
+    }
+
+    /// `outer`: the vars of the config containing this one (for cmd-prep/... blocks).
+    /// `runs`: what the command is called in messages, None if it may run as root (cmd-prep, cmd-clean)
+    fn config(
+        &mut self,
+        cfg: &RunCmdBuilder,
+        outer: &[&(String, VarValue)],
+        syntax: VarSyntax,
+        runs: Option<&str>,
+    ) {
+        let syntax = cfg.var_syntax.unwrap_or(syntax);
+        let mut vars: Vec<&(String, VarValue)> = outer.to_vec();
+        for (i, var) in cfg.vars.iter().enumerate() {
+            let (name, value) = var;
+            // shadowed-var (pointing at the nth `var NAME`, assuming they're in order)
+            let defined = cfg.vars[..i].iter().filter(|(n, _)| n == name).count()
+                + usize::from(outer.iter().any(|(n, _)| n == name));
+            if defined > 0 {
+                self.push_nth(
+                    Severity::Warning,
+                    "shadowed-var",
+                    format!("var {name} is defined again, the earlier value is never used"),
+                    &format!("var {name} "),
+                    defined,
+                );
+            }
+            // cmd-var: from-cmd commands are run as they are written, vars aren't replaced in them
+            if let VarValue::OutputOf(cmd, args, _) = value {
+                let used = vars.iter().find(|(var, _)| {
+                    std::iter::once(cmd)
+                        .chain(args)
+                        .any(|s| references(s, var, syntax))
+                });
+                if let Some((var, _)) = used {
+                    self.push(
+                        Severity::Warning,
+                        "cmd-var",
+                        format!("var {name} runs a command containing {var}, which isn't replaced there"),
+                        &format!("var {name} "),
+                    );
+                }
+            }
+            vars.retain(|(n, _)| n != name);
+            vars.push(var);
+        }
+        // unused-var (only this config's own vars, blocks check theirs)
+        let mut used = vec![];
+        strings(cfg, &mut used);
+        let conditions = condition_vars(cfg);
+        for (i, (name, _)) in cfg.vars.iter().enumerate() {
+            let in_vars = cfg.vars[i + 1..]
+                .iter()
+                .any(|(_, v)| var_strings(v).iter().any(|s| references(s, name, syntax)));
+            let later = cfg.vars[i + 1..].iter().any(|(n, _)| n == name);
+            if !later
+                && !in_vars
+                && !conditions.contains(&name.as_str())
+                && !used.iter().any(|s| references(s, name, syntax))
+            {
+                self.push(
+                    Severity::Warning,
+                    "unused-var",
+                    format!("var {name} is never used"),
+                    &format!("var {name} "),
+                );
+            }
+        }
+        self.statements(cfg, &vars, syntax, runs);
+    }
+
+    /// the rules for everything but vars, also for `if` branches
+    fn statements(
+        &mut self,
+        cfg: &RunCmdBuilder,
+        vars: &[&(String, VarValue)],
+        syntax: VarSyntax,
+        runs: Option<&str>,
+    ) {
+        for (name, value) in &cfg.env {
+            let dangerous = name.starts_with("LD_") || DANGEROUS_ENV.contains(&name.as_str());
+            if value.is_err() && dangerous {
+                self.push(
+                    Severity::Error,
+                    "inherit-dangerous",
+                    format!("env+inherit {name} passes the server's {name} on"),
+                    &format!("env+inherit {name}"),
+                );
+            }
+        }
+        for arg in &cfg.args {
+            for undefined in undefined_vars(arg, vars, syntax) {
+                self.push(
+                    Severity::Warning,
+                    "undefined-var",
+                    format!("arg '{arg}' uses {undefined}, which isn't defined"),
+                    &undefined,
+                );
+            }
+        }
+        if let Some(allow) = &cfg.allow {
+            let path = Path::new(&self.settings.allow_dir).join(allow);
+            match fs::metadata(&path) {
+                Ok(meta) if meta.permissions().mode() & 0o002 != 0 => self.push(
+                    Severity::Warning,
+                    "allow-writable",
+                    format!(
+                        "the allow file {} is world-writable, so anyone can run this",
+                        path.display()
+                    ),
+                    "allow ",
+                ),
+                Ok(_) => (),
+                Err(e) => self.push(
+                    Severity::Warning,
+                    "allow-missing",
+                    format!(
+                        "the allow file {} can't be read ({e}), so nobody can run this",
+                        path.display()
+                    ),
+                    "allow ",
+                ),
+            }
+        }
+        if let Some(runs) = runs {
+            self.root_command(cfg, runs);
+        }
+        for block in cfg.command_prep.iter().chain(&cfg.command_clean) {
+            self.config(block, vars, syntax, None);
+        }
+        for block in &cfg.command_reuse {
+            self.config(block, vars, syntax, Some("cmd-reuse"));
+        }
+        for c in &cfg.conditionals {
+            for (_, branch) in &c.branches {
+                self.statements(branch, vars, syntax, runs);
+            }
+            if let Some(otherwise) = &c.otherwise {
+                self.statements(otherwise, vars, syntax, runs);
+            }
+        }
+    }
+
+    /// the main command (or cmd-reuse) runs as root, unlike cmd-prep/cmd-clean this usually isn't needed
+    fn root_command(&mut self, cfg: &RunCmdBuilder, what: &str) {
+        match &cfg.user {
+            Some(Ok(0)) => self.push(
+                Severity::Warning,
+                "root-command",
+                format!("the {what} runs as root"),
+                "uid 0",
+            ),
+            Some(Err(name)) if name == "root" => self.push(
+                Severity::Warning,
+                "root-command",
+                format!("the {what} runs as root"),
+                "user root",
+            ),
+            _ => (),
+        }
+    }
+}
+
+/// whether `s` uses the var `name`
+fn references(s: &str, name: &str, syntax: VarSyntax) -> bool {
+    match syntax {
+        VarSyntax::Legacy => !name.is_empty() && s.contains(name),
+        VarSyntax::Braced => {
+            s.contains(&format!("${{{name}}}")) || s.contains(&format!("${{{name}:-"))
+        }
+    }
+}
+
+/// the parts of a var's value where vars are replaced
+fn var_strings(value: &VarValue) -> Vec<&str> {
+    match value {
+        VarValue::Val(v) => vec![v],
+        VarValue::InputOrDefault(_, default) => var_strings(default),
+        VarValue::FromFile(path) => vec![path],
+        _ => vec![],
+    }
+}
+
+/// every string in the config where vars are replaced, including blocks and `if` branches
+fn strings<'a>(cfg: &'a RunCmdBuilder, out: &mut Vec<&'a str>) {
+    out.extend(cfg.command.as_deref());
+    out.extend(cfg.args.iter().map(|s| s.as_str()));
+    out.extend(
+        cfg.user
+            .as_ref()
+            .and_then(|u| u.as_ref().err())
+            .map(|s| s.as_str()),
+    );
+    out.extend(cfg.group.iter().chain(&cfg.groups).filter_map(|g| g.name()));
+    for (name, value) in &cfg.env {
+        out.push(name);
+        match value {
+            Ok(v) | Err(Some(v)) => out.push(v),
+            Err(None) => (),
+        }
+    }
+    out.extend(cfg.working_dir.as_deref());
+    for (_, ty) in &cfg.input_types {
+        if let InputType::PathUnder(dir) = ty {
+            out.push(dir);
+        }
+    }
+    for (_, value) in &cfg.vars {
+        out.extend(var_strings(value));
+    }
+    for c in &cfg.conditionals {
+        for (condition, branch) in &c.branches {
+            let mut condition = condition;
+            while let Condition::Not(c) = condition {
+                condition = c;
+            }
+            if let Condition::Exists(path) = condition {
+                out.push(path);
+            }
+            strings(branch, out);
+        }
+        if let Some(otherwise) = &c.otherwise {
+            strings(otherwise, out);
+        }
+    }
+    for block in cfg
+        .command_prep
+        .iter()
+        .chain(&cfg.command_clean)
+        .chain(&cfg.command_reuse)
+    {
+        strings(block, out);
+    }
+}
+
+/// vars used in `if var NAME == ...`
+fn condition_vars(cfg: &RunCmdBuilder) -> Vec<&str> {
+    let mut out = vec![];
+    for c in &cfg.conditionals {
+        for (condition, branch) in &c.branches {
+            let mut condition = condition;
+            while let Condition::Not(c) = condition {
+                condition = c;
+            }
+            if let Condition::Var(name, ..) = condition {
+                out.push(name.as_str());
+            }
+            out.extend(condition_vars(branch));
+        }
+        if let Some(otherwise) = &c.otherwise {
+            out.extend(condition_vars(otherwise));
+        }
+    }
+    out
+}
+
+/// legacy: `%NAME` (the usual naming) which no var matches; braced: `${NAME}` without a default
+fn undefined_vars(s: &str, vars: &[&(String, VarValue)], syntax: VarSyntax) -> Vec<String> {
+    let defined = |name: &str| vars.iter().any(|(n, _)| n == name);
+    let mut out = vec![];
+    match syntax {
+        VarSyntax::Legacy => {
+            for (i, _) in s.match_indices('%') {
+                let len = s[i + 1..]
+                    .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+                    .unwrap_or(s.len() - i - 1);
+                let word = &s[i..i + 1 + len];
+                // `%URL` also replaces the start of `%URLS`
+                let replaced = vars
+                    .iter()
+                    .any(|(n, _)| !n.is_empty() && word.starts_with(n.as_str()));
+                if len > 0 && s[i + 1..].starts_with(|c: char| c.is_ascii_uppercase()) && !replaced
+                {
+                    out.push(word.to_owned());
+                }
+            }
+        }
+        VarSyntax::Braced => {
+            let mut rest = s;
+            while let Some(i) = rest.find('$') {
+                rest = &rest[i + 1..];
+                if let Some(r) = rest.strip_prefix('$') {
+                    rest = r;
+                } else if let Some(r) = rest.strip_prefix('{') {
+                    let end = r.find(['}', ':']).unwrap_or(r.len());
+                    if r[end..].starts_with('}') && !defined(&r[..end]) {
+                        out.push(format!("${{{}}}", &r[..end]));
+                    }
+                    rest = r;
+                }
+            }
+        }
+    }
+    out
+}
+
+/// prints the diagnostics (as JSON if `json`), returns the exit code: 1 if there are errors
+pub fn report(diagnostics: &[Diagnostic], json: bool) -> i32 {
+    if json {
+        println!("{}", to_json(diagnostics));
+    } else {
+        for diagnostic in diagnostics {
+            println!("{diagnostic}\n");
+        }
+        let errors = diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count();
+        println!("{errors} errors, {} warnings", diagnostics.len() - errors);
+    }
+    i32::from(crate::diagnostics::has_errors(diagnostics))
+}
+
+/// `[{"path", "line", "column", "severity", "code", "message"}]`, line and column are null if unknown
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    let list: Vec<_> = diagnostics
+        .iter()
+        .map(|d| {
+            serde_json::json!({
+                "path": d.path,
+                "line": d.position.as_ref().map(|p| p.line),
+                "column": d.position.as_ref().map(|p| p.column),
+                "severity": match d.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                },
+                "code": d.code,
+                "message": d.message,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&list).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "command /bin/true\nuid 65534\ngid 65534\n";
+
+    /// lints a directory containing `files`, the allow files are in its `allow` directory.
+    /// `allow`: name and mode of the allow files
+    fn lint_files(test: &str, files: &[(&str, &str)], allow: &[(&str, u32)]) -> Vec<Diagnostic> {
+        let dir =
+            std::env::temp_dir().join(format!("d26run-lint-{test}-test-{}", std::process::id()));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("allow")).unwrap();
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+        for (name, mode) in allow {
+            let path = dir.join("allow").join(name);
+            fs::write(&path, "").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+        }
+        let settings = Settings {
+            allow_dir: dir.join("allow").to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let diagnostics = lint(&settings, dir.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        diagnostics
+    }
+    /// lints a single config, which is BASE followed by `text`
+    fn lint_config(test: &str, text: &str) -> Vec<Diagnostic> {
+        lint_files(test, &[("c", &format!("{BASE}{text}"))], &[])
+    }
+    fn codes(diagnostics: &[Diagnostic]) -> Vec<Option<&'static str>> {
+        diagnostics.iter().map(|d| d.code).collect()
+    }
+
+    #[test]
+    fn cmd_var() {
+        let found = lint_config(
+            "cmd-var",
+            "var %A set a\nvar %B from-cmd echo %A\nvar %C from-cmd echo a\narg %A\narg %B\narg %C\n",
+        );
+        assert_eq!(codes(&found), [Some("cmd-var")]);
+        assert_eq!(
+            found[0].message,
+            "var %B runs a command containing %A, which isn't replaced there"
+        );
+        assert_eq!(found[0].position.as_ref().unwrap().line, 5);
+    }
+
+    #[test]
+    fn inherit_dangerous() {
+        let found = lint_config(
+            "inherit",
+            "env+inherit HOME\nenv+inherit LD_PRELOAD\nenv+inherit BASH_ENV\nenv+set LD_LIBRARY_PATH=/lib\n",
+        );
+        assert_eq!(codes(&found), [Some("inherit-dangerous"); 2]);
+        assert!(found.iter().all(|d| d.severity == Severity::Error));
+        assert_eq!(found[0].position.as_ref().unwrap().line, 5);
+        assert_eq!(found[1].position.as_ref().unwrap().line, 6);
+    }
+
+    #[test]
+    fn root_command() {
+        let prep = "cmd-prep\ncommand /bin/true\nuid 0\ngid 0\nend\n";
+        let found = lint_files(
+            "root",
+            &[
+                ("prep", &format!("{BASE}{prep}")),
+                ("uid", "command /bin/true\nuid 0\ngid 0\n"),
+                ("user", "command /bin/true\nuser root\ngid 0\n"),
+            ],
+            &[],
+        );
+        assert_eq!(codes(&found), [Some("root-command"); 2]);
+        assert!(found[0].path.ends_with("/uid"), "{}", found[0].path);
+        assert!(found[1].path.ends_with("/user"), "{}", found[1].path);
+        assert_eq!(found[1].message, "the command runs as root");
+    }
+
+    #[test]
+    fn allow_files() {
+        let found = lint_files(
+            "allow",
+            &[
+                ("a", &format!("{BASE}allow ok\n")),
+                ("b", &format!("{BASE}allow writable\n")),
+                ("c", &format!("{BASE}allow missing\n")),
+            ],
+            &[("ok", 0o644), ("writable", 0o666)],
+        );
+        assert_eq!(
+            codes(&found),
+            [Some("allow-writable"), Some("allow-missing")]
+        );
+        assert!(found[0].path.ends_with("/b"), "{}", found[0].path);
+        assert!(found[1].path.ends_with("/c"), "{}", found[1].path);
+        assert!(found.iter().all(|d| d.severity == Severity::Warning));
+    }
+
+    #[test]
+    fn unused_var() {
+        let found = lint_config(
+            "unused",
+            "var %A set a\nvar %B set %A\nvar %C set c\nvar %D set d\nvar %E set e\narg %B\nif var %D == d\narg x\nendif\nworking-dir /tmp/%E\n",
+        );
+        assert_eq!(codes(&found), [Some("unused-var")]);
+        assert_eq!(found[0].message, "var %C is never used");
+    }
+
+    #[test]
+    fn shadowed_var() {
+        let found = lint_config("shadowed", "var %A set a\nvar %A set b\narg %A\n");
+        assert_eq!(codes(&found), [Some("shadowed-var")]);
+        assert_eq!(found[0].position.as_ref().unwrap().line, 5);
+        // blocks can't replace the config's vars either
+        let found = lint_config(
+            "shadowed-block",
+            "var %A set a\narg %A\ncmd-prep\ncommand /bin/true\nuid 0\ngid 0\nvar %A set b\narg %A\nend\n",
+        );
+        assert_eq!(codes(&found), [Some("shadowed-var")]);
+        assert_eq!(found[0].position.as_ref().unwrap().line, 10);
+    }
+
+    #[test]
+    fn undefined_var() {
+        let found = lint_config(
+            "undefined",
+            "var %URL set a\narg %URLS\narg %UR\narg 100%\n",
+        );
+        assert_eq!(codes(&found), [Some("undefined-var")]);
+        assert_eq!(found[0].message, "arg '%UR' uses %UR, which isn't defined");
+        // braced vars which aren't defined can't be loaded at all
+        let found = lint_config(
+            "undefined-braced",
+            "var-syntax braced\nvar A set a\narg ${A}\narg ${B:-b}\narg $${C}\narg ${D}\n",
+        );
+        assert_eq!(codes(&found), [None, Some("undefined-var")]);
+        assert_eq!(found[0].message, "var: '${D}' is not defined");
+        assert_eq!(
+            found[1].message,
+            "arg '${D}' uses ${D}, which isn't defined"
+        );
+    }
+
+    #[test]
+    fn configs_are_verified_like_the_server_does() {
+        let found = lint_files(
+            "verify",
+            &[
+                ("command", "uid 65534\ngid 65534\n"),
+                ("include-only", "include-only\narg x\n"),
+                ("input", &format!("{BASE}var %A from-input A\narg %A\n")),
+                ("reuse", &format!("{BASE}singleton reuse\n")),
+            ],
+            &[],
+        );
+        let found: Vec<_> = found
+            .iter()
+            .map(|d| {
+                (
+                    d.severity,
+                    d.code,
+                    d.path.rsplit('/').next().unwrap(),
+                    &*d.message,
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Severity::Error, None, "command", "missing field 'command'"),
+                (
+                    Severity::Error,
+                    None,
+                    "reuse",
+                    "'singleton reuse' requires a 'cmd-reuse'"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_and_exit_code() {
+        let found = lint_config("json", "var %A set a\nenv+inherit LD_PRELOAD\n");
+        let json: serde_json::Value = serde_json::from_str(&to_json(&found)).unwrap();
+        let json = json.as_array().unwrap();
+        assert_eq!(json.len(), 2);
+        assert!(json[0]["path"].as_str().unwrap().ends_with("/c"));
+        assert_eq!(json[0]["line"], 4);
+        assert_eq!(json[0]["column"], 1);
+        assert_eq!(json[0]["severity"], "warning");
+        assert_eq!(json[0]["code"], "unused-var");
+        assert_eq!(json[0]["message"], "var %A is never used");
+        assert_eq!(json[1]["severity"], "error");
+        assert_eq!(json[1]["code"], "inherit-dangerous");
+        assert_eq!(report(&found, true), 1);
+        assert_eq!(report(&found, false), 1);
+        assert_eq!(report(&found[..1], false), 0);
+        assert_eq!(report(&[], true), 0);
+        // a directory which can't be read
+        let missing = lint(&Settings::default(), "/nonexistent/d26run-lint-test");
+        let json: serde_json::Value = serde_json::from_str(&to_json(&missing)).unwrap();
+        assert_eq!(json[0]["line"], serde_json::Value::Null);
+        assert_eq!(json[0]["code"], serde_json::Value::Null);
+        assert_eq!(report(&missing, true), 1);
+    }
+}
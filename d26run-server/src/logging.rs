@@ -0,0 +1,200 @@
+//! the server's log. messages look like `[INFO] con=3 ...`,
+//! where `con=3` is a structured field: `info!(con = id; "disconnected.")`.
+//! what gets logged (and where to) is configured in server.conf or via `--log-level`.
+
+use std::{
+    fmt::{Arguments, Display, Write as _},
+    fs,
+    io::Write,
+    os::unix::net::UnixDatagram,
+    sync::{Mutex, OnceLock},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+impl Level {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "error" => Self::Error,
+            "warn" => Self::Warn,
+            "info" => Self::Info,
+            "debug" => Self::Debug,
+            "trace" => Self::Trace,
+            _ => return None,
+        })
+    }
+    fn tag(self) -> &'static str {
+        match self {
+            Self::Error => "[ERR!]",
+            Self::Warn => "[WARN]",
+            Self::Info => "[INFO]",
+            Self::Debug => "[DBUG]",
+            Self::Trace => "[TRCE]",
+        }
+    }
+    /// syslog severity, also used as journald's PRIORITY
+    fn severity(self) -> u8 {
+        match self {
+            Self::Error => 3,
+            Self::Warn => 4,
+            Self::Info => 6,
+            Self::Debug | Self::Trace => 7,
+        }
+    }
+}
+
+pub enum Target {
+    Stderr,
+    /// appends to this file
+    File(String),
+    /// sends messages to the syslog daemon via /dev/log
+    Syslog,
+    /// sends messages to journald using its native protocol, so fields are kept
+    Journald,
+}
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// the syslog tag / SYSLOG_IDENTIFIER
+const IDENTIFIER: &str = "d26run-server";
+/// syslog facility "daemon"
+const FACILITY: u8 = 3;
+
+enum Output {
+    Stderr,
+    File(Mutex<fs::File>),
+    Syslog(UnixDatagram),
+    Journald(UnixDatagram),
+}
+
+struct Logger {
+    level: Level,
+    output: Output,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets up the log. Until this is called, everything from `Info` up is written to stderr.
+pub fn init(level: Level, target: &Target) -> std::io::Result<()> {
+    let output = match target {
+        Target::Stderr => Output::Stderr,
+        Target::File(path) => Output::File(Mutex::new(
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?,
+        )),
+        Target::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(SYSLOG_SOCKET)?;
+            Output::Syslog(socket)
+        }
+        Target::Journald => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(JOURNALD_SOCKET)?;
+            Output::Journald(socket)
+        }
+    };
+    _ = LOGGER.set(Logger { level, output });
+    Ok(())
+}
+
+/// use the macros (`info!(...)`, ...) instead of calling this directly
+pub fn log(level: Level, fields: &[(&str, &dyn Display)], args: Arguments) {
+    let (max_level, output) = match LOGGER.get() {
+        Some(logger) => (logger.level, &logger.output),
+        None => (Level::Info, &Output::Stderr),
+    };
+    if level > max_level {
+        return;
+    }
+    match output {
+        Output::Stderr => eprintln!("{}", text_line(level, fields, args)),
+        Output::File(file) => {
+            if let Ok(mut file) = file.lock() {
+                _ = writeln!(file, "{}", text_line(level, fields, args));
+            }
+        }
+        Output::Syslog(socket) => {
+            let mut msg = format!(
+                "<{}>{IDENTIFIER}[{}]: {args}",
+                FACILITY * 8 + level.severity(),
+                std::process::id()
+            );
+            for (key, val) in fields {
+                _ = write!(msg, " {key}={val}");
+            }
+            _ = socket.send(msg.as_bytes());
+        }
+        Output::Journald(socket) => {
+            let mut msg = vec![];
+            journald_field(&mut msg, "MESSAGE", &args.to_string());
+            journald_field(&mut msg, "PRIORITY", &level.severity().to_string());
+            journald_field(&mut msg, "SYSLOG_IDENTIFIER", IDENTIFIER);
+            for (key, val) in fields {
+                let key = format!("D26RUN_{}", key.to_uppercase());
+                journald_field(&mut msg, &key, &val.to_string());
+            }
+            _ = socket.send(&msg);
+        }
+    }
+}
+
+/// `[INFO] con=3 message`
+fn text_line(level: Level, fields: &[(&str, &dyn Display)], args: Arguments) -> String {
+    let mut line = level.tag().to_owned();
+    for (key, val) in fields {
+        _ = write!(line, " {key}={val}");
+    }
+    _ = write!(line, " {args}");
+    line
+}
+
+/// `KEY=value\n`, or, if the value contains a newline, `KEY\n<u64 le length>value\n`
+fn journald_field(msg: &mut Vec<u8>, key: &str, val: &str) {
+    msg.extend_from_slice(key.as_bytes());
+    if val.contains('\n') {
+        msg.push(b'\n');
+        msg.extend_from_slice(&(val.len() as u64).to_le_bytes());
+    } else {
+        msg.push(b'=');
+    }
+    msg.extend_from_slice(val.as_bytes());
+    msg.push(b'\n');
+}
+
+macro_rules! log_ {
+    ($level:ident, $($key:ident = $val:expr),+ ; $($arg:tt)*) => {
+        $crate::logging::log(
+            $crate::logging::Level::$level,
+            &[$((stringify!($key), &$val as &dyn std::fmt::Display)),+],
+            format_args!($($arg)*),
+        )
+    };
+    ($level:ident, $($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::$level, &[], format_args!($($arg)*))
+    };
+}
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::logging::log_!(Error, $($arg)*) };
+}
+macro_rules! warn_ {
+    ($($arg:tt)*) => { $crate::logging::log_!(Warn, $($arg)*) };
+}
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::logging::log_!(Info, $($arg)*) };
+}
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::logging::log_!(Debug, $($arg)*) };
+}
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::logging::log_!(Trace, $($arg)*) };
+}
+// `warn` itself would be ambiguous with the builtin attribute here
+pub(crate) use {debug, error, info, log_, trace, warn_ as warn};
@@ -1,96 +1,281 @@
 #![feature(setgroups)]
-#![feature(fs_try_exists)]
+#![feature(peer_credentials_unix_socket)]
 
-use std::os::unix::fs::PermissionsExt;
-use std::os::unix::net::UnixStream;
-use std::sync::atomic::AtomicBool;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
 use std::{
+    collections::HashMap,
     fs,
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, Write},
 };
 
-use crate::run::ToRunCmdInfo;
+use crate::{
+    logging::{debug, error, info, warn},
+    run::ToRunCmdInfo,
+    server::{opt, ServerState},
+};
 
+mod approvals;
+mod audit;
 mod config;
+mod diagnostics;
+mod formats;
+mod input;
+mod lint;
+mod logging;
+mod metrics;
+mod pam;
 mod run;
 mod server;
+mod sessions;
+mod settings;
+mod signals;
+mod systemd;
 
-const DIR_CONFIGS: &'static str = "/etc/d26run/configs/";
-const DIR_ALLOWS: &'static str = "/etc/d26run/allow/";
+/// a metrics-socket client which doesn't read the metrics is disconnected after this
+const METRICS_WRITE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
 fn main() {
     let mut test_mode = false;
-    let mut socket_path = "/tmp/d26run-socket".to_string();
+    let mut socket_path = None;
+    let mut log_level = None;
+    let mut verify_audit = false;
+    let mut settings_file = None;
+    let mut test_config = None;
+    let mut dry_run = None;
+    let mut convert_config = None;
+    let mut print_config = None;
+    let mut lint = None;
     {
         let mut args = std::env::args().skip(1);
-        loop {
-            if let Some(arg) = args.next() {
-                match arg.as_str() {
-                    "--help" => {
-                        eprintln!("Args:\n--test-mode\n--test-config path/to/config [VAR=VALUE ...]\n--socket-path path/for/socket");
-                        return;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" => {
+                    eprintln!("Args:\n--test-mode\n--config path/to/server.conf (default: {})\n--test-config path/to/config [VAR=VALUE ...]\n--socket-path path/for/socket (overrides socket-path from server.conf, ignored when socket-activated)\n--log-level error|warn|info|debug|trace (overrides log-level from server.conf)\n--verify-audit (checks the audit-log from server.conf, then exits)\n--convert-config path/to/config [toml|json|lines] (prints the config in another format, default toml)\n--print-config path/to/config (prints the config as it is loaded, with includes and templates expanded)\n--dry-run path/to/config [VAR=VALUE ...] (shows what running the config would run, as the current user, without running anything)\n--lint [path/to/dir] [--json] (checks all configs for risky patterns, default: configs-dir from server.conf)", settings::SETTINGS_FILE);
+                    return;
+                }
+                "--test-mode" => test_mode = true,
+                "--config" => {
+                    settings_file = Some(
+                        args.next()
+                            .expect("--config must be followed by the path to the server's config file"),
+                    )
+                }
+                "--test-config" => {
+                    let file = args.next().expect("--test-config must be followed by the path to a config (and optionally some input variables in the format VAR=VALUE)");
+                    let vars: HashMap<_, _> = args.map(|v| v.split_once('=').map(|(a, b)| (a.to_owned(), b.to_owned()))).collect::<Option<_>>().expect("All additional arguments after --test-config must be in the format VAR=VALUE");
+                    test_config = Some((file, vars));
+                    break;
+                },
+                "--dry-run" => {
+                    let file = args.next().expect("--dry-run must be followed by the path to a config (and optionally some input variables in the format VAR=VALUE)");
+                    let vars: HashMap<_, _> = args.map(|v| v.split_once('=').map(|(a, b)| (a.to_owned(), b.to_owned()))).collect::<Option<_>>().expect("All additional arguments after --dry-run must be in the format VAR=VALUE");
+                    dry_run = Some((file, vars));
+                    break;
+                }
+                "--convert-config" => {
+                    let file = args.next().expect("--convert-config must be followed by the path to a config");
+                    let format = args.next().map(|f| formats::Format::parse(&f).expect("--convert-config: the format must be toml, json or lines"));
+                    convert_config = Some((file, format.unwrap_or(formats::Format::Toml)));
+                    break;
+                }
+                "--print-config" => {
+                    print_config = Some(args.next().expect("--print-config must be followed by the path to a config"));
+                    break;
+                }
+                "--lint" => {
+                    let (json, dir): (Vec<_>, Vec<_>) = args.by_ref().partition(|a| a == "--json");
+                    if dir.len() > 1 {
+                        error!("--lint takes at most one directory (and optionally --json)");
+                        std::process::exit(4);
                     }
-                    "--test-mode" => test_mode = true,
-                    "--test-config" => {
-                        let file = args.next().expect("--test-config must be followed by the path to a config (and optionally some input variables in the format VAR=VALUE)");
-                        let vars = args.map(|v| v.split_once('=').map(|(a, b)| (a.to_owned(), b.to_owned()))).collect::<Option<_>>().expect("All additional arguments after --test-config must be in the format VAR=VALUE");
-                        eprintln!("Testing '{file}'...");
-                        let mut config = run::RunCmdBuilder::default();
-                        if let Err(e) = config::runcmd_from_abs_file(&file, &mut config) {
-                            eprintln!("{e}");
-                        }
-                        match config.to_runcmd_check(&vars, &ToRunCmdInfo {
-                            con_id: 42,
-                        }) {
-                            Ok(cmd) => {
-                                eprintln!("=== OK ===\n{}", cmd);
-                            }
-                            Err(e) => {
-                                if e.is_empty() {
-                                    panic!("Empty error list");
-                                }
-                                for e in e {
-                                    eprintln!("{e}");
-                                }
-                            }
+                    lint = Some((dir.into_iter().next(), !json.is_empty()));
+                    break;
+                }
+                "--socket-path" => {
+                    socket_path = Some(
+                        args.next()
+                            .expect("--socket-path must be followed by another argument"),
+                    )
+                }
+                "--verify-audit" => verify_audit = true,
+                "--log-level" => {
+                    let level = args
+                        .next()
+                        .expect("--log-level must be followed by error, warn, info, debug or trace");
+                    match logging::Level::parse(&level) {
+                        Some(level) => log_level = Some(level),
+                        None => {
+                            error!("Unknown log level '{level}'");
+                            std::process::exit(4);
                         }
-                        return;
-                    },
-                    "--socket-path" => {
-                        socket_path = args
-                            .next()
-                            .expect("--socket-path must be followed by another argument")
-                    }
-                    other /* if other.starts_with("-") */ => {
-                        eprintln!("[ERR!] Unknown argument '{other}'");
-                        std::process::exit(4);
                     }
                 }
-            } else {
-                break;
+                other /* if other.starts_with("-") */ => {
+                    error!("Unknown argument '{other}'");
+                    std::process::exit(4);
+                }
+            }
+        }
+    }
+    if let Some((file, format)) = convert_config {
+        let converted = fs::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|text| {
+                formats::convert(&text, formats::Format::of(Path::new(&file)), format)
+            });
+        match converted {
+            Ok(converted) => print!("{converted}"),
+            Err(e) => {
+                eprintln!("{file}: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(file) = print_config {
+        let mut config = run::RunCmdBuilder::default();
+        let diagnostics = config::runcmd_from_abs_file(&file, &mut config);
+        for diagnostic in &diagnostics {
+            eprintln!("{diagnostic}\n");
+        }
+        if diagnostics::has_errors(&diagnostics) {
+            std::process::exit(1);
+        }
+        print!("{config}");
+        return;
+    }
+    let mut settings = {
+        let path = settings_file.as_deref().unwrap_or(settings::SETTINGS_FILE);
+        match settings::load(path, settings_file.is_some()) {
+            Ok(v) => v,
+            Err(errors) => {
+                for e in errors {
+                    error!("{path}: {e}");
+                }
+                std::process::exit(3);
+            }
+        }
+    };
+    if let Some(socket_path) = socket_path {
+        settings.sockets[0].path = socket_path;
+    }
+    if let Some(level) = log_level {
+        settings.log_level = level;
+    }
+    if verify_audit {
+        let Some(path) = &settings.audit_log else {
+            error!("no audit-log is configured in server.conf.");
+            std::process::exit(3);
+        };
+        match audit::verify(path) {
+            Ok((count, hash)) => {
+                eprintln!("{path}: OK ({count} records, the last one is seq={count} hash={hash})")
+            }
+            Err(e) => {
+                eprintln!("{path}: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some((dir, json)) = lint {
+        let dir = dir.unwrap_or_else(|| settings.configs_dir.clone());
+        let diagnostics = lint::lint(&settings, &dir);
+        let code = lint::report(&diagnostics, json);
+        if code != 0 {
+            std::process::exit(code);
+        }
+        return;
+    }
+    if let Some((file, vars)) = dry_run {
+        let mut config = run::RunCmdBuilder::default();
+        let diagnostics = config::runcmd_from_abs_file(&file, &mut config);
+        for diagnostic in &diagnostics {
+            eprintln!("{diagnostic}\n");
+        }
+        if diagnostics::has_errors(&diagnostics) {
+            std::process::exit(1);
+        }
+        if config.include_only {
+            eprintln!("{file} is include-only, so it can't be run.");
+            std::process::exit(1);
+        }
+        let info = ToRunCmdInfo {
+            con_id: 42,
+            var_syntax: settings.default_var_syntax,
+            client_uid: Some(users::get_current_uid()),
+            client_pid: Some(std::process::id() as i32),
+        };
+        match config.dry_run(&vars, &info) {
+            Ok((cmd, dry_run)) => print!("{dry_run}{cmd}"),
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("{e}");
+                }
+                std::process::exit(1);
             }
         }
+        return;
+    }
+    if let Some((file, vars)) = test_config {
+        eprintln!("Testing '{file}'...");
+        let mut config = run::RunCmdBuilder::default();
+        let diagnostics = config::runcmd_from_abs_file(&file, &mut config);
+        for diagnostic in &diagnostics {
+            eprintln!("{diagnostic}\n");
+        }
+        if diagnostics::has_errors(&diagnostics) {
+            eprintln!("=== couldn't parse the config ===");
+            return;
+        }
+        if config.include_only {
+            eprintln!("=== OK (include-only, so it isn't checked further) ===");
+            return;
+        }
+        match config.to_runcmd_check(
+            &vars,
+            &ToRunCmdInfo {
+                con_id: 42,
+                var_syntax: settings.default_var_syntax,
+                client_uid: None,
+                client_pid: None,
+            },
+        ) {
+            Ok(cmd) => {
+                eprintln!("=== OK ===\n{}", cmd);
+            }
+            Err(e) => {
+                if e.is_empty() {
+                    panic!("Empty error list");
+                }
+                for e in e {
+                    eprintln!("{e}");
+                }
+            }
+        }
+        return;
+    }
+    if let Err(e) = logging::init(settings.log_level, &settings.log_target) {
+        error!("couldn't set up the log: {e}");
+        std::process::exit(3);
     }
     if test_mode {
-        eprintln!("[INFO] test-mode enabled!");
+        info!("test-mode enabled!");
     }
-    eprintln!("[INFO] socket_path: {socket_path}");
-    let min_duration_between_reloads = Duration::from_secs(15);
     if !test_mode {
         // remove previous socket and client directories
         if let Ok(dir) = fs::read_dir("/tmp/") {
-            for entry in dir {
-                if let Ok(entry) = entry {
-                    if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_dir() {
-                            if let Some(name) = entry.file_name().to_str() {
-                                if name.starts_with("d26run-client-") {
-                                    fs::remove_dir_all(entry.path()).expect(
-                                        "couldn't remove previous d26run-client-* directory.",
-                                    );
-                                }
+            for entry in dir.flatten() {
+                if let Ok(file_type) = entry.file_type() {
+                    if file_type.is_dir() {
+                        if let Some(name) = entry.file_name().to_str() {
+                            if name.starts_with("d26run-client-") {
+                                fs::remove_dir_all(entry.path())
+                                    .expect("couldn't remove previous d26run-client-* directory.");
                             }
                         }
                     }
@@ -98,36 +283,180 @@ fn main() {
             }
         }
     }
-    if let Ok(true) = fs::try_exists(&socket_path) {
-        fs::remove_file(&socket_path).unwrap();
+    let audit = match &settings.audit_log {
+        Some(path) => match audit::AuditLog::open(path) {
+            Ok(v) => Some(Arc::new(v)),
+            Err(e) => {
+                error!("couldn't open the audit log: {e}");
+                std::process::exit(3);
+            }
+        },
+        None => None,
+    };
+    let state = Arc::new(ServerState::new(settings, audit));
+    // use the sockets passed by the service manager, and open (and chmod) the others
+    let mut activated = systemd::listen_fds();
+    let mut listeners = vec![];
+    let mut bound_sockets = vec![];
+    for (i, socket) in state.settings.sockets.iter().enumerate() {
+        let passed = activated
+            .iter()
+            .position(|l| local_path(l).is_some_and(|p| p == Path::new(&socket.path)))
+            .or({
+                // if there is only one, the path doesn't matter
+                if state.settings.sockets.len() == 1 && activated.len() == 1 {
+                    Some(0)
+                } else {
+                    None
+                }
+            });
+        if let Some(passed) = passed {
+            let listener = activated.swap_remove(passed);
+            info!(
+                "socket-activated, using the passed socket ({:?}).",
+                local_path(&listener)
+            );
+            listeners.push((i, listener));
+        } else {
+            info!("socket_path: {}", socket.path);
+            let listener = bind(&socket.path, socket.owner, socket.group, socket.mode);
+            listeners.push((i, listener));
+            bound_sockets.push(socket.path.clone());
+        }
     }
-    // open the socket and chmod it
-    let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
-    let mut socket_permissions = fs::metadata(&socket_path).unwrap().permissions();
-    socket_permissions.set_mode(0o666);
-    fs::set_permissions(&socket_path, socket_permissions).unwrap();
+    let metrics_listener = state.settings.metrics_socket.as_ref().map(|path| {
+        match activated
+            .iter()
+            .position(|l| local_path(l).is_some_and(|p| p == Path::new(path)))
+        {
+            Some(passed) => activated.swap_remove(passed),
+            None => {
+                info!("metrics socket: {path}");
+                bound_sockets.push(path.clone());
+                bind(path, None, None, 0o660)
+            }
+        }
+    });
+    for listener in activated {
+        warn!(
+            "ignoring socket {:?} passed via LISTEN_FDS, it isn't configured in server.conf.",
+            local_path(&listener)
+        );
+    }
+    signals::spawn_handler({
+        let state = Arc::clone(&state);
+        move |signal| match signal {
+            signals::Signal::Reload => {
+                info!("got SIGHUP, reloading configs on the next connection.");
+                state
+                    .please_reload
+                    .store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+            signals::Signal::Stop => {
+                info!("stopping.");
+                systemd::notify("STOPPING=1");
+                // sockets passed to us are managed by the service manager, not by us
+                for path in &bound_sockets {
+                    _ = fs::remove_file(path);
+                }
+                std::process::exit(0);
+            }
+        }
+    });
     // accept connections
-    let mut current_id: u128 = 0;
-    let mut config = Arc::new(config::init());
-    let mut last_reload = Instant::now();
-    let please_reload = Arc::new(AtomicBool::new(false));
-    loop {
-        if let Ok((stream, _addr)) = listener.accept() {
-            // update stuff
-            if please_reload.load(std::sync::atomic::Ordering::Relaxed)
-                && last_reload.elapsed() > min_duration_between_reloads
-            {
-                config = Arc::new(config::init());
-                last_reload = Instant::now();
+    if let Some(listener) = metrics_listener {
+        let state = Arc::clone(&state);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // a client which doesn't read shouldn't block the others
+                let state = Arc::clone(&state);
+                std::thread::spawn(move || {
+                    _ = stream.set_write_timeout(Some(METRICS_WRITE_TIMEOUT));
+                    _ = stream.write_all(metrics::render(&state).as_bytes());
+                });
             }
-            // start task
-            let id = current_id;
-            current_id += 1;
-            let config = Arc::clone(&config);
-            let please_reload = Arc::clone(&please_reload);
-            std::thread::spawn(move || server::handle_con(stream, id, config, please_reload));
+        });
+    }
+    let next_id = Arc::new(AtomicU64::new(0));
+    let accept_threads: Vec<_> = listeners
+        .into_iter()
+        .map(|(socket, listener)| {
+            let state = Arc::clone(&state);
+            let next_id = Arc::clone(&next_id);
+            std::thread::spawn(move || loop {
+                if let Ok((mut stream, _addr)) = listener.accept() {
+                    let uid = stream.peer_cred().ok().map(|cred| cred.uid);
+                    // checked before spawning a thread for the connection
+                    let connection = match state.sessions.try_connect(&state.settings, uid) {
+                        Ok(v) => v,
+                        Err(limit) => {
+                            warn!(uid = opt(uid); "refusing connection: {limit} reached.");
+                            _ = writeln!(stream, "error_limit_reached {limit}");
+                            continue;
+                        }
+                    };
+                    // start task
+                    let id = next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed) as u128;
+                    debug!(con = id, socket = state.settings.sockets[socket].path; "connected.");
+                    let config = state.config();
+                    let state = Arc::clone(&state);
+                    std::thread::spawn(move || {
+                        server::handle_con(stream, id, config, state, socket);
+                        drop(connection);
+                    });
+                }
+            })
+        })
+        .collect();
+    systemd::notify("READY=1");
+    for thread in accept_threads {
+        _ = thread.join();
+    }
+}
+
+/// creates the socket (and its parent directory, if necessary), then chowns and chmods it.
+/// an existing parent directory must pass `check_socket_dir`.
+fn bind(path: &str, owner: Option<u32>, group: Option<u32>, mode: u32) -> UnixListener {
+    if let Some(dir) = Path::new(path).parent() {
+        if !dir.exists() {
+            // only root should be able to put things in here
+            fs::create_dir_all(dir).unwrap();
+            fs::set_permissions(dir, fs::Permissions::from_mode(0o755)).unwrap();
+        } else if let Err(e) = check_socket_dir(dir) {
+            error!("refusing to bind '{path}': {e}");
+            std::process::exit(3);
         }
     }
+    if let Ok(true) = Path::new(path).try_exists() {
+        fs::remove_file(path).unwrap();
+    }
+    let listener = UnixListener::bind(path).unwrap();
+    std::os::unix::fs::chown(path, owner, group).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    listener
+}
+
+/// the socket's directory must be owned by the server's user, and neither its group nor others may write to it:
+/// if someone else can write to it, they can replace the socket after we bind it.
+fn check_socket_dir(dir: &Path) -> Result<(), String> {
+    let meta = fs::metadata(dir).map_err(|e| format!("'{}': {e}", dir.display()))?;
+    let euid = unsafe { libc::geteuid() };
+    if meta.uid() != euid || meta.mode() & 0o022 != 0 {
+        return Err(format!(
+            "'{}' must be owned by uid {euid} and only be writable by its owner (it is owned by uid {} with mode {:o})",
+            dir.display(),
+            meta.uid(),
+            meta.mode() & 0o7777
+        ));
+    }
+    Ok(())
+}
+
+fn local_path(listener: &UnixListener) -> Option<PathBuf> {
+    listener
+        .local_addr()
+        .ok()
+        .and_then(|a| a.as_pathname().map(Path::to_path_buf))
 }
 
 trait NewlineRemover {
@@ -155,3 +484,25 @@ impl Liner for BufReader<UnixStream> {
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn socket_dir_must_only_be_writable_by_its_owner() {
+        let dir =
+            std::env::temp_dir().join(format!("d26run-socket-dir-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let check = |mode| {
+            fs::set_permissions(&dir, fs::Permissions::from_mode(mode)).unwrap();
+            check_socket_dir(&dir)
+        };
+        let results = [0o755, 0o700, 0o775, 0o757, 0o777, 0o1777].map(check);
+        fs::remove_dir(&dir).unwrap();
+        assert!(results[0].is_ok() && results[1].is_ok(), "{results:?}");
+        for result in &results[2..] {
+            assert!(result.is_err());
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! counters for runs, auth failures, reloads, ..., rendered in the prometheus text format
+//! by the `metrics` command or on the `metrics-socket`.
+
+use std::{
+    collections::BTreeMap, fmt::Write as _, process::ExitStatus, sync::Mutex, time::Duration,
+};
+
+use crate::server::ServerState;
+
+/// name, help
+const COUNTERS: &[(&str, &str)] = &[
+    (
+        "d26run_runs_started_total",
+        "Runs whose command was spawned.",
+    ),
+    (
+        "d26run_runs_denied_total",
+        "Run requests which were denied (unknown config, auth, limits, ...).",
+    ),
+    (
+        "d26run_runs_failed_total",
+        "Runs which were allowed, but failed (invalid config, spawn failed).",
+    ),
+    ("d26run_auth_failures_total", "Failed authentications."),
+    ("d26run_config_reloads_total", "Config reloads."),
+    (
+        "d26run_config_errors_total",
+        "Config files which were skipped due to errors, summed over all (re)loads.",
+    ),
+    (
+        "d26run_child_exits_total",
+        "Main commands which exited, by exit code (or signal).",
+    ),
+];
+/// upper bounds of the run duration histogram's buckets, in seconds
+const DURATION_BUCKETS: &[f64] = &[1.0, 10.0, 60.0, 600.0, 3600.0, 86400.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    /// (name, labels) -> value
+    counters: Mutex<BTreeMap<(&'static str, String), u64>>,
+    /// config -> run durations
+    durations: Mutex<BTreeMap<String, Histogram>>,
+}
+
+struct Histogram {
+    /// one per `DURATION_BUCKETS`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    /// `name` must be one of `COUNTERS`
+    pub fn inc(&self, name: &'static str, labels: &[(&str, &str)]) {
+        self.add(name, labels, 1);
+    }
+    pub fn add(&self, name: &'static str, labels: &[(&str, &str)], n: u64) {
+        debug_assert!(COUNTERS.iter().any(|(c, _)| *c == name));
+        let mut labels_str = String::new();
+        for (key, val) in labels {
+            if !labels_str.is_empty() {
+                labels_str.push(',');
+            }
+            _ = write!(labels_str, "{key}=\"{}\"", escape(val));
+        }
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry((name, labels_str))
+            .or_default() += n;
+    }
+    /// the main command of a run exited (`status` is None if it couldn't be waited for)
+    pub fn run_finished(&self, config: &str, status: Option<ExitStatus>, duration: Duration) {
+        use std::os::unix::process::ExitStatusExt;
+        let code = match status.map(|s| (s.code(), s.signal())) {
+            Some((Some(code), _)) => code.to_string(),
+            Some((None, Some(signal))) => format!("signal {signal}"),
+            _ => "unknown".to_owned(),
+        };
+        self.inc(
+            "d26run_child_exits_total",
+            &[("config", config), ("code", &code)],
+        );
+        let secs = duration.as_secs_f64();
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations
+            .entry(config.to_owned())
+            .or_insert_with(|| Histogram {
+                buckets: vec![0; DURATION_BUCKETS.len()],
+                sum: 0.0,
+                count: 0,
+            });
+        for (bucket, le) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+}
+
+/// all metrics, in the prometheus text format
+pub fn render(state: &ServerState) -> String {
+    let mut out = String::new();
+    let counters = state.metrics.counters.lock().unwrap();
+    for (name, help) in COUNTERS {
+        _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+        for ((_, labels), val) in counters.iter().filter(|((n, _), _)| n == name) {
+            if labels.is_empty() {
+                _ = writeln!(out, "{name} {val}");
+            } else {
+                _ = writeln!(out, "{name}{{{labels}}} {val}");
+            }
+        }
+    }
+    drop(counters);
+    out.push_str("# HELP d26run_run_duration_seconds How long the main commands ran.\n# TYPE d26run_run_duration_seconds histogram\n");
+    for (config, histogram) in state.metrics.durations.lock().unwrap().iter() {
+        let Histogram {
+            buckets,
+            sum,
+            count,
+        } = histogram;
+        let config = escape(config);
+        for (bucket, le) in buckets.iter().zip(DURATION_BUCKETS) {
+            _ = writeln!(
+                out,
+                "d26run_run_duration_seconds_bucket{{config=\"{config}\",le=\"{le}\"}} {bucket}"
+            );
+        }
+        _ = writeln!(
+            out,
+            "d26run_run_duration_seconds_bucket{{config=\"{config}\",le=\"+Inf\"}} {count}"
+        );
+        _ = writeln!(
+            out,
+            "d26run_run_duration_seconds_sum{{config=\"{config}\"}} {sum}"
+        );
+        _ = writeln!(
+            out,
+            "d26run_run_duration_seconds_count{{config=\"{config}\"}} {count}"
+        );
+    }
+    let mut sessions = 0;
+    state.sessions.for_each(|_| sessions += 1);
+    _ = writeln!(out, "# HELP d26run_sessions_active Sessions which haven't finished yet.\n# TYPE d26run_sessions_active gauge\nd26run_sessions_active {sessions}");
+    let config = state.loaded_config();
+    _ = writeln!(out, "# HELP d26run_configs_loaded Currently loaded configs.\n# TYPE d26run_configs_loaded gauge\nd26run_configs_loaded {}", config.run_cmds.len());
+    _ = writeln!(out, "# HELP d26run_configs_skipped Config files which were skipped due to errors during the last (re)load.\n# TYPE d26run_configs_skipped gauge\nd26run_configs_skipped {}", config.skipped);
+    out
+}
+
+/// label values: `\`, `"` and newlines must be escaped
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,210 @@
+//! authentication via PAM (`auth pam <service>` in a config).
+//! libpam is loaded at runtime, so the server still works (without PAM) where it isn't installed.
+//! the conversation (password prompts, ...) is relayed to the client by the caller.
+
+use std::{
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    sync::OnceLock,
+};
+
+const LIBPAM: &CStr = c"libpam.so.0";
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+type ConvFn =
+    extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+#[repr(C)]
+struct PamConv {
+    conv: ConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+type StartFn =
+    unsafe extern "C" fn(*const c_char, *const c_char, *const PamConv, *mut *mut c_void) -> c_int;
+/// pam_authenticate, pam_acct_mgmt and pam_end
+type HandleFn = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type StrerrorFn = unsafe extern "C" fn(*mut c_void, c_int) -> *const c_char;
+
+/// the functions we need from libpam
+struct Lib {
+    start: StartFn,
+    authenticate: HandleFn,
+    acct_mgmt: HandleFn,
+    end: HandleFn,
+    strerror: StrerrorFn,
+}
+
+static LIB: OnceLock<Option<Lib>> = OnceLock::new();
+
+fn lib() -> Option<&'static Lib> {
+    LIB.get_or_init(|| unsafe {
+        let handle = libc::dlopen(LIBPAM.as_ptr(), libc::RTLD_NOW);
+        if handle.is_null() {
+            return None;
+        }
+        let sym = |name: &CStr| {
+            let f = libc::dlsym(handle, name.as_ptr());
+            (!f.is_null()).then_some(f)
+        };
+        Some(Lib {
+            start: std::mem::transmute::<*mut c_void, StartFn>(sym(c"pam_start")?),
+            authenticate: std::mem::transmute::<*mut c_void, HandleFn>(sym(c"pam_authenticate")?),
+            acct_mgmt: std::mem::transmute::<*mut c_void, HandleFn>(sym(c"pam_acct_mgmt")?),
+            end: std::mem::transmute::<*mut c_void, HandleFn>(sym(c"pam_end")?),
+            strerror: std::mem::transmute::<*mut c_void, StrerrorFn>(sym(c"pam_strerror")?),
+        })
+    })
+    .as_ref()
+}
+
+/// something PAM wants to tell the user or ask them
+pub enum Message<'a> {
+    /// a prompt whose answer shouldn't be shown (password, ...)
+    Secret(&'a str),
+    Visible(&'a str),
+    Error(&'a str),
+    Info(&'a str),
+}
+
+#[derive(Debug)]
+pub enum PamError {
+    /// libpam couldn't be loaded
+    Unavailable,
+    /// the service or user contains a 0 byte
+    InvalidName,
+    /// the client went away or sent something unexpected while PAM was asking it something
+    Conversation,
+    /// authentication or account check failed, with PAM's description
+    Denied(String),
+}
+impl std::fmt::Display for PamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unavailable => write!(f, "unavailable"),
+            Self::InvalidName => write!(f, "invalid_name"),
+            Self::Conversation => write!(f, "conversation"),
+            Self::Denied(e) => write!(f, "denied {e}"),
+        }
+    }
+}
+
+type Converse<'a> = &'a mut dyn FnMut(Message) -> Option<String>;
+
+/// Authenticates `user` using `service` (/etc/pam.d/<service>) and checks that the account is valid.
+/// `converse` is called for every message, and must return the answer to prompts (None aborts).
+pub fn authenticate(
+    service: &str,
+    user: &str,
+    mut converse: impl FnMut(Message) -> Option<String>,
+) -> Result<(), PamError> {
+    let lib = lib().ok_or(PamError::Unavailable)?;
+    let service = CString::new(service).map_err(|_| PamError::InvalidName)?;
+    let user = CString::new(user).map_err(|_| PamError::InvalidName)?;
+    let mut converse: Converse = &mut converse;
+    let conv = PamConv {
+        conv: conversation,
+        appdata_ptr: &mut converse as *mut Converse as *mut c_void,
+    };
+    let mut handle = std::ptr::null_mut();
+    let status = unsafe { (lib.start)(service.as_ptr(), user.as_ptr(), &conv, &mut handle) };
+    if status != PAM_SUCCESS {
+        return Err(PamError::Denied(describe(lib, handle, status)));
+    }
+    let mut status = unsafe { (lib.authenticate)(handle, 0) };
+    if status == PAM_SUCCESS {
+        status = unsafe { (lib.acct_mgmt)(handle, 0) };
+    }
+    let out = match status {
+        PAM_SUCCESS => Ok(()),
+        PAM_CONV_ERR => Err(PamError::Conversation),
+        status => Err(PamError::Denied(describe(lib, handle, status))),
+    };
+    unsafe { (lib.end)(handle, status) };
+    out
+}
+
+fn describe(lib: &Lib, handle: *mut c_void, status: c_int) -> String {
+    let s = unsafe { (lib.strerror)(handle, status) };
+    if s.is_null() {
+        format!("error {status}")
+    } else {
+        unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+    }
+}
+
+/// called by libpam. the responses must be allocated with malloc, because libpam frees them.
+extern "C" fn conversation(
+    count: c_int,
+    messages: *mut *const PamMessage,
+    responses: *mut *mut PamResponse,
+    appdata: *mut c_void,
+) -> c_int {
+    if count <= 0 || messages.is_null() || responses.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let converse = unsafe { &mut *(appdata as *mut Converse) };
+    let count = count as usize;
+    let out =
+        unsafe { libc::calloc(count, std::mem::size_of::<PamResponse>()) } as *mut PamResponse;
+    if out.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..count {
+        // linux-pam: an array of pointers
+        let message = unsafe { &**messages.add(i) };
+        let text = if message.msg.is_null() {
+            std::borrow::Cow::Borrowed("")
+        } else {
+            unsafe { CStr::from_ptr(message.msg) }.to_string_lossy()
+        };
+        let answer = match message.msg_style {
+            PAM_PROMPT_ECHO_OFF => converse(Message::Secret(&text)),
+            PAM_PROMPT_ECHO_ON => converse(Message::Visible(&text)),
+            PAM_ERROR_MSG => converse(Message::Error(&text)).or(Some(String::new())),
+            PAM_TEXT_INFO => converse(Message::Info(&text)).or(Some(String::new())),
+            _ => None,
+        };
+        let Some(answer) = answer.and_then(|a| CString::new(a).ok()) else {
+            unsafe { free_responses(out, i) };
+            return PAM_CONV_ERR;
+        };
+        if matches!(message.msg_style, PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON) {
+            let resp = unsafe { libc::strdup(answer.as_ptr()) };
+            if resp.is_null() {
+                unsafe { free_responses(out, i) };
+                return PAM_BUF_ERR;
+            }
+            unsafe { (*out.add(i)).resp = resp };
+        }
+    }
+    unsafe { *responses = out };
+    PAM_SUCCESS
+}
+
+/// frees the first `count` responses (overwriting them first, they might be passwords) and the array
+unsafe fn free_responses(responses: *mut PamResponse, count: usize) {
+    for i in 0..count {
+        let resp = (*responses.add(i)).resp;
+        if !resp.is_null() {
+            libc::memset(resp as *mut c_void, 0, libc::strlen(resp));
+            libc::free(resp as *mut c_void);
+        }
+    }
+    libc::free(responses as *mut c_void);
+}
@@ -1,9 +1,19 @@
 use std::{
+    borrow::Cow,
+    cell::RefCell,
     collections::HashMap,
     ffi::OsString,
     fmt::Display,
-    os::unix::process::CommandExt,
-    process::{Child, Command, Stdio},
+    os::unix::{ffi::OsStrExt, process::CommandExt},
+    process::{Child, Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config,
+    input::InputType,
+    logging::{debug, warn},
+    signals,
 };
 
 #[derive(Clone, Debug)]
@@ -17,21 +27,23 @@ pub struct RunCmd {
     pub working_dir: Option<String>,
     // pub chroot: Option<String>,
     pub command_clean: Vec<Self>,
+    pub command_reuse: Vec<Self>,
 }
+/// as config statements (variables are already replaced, users and groups are ids)
 impl std::fmt::Display for RunCmd {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "command {}", self.command)?;
         for a in &self.args {
-            writeln!(f, "arg {a}")?;
+            config::write_arg(f, a)?;
         }
-        writeln!(f, "user {}", self.user)?;
-        writeln!(f, "group {}", self.group)?;
+        writeln!(f, "uid {}", self.user)?;
+        writeln!(f, "gid {}", self.group)?;
         for g in &self.groups {
-            writeln!(f, "g+group {g}")?;
+            writeln!(f, "g+gid {g}")?;
         }
         for (n, v) in &self.env {
             match v {
-                Ok(s) => writeln!(f, "env+set {n}={}", s.to_string_lossy())?,
+                Ok(s) => config::write_env(f, n, &s.to_string_lossy())?,
                 Err(None) => writeln!(f, "env+inherit {n}")?,
                 Err(Some(v)) => writeln!(f, "env+inherit {n}={v}")?,
             }
@@ -39,37 +51,309 @@ impl std::fmt::Display for RunCmd {
         if let Some(wd) = &self.working_dir {
             writeln!(f, "working-dir {wd}")?;
         }
+        for cmd in &self.command_clean {
+            write!(f, "cmd-clean\n{cmd}end\n")?;
+        }
+        for cmd in &self.command_reuse {
+            write!(f, "cmd-reuse\n{cmd}end\n")?;
+        }
         Ok(())
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Clone, Default, Debug)]
 pub struct RunCmdBuilder {
+    /// `include-only`: only used via `config <name>` by other configs, so it isn't loaded itself
+    pub include_only: bool,
     // vars
     pub vars: Vec<(String, VarValue)>,
+    /// how variables are used in strings (None = like the config containing this one, or the server's default)
+    pub var_syntax: Option<VarSyntax>,
+    /// input name -> what the client may send for it
+    pub input_types: Vec<(String, InputType)>,
     // access
     pub allow: Option<String>,
+    /// `auth pam <service>`: the client's user must also authenticate via PAM
+    pub pam_service: Option<String>,
+    /// if set, an admin has to approve each run (within this time)
+    pub require_approval: Option<Duration>,
+    // limits
+    /// max. number of sessions of this config running at the same time
+    pub max_instances: Option<usize>,
+    /// max. number of sessions of this config running at the same time, per client uid
+    pub max_per_user: Option<usize>,
+    // singleton
+    /// what happens if this config is run while a session of it is still running (None = start another one)
+    pub singleton: Option<Singleton>,
+    /// only sessions started by the same client uid count (`singleton-per-user`)
+    pub singleton_per_user: bool,
     // prep and clean (runs before/after command)
     pub command_prep: Vec<Self>,
     pub command_clean: Vec<Self>,
+    /// runs instead of the command if `singleton reuse` found a running session
+    pub command_reuse: Vec<Self>,
     // what to run
     pub command: Option<String>,
     pub args: Vec<String>,
     pub user: Option<Result<u32, String>>,
-    pub group: Option<Result<u32, String>>,
-    pub groups: Vec<Result<u32, String>>,
+    pub group: Option<GroupSpec>,
+    pub groups: Vec<GroupSpec>,
     pub env: Vec<(String, Result<String, Option<String>>)>,
     pub working_dir: Option<String>,
     // pub chroot: Option<Option<String>>,
+    /// `if` blocks, decided when the config is run
+    pub conditionals: Vec<Conditional>,
 }
 
-#[derive(Debug)]
+/// `if <condition>` ... [`elif <condition>` ...] [`else` ...] `endif`
+#[derive(Clone, Debug)]
+pub struct Conditional {
+    /// the statements of the first branch whose condition is true are used
+    pub branches: Vec<(Condition, RunCmdBuilder)>,
+    /// `else`
+    pub otherwise: Option<RunCmdBuilder>,
+    /// how many args, groups and env entries came before the `if`, the branch's are inserted there
+    pub position: (usize, usize, usize),
+}
+
+#[derive(Clone, Debug)]
+pub enum Condition {
+    /// the client sent this input
+    Input(String),
+    /// `var NAME == value` (true) or `var NAME != value` (false)
+    Var(String, String, bool),
+    /// this path (which can contain variables) exists
+    Exists(String),
+    /// the client's user is in this group
+    UserInGroup(String),
+    Not(Box<Self>),
+}
+impl std::fmt::Display for Condition {
+    /// the same syntax `parse` accepts
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Not(c) => write!(f, "not {c}"),
+            Self::Input(name) => write!(f, "input {name}"),
+            Self::Var(name, value, true) => write!(f, "var {name} == {value}"),
+            Self::Var(name, value, false) => write!(f, "var {name} != {value}"),
+            Self::Exists(path) => write!(f, "exists {path}"),
+            Self::UserInGroup(group) => write!(f, "user-in-group {group}"),
+        }
+    }
+}
+impl Condition {
+    /// `[not] input <name>|var <name> ==|!= <value>|exists <path>|user-in-group <group>`
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (kind, right) = s.split_once(' ').unwrap_or((s, ""));
+        if right.is_empty() {
+            return Err(format!("'{kind}' needs an argument"));
+        }
+        Ok(match kind {
+            "not" => Self::Not(Box::new(Self::parse(right)?)),
+            "input" => Self::Input(right.to_owned()),
+            "var" => {
+                let (name, op, value) = right
+                    .split_once(" == ")
+                    .map(|(n, v)| (n, true, v))
+                    .or_else(|| right.split_once(" != ").map(|(n, v)| (n, false, v)))
+                    .ok_or_else(|| {
+                        format!("expected 'var <name> == <value>', got 'var {right}'")
+                    })?;
+                Self::Var(name.to_owned(), value.to_owned(), op)
+            }
+            "exists" => Self::Exists(right.to_owned()),
+            "user-in-group" => Self::UserInGroup(right.to_owned()),
+            kind => return Err(format!("unknown condition '{kind}'")),
+        })
+    }
+    fn eval(
+        &self,
+        input_vars: &HashMap<String, String>,
+        vars_all: &[(String, String)],
+        replace_variables_in_str: impl Fn(&str) -> String + Copy,
+        client_uid: Option<u32>,
+    ) -> bool {
+        match self {
+            Self::Not(c) => !c.eval(input_vars, vars_all, replace_variables_in_str, client_uid),
+            Self::Input(name) => input_vars.contains_key(name),
+            Self::Var(name, value, eq) => {
+                vars_all.iter().any(|(n, v)| n == name && v == value) == *eq
+            }
+            Self::Exists(path) => std::path::Path::new(&replace_variables_in_str(path)).exists(),
+            Self::UserInGroup(group) => client_uid
+                .and_then(users::get_user_by_uid)
+                .and_then(|user| user_groups(&user))
+                .zip(users::get_group_by_name(group))
+                .is_some_and(|(groups, group)| groups.contains(&group.gid())),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Singleton {
+    /// `run error_singleton_running <session>`
+    Refuse,
+    /// the client waits until the running session ends
+    Attach,
+    /// runs the `cmd-reuse` commands (with the new input vars), for example to open a new tab
+    Reuse,
+}
+impl std::fmt::Display for Singleton {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Refuse => write!(f, "refuse"),
+            Self::Attach => write!(f, "attach"),
+            Self::Reuse => write!(f, "reuse"),
+        }
+    }
+}
+impl Singleton {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "refuse" => Self::Refuse,
+            "attach" => Self::Attach,
+            "reuse" => Self::Reuse,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum VarSyntax {
+    /// every occurrence of a variable's name is replaced (`%URL`)
+    Legacy,
+    /// `${NAME}`, `${NAME:-default}`, and `$$` for a `$`
+    Braced,
+}
+impl std::fmt::Display for VarSyntax {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Legacy => write!(f, "legacy"),
+            Self::Braced => write!(f, "braced"),
+        }
+    }
+}
+impl VarSyntax {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "legacy" => Self::Legacy,
+            "braced" => Self::Braced,
+            _ => return None,
+        })
+    }
+}
+
+/// `gid`/`group` and the `g+...` statements
+#[derive(Clone, Debug)]
+pub enum GroupSpec {
+    Id(u32),
+    /// can contain variables
+    Name(String),
+    /// `group <name> optional`: the user's primary group if the group doesn't exist,
+    /// `g+group <name> optional`: left out if the group doesn't exist
+    OptionalName(String),
+    /// `group from-user`: the user's primary group, `g+from-user`: all groups the user is in
+    FromUser,
+}
+impl GroupSpec {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Name(name) | Self::OptionalName(name) => Some(name),
+            Self::Id(_) | Self::FromUser => None,
+        }
+    }
+}
+
+/// `from-cmd [--user <name>] [--timeout <seconds>] [--keep-newline] <program> [args...]` (also `from-cmd-sh`)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CmdOptions {
+    /// who runs the command (default: nobody)
+    pub user: Option<String>,
+    /// after this, the command is killed and the var fails (default: `DEFAULT_CMD_TIMEOUT`)
+    pub timeout: Option<Duration>,
+    /// keep the output's trailing newlines
+    pub keep_newline: bool,
+}
+/// the options as they are written in the config, each followed by a space
+impl Display for CmdOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(user) = &self.user {
+            write!(f, "--user {user} ")?;
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, "--timeout {} ", timeout.as_secs())?;
+        }
+        if self.keep_newline {
+            write!(f, "--keep-newline ")?;
+        }
+        Ok(())
+    }
+}
+const DEFAULT_CMD_TIMEOUT: Duration = Duration::from_secs(10);
+/// the only environment variables a `from-cmd` command gets, besides HOME, USER and LOGNAME
+const CMD_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// what `to_runcmd_` runs
+#[derive(Clone, Copy)]
+enum Mode<'a> {
+    /// the `cmd-prep` blocks and `from-cmd` vars
+    Run,
+    /// only the `from-cmd` vars (for `singleton reuse`)
+    NoPrep,
+    /// nothing, what would run is recorded instead
+    DryRun(&'a RefCell<DryRun>),
+}
+
+/// what running a config would do, without running anything (`--dry-run`, and checking configs)
+#[derive(Default, Debug)]
+pub struct DryRun {
+    /// the `from-cmd` vars: name, command, args, options
+    pub var_cmds: Vec<(String, String, Vec<String>, CmdOptions)>,
+    pub prep: Vec<RunCmd>,
+}
+/// as comments and config statements, the `from-cmd` outputs are shown as `<output of NAME>`
+impl Display for DryRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, exec, args, options) in &self.var_cmds {
+            let cmd: Vec<_> = std::iter::once(exec)
+                .chain(args)
+                .map(|a| config::quote(a))
+                .collect();
+            writeln!(
+                f,
+                "# var {name}: runs {} as {} (timeout {}s)",
+                cmd.join(" "),
+                options.user.as_deref().unwrap_or("nobody"),
+                options.timeout.unwrap_or(DEFAULT_CMD_TIMEOUT).as_secs()
+            )?;
+        }
+        for cmd in &self.prep {
+            write!(f, "cmd-prep\n{cmd}end\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum VarValue {
     Val(String),
-    OutputOf(String, Vec<String>),
+    OutputOf(String, Vec<String>, CmdOptions),
     Input(String),
     InputOrDefault(String, Box<Self>),
     ConId,
+    /// the file's contents, trimmed (the path can contain variables)
+    FromFile(String),
+    /// the server's environment variable
+    ServerEnv(String),
+    /// the client's uid, user name, home directory or pid (from the socket, so the client can't fake them)
+    ClientUid,
+    ClientUser,
+    ClientHome,
+    ClientPid,
+    /// the current local time, formatted with strftime
+    Timestamp(String),
+    /// this many random hex digits
+    RandomHex(usize),
 }
 
 #[derive(Debug)]
@@ -78,50 +362,125 @@ pub enum ToRunCmdError {
     MissingFieldCommand,
     MissingFieldUser,
     MissingFieldGroup,
+    /// `singleton reuse` without any `cmd-reuse`
+    MissingCmdReuse,
     // variables
     VarFailedToRun(String, Vec<String>),
+    /// the command, its exit status, its stderr
+    VarCmdFailed(String, String, String),
+    VarCmdTimeout(String, Duration),
     VarMissingInput(String),
+    /// input, value, what was expected
+    VarInvalidInput(String, String, String),
+    /// `${NAME}`, but there is no var NAME (and no default)
+    VarUndefined(String),
+    /// a `${` without `}`, in this string
+    VarUnterminated(String),
+    /// path, error
+    VarFailedToRead(String, String),
+    VarMissingServerEnv(String),
+    /// a client-... var, but the client is unknown
+    VarNoClient(String),
     // unknown user/group
     UnknownUser(String),
     UnknownGroup(String),
 }
 
+#[derive(Clone, Copy)]
 pub struct ToRunCmdInfo {
     pub con_id: u128,
+    /// used if the config doesn't specify a `var-syntax`
+    pub var_syntax: VarSyntax,
+    /// None when checking a config
+    pub client_uid: Option<u32>,
+    pub client_pid: Option<i32>,
 }
 
 impl RunCmdBuilder {
+    /// this config with the statements of the `if` branches whose conditions are true.
+    /// args, groups and env are inserted where the `if` was, everything else set in a branch takes precedence.
+    fn resolve_conditionals(&self, test: &impl Fn(&Condition) -> bool) -> Cow<'_, Self> {
+        if self.conditionals.is_empty() {
+            return Cow::Borrowed(self);
+        }
+        let chosen: Vec<_> = self
+            .conditionals
+            .iter()
+            .filter_map(|c| {
+                let branch = c
+                    .branches
+                    .iter()
+                    .find(|(condition, _)| test(condition))
+                    .map(|(_, branch)| branch)
+                    .or(c.otherwise.as_ref())?;
+                Some((c.position, branch.resolve_conditionals(test)))
+            })
+            .collect();
+        let mut out = Self {
+            conditionals: vec![],
+            ..self.clone()
+        };
+        // from the end, so the positions of the earlier ones stay valid
+        for ((args, groups, env), branch) in chosen.iter().rev() {
+            out.args.splice(args..args, branch.args.iter().cloned());
+            out.groups
+                .splice(groups..groups, branch.groups.iter().cloned());
+            out.env.splice(env..env, branch.env.iter().cloned());
+        }
+        for (_, branch) in chosen {
+            let branch = branch.into_owned();
+            out.command = branch.command.or(out.command);
+            out.user = branch.user.or(out.user);
+            out.group = branch.group.or(out.group);
+            out.working_dir = branch.working_dir.or(out.working_dir);
+            out.command_prep.extend(branch.command_prep);
+            out.command_clean.extend(branch.command_clean);
+            out.command_reuse.extend(branch.command_reuse);
+        }
+        Cow::Owned(out)
+    }
     pub fn to_runcmd(
         &self,
         vars: &HashMap<String, String>,
         info: &ToRunCmdInfo,
     ) -> Result<RunCmd, Vec<ToRunCmdError>> {
-        self.to_runcmd_wrapper(vars, info, None, false)
+        self.to_runcmd_wrapper(vars, info, None, Mode::Run)
     }
-    pub fn to_runcmd_check(
+    /// for `singleton reuse`: doesn't run the `cmd-prep` blocks
+    pub fn to_runcmd_without_prep(
         &self,
         vars: &HashMap<String, String>,
         info: &ToRunCmdInfo,
     ) -> Result<RunCmd, Vec<ToRunCmdError>> {
-        self.to_runcmd_wrapper(vars, info, None, true)
+        self.to_runcmd_wrapper(vars, info, None, Mode::NoPrep)
     }
-    pub fn to_runcmd_with_existing_vars(
+    /// doesn't run anything (`from-cmd` vars are `<output of NAME>`)
+    pub fn to_runcmd_check(
         &self,
         vars: &HashMap<String, String>,
         info: &ToRunCmdInfo,
-        existing_vars: &Vec<(String, String)>,
     ) -> Result<RunCmd, Vec<ToRunCmdError>> {
-        self.to_runcmd_wrapper(vars, info, Some(existing_vars), false)
+        self.dry_run(vars, info).map(|(cmd, _)| cmd)
+    }
+    /// like `to_runcmd_check`, but also returns what would have run
+    pub fn dry_run(
+        &self,
+        vars: &HashMap<String, String>,
+        info: &ToRunCmdInfo,
+    ) -> Result<(RunCmd, DryRun), Vec<ToRunCmdError>> {
+        let dry_run = RefCell::new(DryRun::default());
+        let cmd = self.to_runcmd_wrapper(vars, info, None, Mode::DryRun(&dry_run))?;
+        Ok((cmd, dry_run.into_inner()))
     }
     fn to_runcmd_wrapper(
         &self,
         vars: &HashMap<String, String>,
         info: &ToRunCmdInfo,
         existing_vars: Option<&Vec<(String, String)>>,
-        just_check: bool,
+        mode: Mode,
     ) -> Result<RunCmd, Vec<ToRunCmdError>> {
         let mut errors = Vec::new();
-        let o = self.to_runcmd_(vars, existing_vars, info, &mut errors, just_check);
+        let o = self.to_runcmd_(vars, existing_vars, info, &mut errors, mode);
         if errors.is_empty() {
             if let Some(o) = o {
                 Ok(o)
@@ -145,7 +504,13 @@ impl RunCmdBuilder {
                     match e {
                         // these can still change -> not fatal
                         ToRunCmdError::VarFailedToRun(..)
+                        | ToRunCmdError::VarCmdFailed(..)
+                        | ToRunCmdError::VarCmdTimeout(..)
                         | ToRunCmdError::VarMissingInput(..)
+                        | ToRunCmdError::VarInvalidInput(..)
+                        | ToRunCmdError::VarFailedToRead(..)
+                        | ToRunCmdError::VarMissingServerEnv(_)
+                        | ToRunCmdError::VarNoClient(_)
                         | ToRunCmdError::UnknownUser(_)
                         | ToRunCmdError::UnknownGroup(_) => nf.push(e),
                         e => fatal.push(e),
@@ -165,7 +530,7 @@ impl RunCmdBuilder {
         existing_vars: Option<&Vec<(String, String)>>,
         info: &ToRunCmdInfo,
         es: &mut Vec<ToRunCmdError>,
-        just_check: bool,
+        mode: Mode,
     ) -> Option<RunCmd> {
         fn er<T>(r: Result<T, ToRunCmdError>, def: T, errors: &mut Vec<ToRunCmdError>) -> T {
             match r {
@@ -184,19 +549,33 @@ impl RunCmdBuilder {
             input_vars: &HashMap<String, String>,
             info: &ToRunCmdInfo,
             vars: &Vec<(String, String)>,
+            var_errors: &RefCell<Vec<ToRunCmdError>>,
+            mode: Mode,
         ) -> Result<String, ToRunCmdError> {
             let (key, value) = v;
+            let client_var = matches!(
+                value,
+                VarValue::ClientUid
+                    | VarValue::ClientUser
+                    | VarValue::ClientHome
+                    | VarValue::ClientPid
+            );
+            if client_var && info.client_uid.is_none() && matches!(mode, Mode::DryRun(_)) {
+                // checking a config, there is no client yet
+                return Ok(format!("<client's {key}>"));
+            }
             Ok(match value {
-                VarValue::Val(v) => replace_variables_in_str_given_vars(v, vars),
-                VarValue::OutputOf(exec, args) => {
-                    let mut cmd = Command::new(exec);
-                    cmd.args(args);
-                    if let Ok(output) = cmd.output() {
-                        String::from_utf8_lossy(&output.stdout).into_owned()
-                    } else {
-                        return Err(ToRunCmdError::VarFailedToRun(exec.to_owned(), args.clone()));
-                    }
+                VarValue::Val(v) => replace_variables(v, vars, info.var_syntax, var_errors),
+                VarValue::OutputOf(exec, args, options) if let Mode::DryRun(dry_run) = mode => {
+                    dry_run.borrow_mut().var_cmds.push((
+                        key.to_owned(),
+                        exec.to_owned(),
+                        args.clone(),
+                        options.clone(),
+                    ));
+                    format!("<output of {key}>")
                 }
+                VarValue::OutputOf(exec, args, options) => run_var_cmd(exec, args, options)?,
                 VarValue::Input(arg_name) => {
                     if let Some(val) = input_vars.get(arg_name) {
                         val.to_owned()
@@ -208,148 +587,230 @@ impl RunCmdBuilder {
                     if let Some(val) = input_vars.get(arg_name) {
                         val.to_owned()
                     } else {
-                        map_var_fn((key, default), input_vars, info, vars)?
+                        map_var_fn((key, default), input_vars, info, vars, var_errors, mode)?
                     }
                 }
                 VarValue::ConId => format!("{}", info.con_id),
+                VarValue::FromFile(path) => {
+                    let path = replace_variables(path, vars, info.var_syntax, var_errors);
+                    match std::fs::read_to_string(&path) {
+                        Ok(s) => s.trim().to_owned(),
+                        Err(e) => return Err(ToRunCmdError::VarFailedToRead(path, e.to_string())),
+                    }
+                }
+                VarValue::ServerEnv(name) => match std::env::var(name) {
+                    Ok(v) => v,
+                    Err(_) => return Err(ToRunCmdError::VarMissingServerEnv(name.to_owned())),
+                },
+                VarValue::ClientUid => match info.client_uid {
+                    Some(uid) => uid.to_string(),
+                    None => return Err(ToRunCmdError::VarNoClient(key.to_owned())),
+                },
+                VarValue::ClientUser | VarValue::ClientHome => {
+                    let Some(uid) = info.client_uid else {
+                        return Err(ToRunCmdError::VarNoClient(key.to_owned()));
+                    };
+                    let Some(user) = users::get_user_by_uid(uid) else {
+                        return Err(ToRunCmdError::UnknownUser(uid.to_string()));
+                    };
+                    if matches!(value, VarValue::ClientUser) {
+                        user.name().to_string_lossy().into_owned()
+                    } else {
+                        users::os::unix::UserExt::home_dir(&user)
+                            .to_string_lossy()
+                            .into_owned()
+                    }
+                }
+                VarValue::ClientPid => match info.client_pid {
+                    Some(pid) => pid.to_string(),
+                    None => return Err(ToRunCmdError::VarNoClient(key.to_owned())),
+                },
+                VarValue::Timestamp(format) => timestamp(format),
+                VarValue::RandomHex(len) => {
+                    let mut bytes = vec![0; len.div_ceil(2)];
+                    if let Err(e) = std::fs::File::open("/dev/urandom")
+                        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut bytes))
+                    {
+                        return Err(ToRunCmdError::VarFailedToRead(
+                            "/dev/urandom".to_owned(),
+                            e.to_string(),
+                        ));
+                    }
+                    let mut hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+                    hex.truncate(*len);
+                    hex
+                }
             })
         }
-        let mut vars_all: Vec<(String, String)> = {
-            let mut vars_all = existing_vars.cloned().unwrap_or_else(|| vec![]);
-            vars_all.reserve(self.vars.len());
-            for (var, val) in self.vars.iter() {
-                let v = map_var_fn((var, val), input_vars, info, &vars_all);
-                if let Some(i) = vars_all.iter().position(|(n, _)| n == var) {
-                    vars_all.remove(i);
+        // nested configs (cmd-prep, ...) use this config's syntax, unless they specify their own
+        let info = &ToRunCmdInfo {
+            var_syntax: self.var_syntax.unwrap_or(info.var_syntax),
+            ..*info
+        };
+        let collect_vars =
+            |mode: Mode, var_errors: &RefCell<Vec<ToRunCmdError>>, es: &mut Vec<ToRunCmdError>| {
+                let mut vars_all = existing_vars.cloned().unwrap_or_default();
+                vars_all.reserve(self.vars.len());
+                for (var, val) in self.vars.iter() {
+                    let v = map_var_fn((var, val), input_vars, info, &vars_all, var_errors, mode);
+                    if let Some(i) = vars_all.iter().position(|(n, _)| n == var) {
+                        vars_all.remove(i);
+                    }
+                    vars_all.push((var.to_owned(), erd(v, es)));
+                }
+                vars_all.sort_by(|(a, _), (b, _)| a.cmp(b));
+                vars_all
+            };
+        // the input is checked before the `from-cmd` vars run, so path-under's directory
+        // sees them as `<output of NAME>` (the errors are reported by the real run below)
+        {
+            let vars_checked = collect_vars(
+                Mode::DryRun(&RefCell::default()),
+                &RefCell::default(),
+                &mut vec![],
+            );
+            let var_errors = RefCell::new(vec![]);
+            for (input, ty) in &self.input_types {
+                if let Some(value) = input_vars.get(input) {
+                    let replace_variables_in_str = |val: &str| {
+                        replace_variables(val, &vars_checked, info.var_syntax, &var_errors)
+                    };
+                    if let Err(expected) = ty.check(value, replace_variables_in_str) {
+                        es.push(ToRunCmdError::VarInvalidInput(
+                            input.to_owned(),
+                            value.to_owned(),
+                            expected,
+                        ));
+                    }
                 }
-                vars_all.push((var.to_owned(), erd(v, es)));
             }
-            vars_all
+            // don't run anything if the input is invalid
+            if !es.is_empty() && !matches!(mode, Mode::DryRun(_)) {
+                return None;
+            }
+        }
+        let var_errors = RefCell::new(vec![]);
+        let vars_all = collect_vars(mode, &var_errors, es);
+        let replace_variables_in_str =
+            |val: &str| replace_variables(val, &vars_all, info.var_syntax, &var_errors);
+        let this = self.resolve_conditionals(&|c: &Condition| {
+            c.eval(
+                input_vars,
+                &vars_all,
+                replace_variables_in_str,
+                info.client_uid,
+            )
+        });
+        if matches!(this.singleton, Some(Singleton::Reuse)) && this.command_reuse.is_empty() {
+            es.push(ToRunCmdError::MissingCmdReuse);
+        }
+        es.extend(var_errors.take());
+        // the blocks run nothing in a dry run either, the others run their `from-cmd` vars
+        let nested = match mode {
+            Mode::DryRun(dry_run) => Mode::DryRun(dry_run),
+            Mode::Run | Mode::NoPrep => Mode::Run,
         };
-        vars_all.sort_by(|(a, _), (b, _)| a.cmp(b));
-        // makes variables work in this string
-        fn replace_variables_in_str_given_vars(
-            val: &str,
-            vars_all: &Vec<(String, String)>,
-        ) -> String {
-            let mut out = String::new();
-            let mut vars_local: Vec<(Vec<char>, _, usize, usize)> = vars_all
-                .iter()
-                .filter(|(key, _)| !key.is_empty())
-                .map(|(key, val)| (key.chars().collect(), val, 0, 0))
-                .collect();
-            for (byte_pos, ch) in val.char_indices() {
-                out.push(ch);
-                for (var_name, var_value, start, len) in vars_local.iter_mut() {
-                    if ch == var_name[*len] {
-                        if *len == 0 {
-                            *start = byte_pos;
+        match mode {
+            Mode::Run => {
+                // don't run anything if the input (or any other var) is invalid
+                if !es.is_empty() {
+                    return None;
+                }
+                for cmd in &this.command_prep {
+                    match cmd.to_runcmd_wrapper(input_vars, info, Some(&vars_all), mode) {
+                        Ok(v) => {
+                            Runner::new_prep_or_clean(v).start().wait();
                         }
-                        *len += 1;
-                        if *len >= var_name.len() {
-                            // end of varname
-                            // remove the varname from the string (it has already been .push()ed)
-                            out.truncate(*start);
-                            // then add the variables value
-                            out.push_str(var_value);
-                            // reset the len of everything
-                            for (_, _, _, len) in vars_local.iter_mut() {
-                                *len = 0;
-                            }
-                            // no need to check the other variables
-                            break;
+                        Err(e) => {
+                            es.extend(e);
+                            return None;
                         }
-                    } else {
-                        *len = 0;
                     }
                 }
             }
-            out
-        }
-        let replace_variables_in_str =
-            |val: &str| replace_variables_in_str_given_vars(val, &vars_all);
-        if !just_check {
-            for cmd in &self.command_prep {
-                match cmd.to_runcmd_with_existing_vars(input_vars, info, &vars_all) {
-                    Ok(v) => Runner::new_prep_or_clean(v).start().wait(),
-                    Err(e) => {
-                        es.extend(e);
-                        return None;
+            Mode::NoPrep => (),
+            Mode::DryRun(dry_run) => {
+                for cmd in &this.command_prep {
+                    match cmd.to_runcmd_wrapper(input_vars, info, Some(&vars_all), mode) {
+                        Ok(v) => dry_run.borrow_mut().prep.push(v),
+                        Err(e) => es.extend(e),
                     }
                 }
             }
         }
-        Some(RunCmd {
+        let user = match er(
+            this.user.clone().ok_or(ToRunCmdError::MissingFieldUser),
+            Ok(0),
+            es,
+        ) {
+            Ok(id) => id,
+            Err(name) => {
+                let name = replace_variables_in_str(&name);
+                erd(
+                    match users::get_user_by_name(&name) {
+                        Some(user) => Ok(user.uid()),
+                        None => Err(ToRunCmdError::UnknownUser(name)),
+                    },
+                    es,
+                )
+            }
+        };
+        let group_by_name = |name: &str| {
+            let name = replace_variables_in_str(name);
+            match users::get_group_by_name(&name) {
+                Some(group) => Ok(group.gid()),
+                None => Err(ToRunCmdError::UnknownGroup(name)),
+            }
+        };
+        // for `from-user`
+        let user_entry =
+            || users::get_user_by_uid(user).ok_or(ToRunCmdError::UnknownUser(user.to_string()));
+        let group = match er(
+            this.group.clone().ok_or(ToRunCmdError::MissingFieldGroup),
+            GroupSpec::Id(0),
+            es,
+        ) {
+            GroupSpec::Id(id) => id,
+            GroupSpec::Name(name) => erd(group_by_name(&name), es),
+            GroupSpec::OptionalName(name) => erd(
+                group_by_name(&name).or_else(|_| user_entry().map(|u| u.primary_group_id())),
+                es,
+            ),
+            GroupSpec::FromUser => erd(user_entry().map(|u| u.primary_group_id()), es),
+        };
+        let mut groups = Vec::with_capacity(this.groups.len());
+        for g in &this.groups {
+            match g {
+                GroupSpec::Id(id) => groups.push(*id),
+                GroupSpec::Name(name) => groups.push(erd(group_by_name(name), es)),
+                GroupSpec::OptionalName(name) => groups.extend(group_by_name(name).ok()),
+                GroupSpec::FromUser => {
+                    let user_groups = user_entry().and_then(|u| {
+                        user_groups(&u).ok_or_else(|| {
+                            ToRunCmdError::UnknownUser(u.name().to_string_lossy().into_owned())
+                        })
+                    });
+                    groups.extend(erd(user_groups, es));
+                }
+            }
+        }
+        let runcmd = RunCmd {
             command: erd(
-                self.command
+                this.command
                     .as_ref()
                     .map(|v| replace_variables_in_str(v))
-                    .ok_or_else(|| ToRunCmdError::MissingFieldCommand),
+                    .ok_or(ToRunCmdError::MissingFieldCommand),
                 es,
             ),
-            args: self
+            args: this
                 .args
                 .iter()
                 .map(|v| replace_variables_in_str(v))
                 .collect(),
-            user: match er(
-                self.user
-                    .clone()
-                    .ok_or_else(|| ToRunCmdError::MissingFieldUser),
-                Ok(0),
-                es,
-            ) {
-                Ok(id) => id,
-                Err(name) => {
-                    let name = replace_variables_in_str(&name);
-                    erd(
-                        match users::get_user_by_name(&name) {
-                            Some(user) => Ok(user.uid()),
-                            None => Err(ToRunCmdError::UnknownUser(name)),
-                        },
-                        es,
-                    )
-                }
-            },
-            group: match er(
-                self.user
-                    .clone()
-                    .ok_or_else(|| ToRunCmdError::MissingFieldGroup),
-                Ok(0),
-                es,
-            ) {
-                Ok(id) => id,
-                Err(name) => {
-                    let name = replace_variables_in_str(&name);
-                    erd(
-                        match users::get_group_by_name(&name) {
-                            Some(group) => Ok(group.gid()),
-                            None => Err(ToRunCmdError::UnknownGroup(name)),
-                        },
-                        es,
-                    )
-                }
-            },
-            groups: {
-                let mut o = Vec::with_capacity(self.groups.len());
-                for group in self.groups.iter() {
-                    o.push(match group {
-                        Ok(v) => *v,
-                        Err(name) => {
-                            let name = replace_variables_in_str(name);
-                            erd(
-                                match users::get_group_by_name(&name) {
-                                    Some(group) => Ok(group.gid()),
-                                    None => Err(ToRunCmdError::UnknownGroup(name)),
-                                },
-                                es,
-                            )
-                        }
-                    });
-                }
-                o
-            },
-            env: self
+            user,
+            group,
+            groups,
+            env: this
                 .env
                 .iter()
                 .map(|(name, val)| {
@@ -363,24 +824,40 @@ impl RunCmdBuilder {
                     )
                 })
                 .collect(),
-            working_dir: self
+            working_dir: this
                 .working_dir
                 .as_ref()
                 .map(|v| replace_variables_in_str(v)),
-            command_clean: self
+            command_clean: this
                 .command_clean
                 .iter()
-                .filter_map(
-                    |v| match v.to_runcmd_with_existing_vars(input_vars, info, &vars_all) {
+                .filter_map(|v| {
+                    match v.to_runcmd_wrapper(input_vars, info, Some(&vars_all), nested) {
                         Ok(v) => Some(v),
                         Err(e) => {
                             es.extend(e);
                             None
                         }
-                    },
-                )
+                    }
+                })
                 .collect(),
-        })
+            command_reuse: this
+                .command_reuse
+                .iter()
+                .filter_map(|v| {
+                    match v.to_runcmd_wrapper(input_vars, info, Some(&vars_all), nested) {
+                        Ok(v) => Some(v),
+                        Err(e) => {
+                            es.extend(e);
+                            None
+                        }
+                    }
+                })
+                .collect(),
+        };
+        // undefined vars in the fields above
+        es.extend(var_errors.take());
+        Some(runcmd)
     }
 }
 
@@ -416,7 +893,14 @@ impl Runner {
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped());
         }
-        command.groups(cmd.groups.as_slice().into());
+        command.groups(cmd.groups.as_slice());
+        // the server blocks some signals (see signals.rs), the child shouldn't inherit that
+        unsafe {
+            command.pre_exec(|| {
+                signals::unblock();
+                Ok(())
+            });
+        }
         command.env_clear();
         command.envs(cmd.env.iter().filter_map(|(name, val)| {
             Some((
@@ -438,7 +922,7 @@ impl Runner {
         if let Some(dir) = &cmd.working_dir {
             command.current_dir(dir);
         }
-        eprintln!(
+        debug!(
             "Spawning {:?}:\n    args: {:?}\n     env: {:?}\n   cwdir: {:?}",
             command.get_program(),
             command.get_args(),
@@ -446,35 +930,38 @@ impl Runner {
             command.get_current_dir(),
         );
         if !self.is_inner {
-            eprintln!(" ~ ~ ~ ~ ~ running...");
+            debug!(" ~ ~ ~ ~ ~ running...");
         }
         match command.spawn() {
             Ok(child_proc) => self.child_process = Some(child_proc),
             Err(e) => {
-                eprintln!("[WARN] failed to spawn child process: {e:?}");
+                warn!("failed to spawn child process: {e:?}");
             }
         }
         self
     }
     /// note: automatically called on drop - to prevent blocking, run wait in a thread and move the runner to that thread.
-    pub fn wait(&mut self) {
+    /// returns the main command's exit status (None if it couldn't be spawned).
+    pub fn wait(&mut self) -> Option<ExitStatus> {
+        let mut status = None;
         if let Some(v) = &mut self.child_process {
-            _ = v.wait();
+            status = v.wait().ok();
             if !self.is_inner {
-                eprintln!(" ~ ~ ~ ~ ~ cleaning...");
+                debug!(" ~ ~ ~ ~ ~ cleaning...");
             }
-            for cmd in std::mem::replace(&mut self.cmd.command_clean, vec![]) {
+            for cmd in std::mem::take(&mut self.cmd.command_clean) {
                 Runner::new_prep_or_clean(cmd).start().wait();
             }
             if !self.is_inner {
-                eprintln!(" ~ ~ ~ ~ ~ done.");
+                debug!(" ~ ~ ~ ~ ~ done.");
             }
         }
+        status
     }
 }
 impl Drop for Runner {
     fn drop(&mut self) {
-        self.wait()
+        self.wait();
     }
 }
 
@@ -484,12 +971,462 @@ impl Display for ToRunCmdError {
             Self::MissingFieldCommand => write!(f, "missing field 'command'"),
             Self::MissingFieldUser => write!(f, "missing field 'user'"),
             Self::MissingFieldGroup => write!(f, "missing field 'group'"),
+            Self::MissingCmdReuse => write!(f, "'singleton reuse' requires a 'cmd-reuse'"),
             Self::VarFailedToRun(exec, args) => {
                 write!(f, "var: failed to run command {exec:?} with args {args:?}")
             }
+            Self::VarCmdFailed(exec, status, stderr) if stderr.is_empty() => {
+                write!(f, "var: command {exec:?} failed ({status})")
+            }
+            Self::VarCmdFailed(exec, status, stderr) => {
+                write!(f, "var: command {exec:?} failed ({status}): {stderr}")
+            }
+            Self::VarCmdTimeout(exec, timeout) => write!(
+                f,
+                "var: command {exec:?} didn't finish within {} seconds",
+                timeout.as_secs()
+            ),
             Self::VarMissingInput(input) => write!(f, "var: missing input '{input}'"),
+            Self::VarInvalidInput(input, value, expected) => {
+                write!(
+                    f,
+                    "var: invalid input '{input}': '{value}' is not {expected}"
+                )
+            }
+            Self::VarUndefined(name) => write!(f, "var: '${{{name}}}' is not defined"),
+            Self::VarUnterminated(s) => write!(f, "var: missing '}}' after '${{' in '{s}'"),
+            Self::VarFailedToRead(path, e) => write!(f, "var: failed to read '{path}': {e}"),
+            Self::VarMissingServerEnv(name) => {
+                write!(f, "var: the server has no environment variable '{name}'")
+            }
+            Self::VarNoClient(name) => write!(f, "var: {name} needs a client"),
             Self::UnknownUser(n) => write!(f, "unknown user '{n}' (couldn't find uid)!"),
             Self::UnknownGroup(n) => write!(f, "unknown group '{n}' (couldn't find gid)!"),
         }
     }
 }
+
+/// `legacy`: every occurrence of a variable's name is replaced, the longest name if several start at the same place
+fn replace_variables_in_str_given_vars(val: &str, vars_all: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(val.len());
+    let mut rest = val;
+    while let Some(ch) = rest.chars().next() {
+        let var = vars_all
+            .iter()
+            .filter(|(name, _)| !name.is_empty() && rest.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len());
+        match var {
+            Some((name, value)) => {
+                out.push_str(value);
+                rest = &rest[name.len()..];
+            }
+            None => {
+                out.push(ch);
+                rest = &rest[ch.len_utf8()..];
+            }
+        }
+    }
+    out
+}
+fn replace_variables(
+    val: &str,
+    vars_all: &[(String, String)],
+    syntax: VarSyntax,
+    var_errors: &RefCell<Vec<ToRunCmdError>>,
+) -> String {
+    match syntax {
+        VarSyntax::Legacy => replace_variables_in_str_given_vars(val, vars_all),
+        VarSyntax::Braced => replace_variables_braced(val, vars_all, &mut var_errors.borrow_mut()),
+    }
+}
+/// `${NAME}`, `${NAME:-default}` (if NAME is undefined or empty) and `$$`. other `$`s are kept.
+fn replace_variables_braced(
+    val: &str,
+    vars_all: &[(String, String)],
+    var_errors: &mut Vec<ToRunCmdError>,
+) -> String {
+    let mut out = String::with_capacity(val.len());
+    let mut rest = val;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            // find the matching `}`, defaults can contain `${...}`
+            let mut depth = 1;
+            let Some(end) = r.find(|c| {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            }) else {
+                var_errors.push(ToRunCmdError::VarUnterminated(val.to_owned()));
+                return out;
+            };
+            let inner = &r[..end];
+            let (name, default) = match inner.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (inner, None),
+            };
+            let value = vars_all.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+            match (value, default) {
+                (Some(value), Some(default)) if value.is_empty() => {
+                    out.push_str(&replace_variables_braced(default, vars_all, var_errors))
+                }
+                (Some(value), _) => out.push_str(value),
+                (None, Some(default)) => {
+                    out.push_str(&replace_variables_braced(default, vars_all, var_errors))
+                }
+                (None, None) => var_errors.push(ToRunCmdError::VarUndefined(name.to_owned())),
+            }
+            rest = &r[end + 1..];
+        } else {
+            out.push('$');
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// the gids of the groups `user` is in, like `id -G`.
+/// (`users::get_user_groups` ignores how many groups `getgrouplist` found, so it also returns gid 0 for every user)
+fn user_groups(user: &users::User) -> Option<Vec<u32>> {
+    let name = std::ffi::CString::new(user.name().as_bytes()).ok()?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let res = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                user.primary_group_id(),
+                groups.as_mut_ptr(),
+                &mut count,
+            )
+        };
+        if res >= 0 {
+            groups.truncate(count as usize);
+            return Some(groups);
+        }
+        // too small: `count` is how many there are
+        if groups.len() >= 1 << 16 {
+            return None;
+        }
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// the current local time, formatted with strftime (empty if it's longer than 256 bytes)
+fn timestamp(format: &str) -> String {
+    let Ok(format) = std::ffi::CString::new(format) else {
+        return String::new();
+    };
+    let mut buf = [0u8; 256];
+    let len = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// a `from-cmd` var's command, as `options.user` (default: nobody), with a minimal environment and a timeout.
+/// the output's trailing newlines are removed (unless `--keep-newline`), a non-zero exit status is an error.
+/// it runs in its own process group, which is killed once the command exits or times out,
+/// so processes it started in the background can't keep the pipes open.
+fn run_var_cmd(exec: &str, args: &[String], options: &CmdOptions) -> Result<String, ToRunCmdError> {
+    let name = options.user.as_deref().unwrap_or("nobody");
+    let Some(user) = users::get_user_by_name(name) else {
+        return Err(ToRunCmdError::UnknownUser(name.to_owned()));
+    };
+    let failed = || ToRunCmdError::VarFailedToRun(exec.to_owned(), args.to_vec());
+    let mut command = Command::new(exec);
+    // the server blocks some signals (see signals.rs), the child shouldn't inherit that
+    unsafe {
+        command.pre_exec(|| {
+            signals::unblock();
+            Ok(())
+        });
+    }
+    let mut child = command
+        .args(args)
+        .process_group(0)
+        .uid(user.uid())
+        .gid(user.primary_group_id())
+        .groups(&[])
+        .env_clear()
+        .env("PATH", CMD_PATH)
+        .env("HOME", users::os::unix::UserExt::home_dir(&user))
+        .env("USER", user.name())
+        .env("LOGNAME", user.name())
+        .current_dir("/")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|_| failed())?;
+    // read while waiting, so the command doesn't block on a full pipe
+    let read = |pipe: Option<Box<dyn std::io::Read + Send>>| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = vec![];
+            if let Some(mut pipe) = pipe {
+                _ = pipe.read_to_end(&mut buf);
+            }
+            _ = tx.send(String::from_utf8_lossy(&buf).into_owned());
+        });
+        rx
+    };
+    let stdout = read(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read(child.stderr.take().map(|p| Box::new(p) as _));
+    let timeout = options.timeout.unwrap_or(DEFAULT_CMD_TIMEOUT);
+    let start = Instant::now();
+    // the process group's id is the child's pid, which isn't reused until the child is reaped.
+    // so the child is only waited for without reaping it (WNOWAIT), then the group is killed, then it's reaped.
+    let group = child.id() as libc::pid_t;
+    let kill_group = || unsafe { libc::kill(-group, libc::SIGKILL) };
+    let exited = || {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        match unsafe { libc::waitid(libc::P_PID, group as libc::id_t, &mut info, flags) } {
+            // with WNOHANG, si_pid stays 0 if the child is still running
+            0 => Ok(unsafe { info.si_pid() } != 0),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    };
+    let timed_out = loop {
+        match exited() {
+            Ok(true) => break false,
+            Ok(false) if start.elapsed() < timeout => std::thread::sleep(Duration::from_millis(10)),
+            Ok(false) => break true,
+            Err(_) => {
+                kill_group();
+                _ = child.wait();
+                return Err(failed());
+            }
+        }
+    };
+    // also kills what the command left running in its group
+    kill_group();
+    let status = child.wait().map_err(|_| failed())?;
+    if timed_out {
+        return Err(ToRunCmdError::VarCmdTimeout(exec.to_owned(), timeout));
+    }
+    // processes which left the group can keep the pipes open, they aren't waited for longer than the timeout
+    // (the reading thread ends once they close them)
+    let output = |rx: std::sync::mpsc::Receiver<String>| {
+        rx.recv_timeout(
+            timeout
+                .saturating_sub(start.elapsed())
+                .max(Duration::from_millis(100)),
+        )
+    };
+    let (Ok(stdout), Ok(stderr)) = (output(stdout), output(stderr)) else {
+        return Err(ToRunCmdError::VarCmdTimeout(exec.to_owned(), timeout));
+    };
+    if !status.success() {
+        return Err(ToRunCmdError::VarCmdFailed(
+            exec.to_owned(),
+            status.to_string(),
+            stderr.trim().to_owned(),
+        ));
+    }
+    Ok(if options.keep_newline {
+        stdout
+    } else {
+        stdout.trim_end_matches('\n').to_owned()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// runs `sh -c script`, with a timeout in seconds
+    fn var_cmd(
+        script: &str,
+        timeout: Option<u64>,
+        keep_newline: bool,
+    ) -> Result<String, ToRunCmdError> {
+        let options = CmdOptions {
+            user: None,
+            timeout: timeout.map(Duration::from_secs),
+            keep_newline,
+        };
+        run_var_cmd("/bin/sh", &["-c".to_owned(), script.to_owned()], &options)
+    }
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+    fn braced(val: &str, vars_all: &[(String, String)]) -> (String, Vec<ToRunCmdError>) {
+        let mut errors = vec![];
+        let out = replace_variables_braced(val, vars_all, &mut errors);
+        (out, errors)
+    }
+
+    #[test]
+    fn user_in_group() {
+        let in_group = |uid, group: &str| {
+            Condition::UserInGroup(group.to_owned()).eval(
+                &HashMap::new(),
+                &[],
+                |s| s.to_owned(),
+                uid,
+            )
+        };
+        // compared with `id -G` for the accounts which exist where the test runs
+        let root_group = users::get_group_by_gid(0).unwrap();
+        for user in unsafe { users::all_users() }.take(20) {
+            let out = std::process::Command::new("id")
+                .arg("-G")
+                .arg(user.name())
+                .output()
+                .unwrap();
+            let gids: Vec<u32> = String::from_utf8(out.stdout)
+                .unwrap()
+                .split_whitespace()
+                .map(|g| g.parse().unwrap())
+                .collect();
+            let primary = users::get_group_by_gid(user.primary_group_id());
+            for group in primary.iter().chain([&root_group]) {
+                assert_eq!(
+                    in_group(Some(user.uid()), group.name().to_str().unwrap()),
+                    gids.contains(&group.gid()),
+                    "{:?} in {:?}",
+                    user.name(),
+                    group.name()
+                );
+            }
+            assert!(!in_group(Some(user.uid()), "d26run-test-no-such-group"));
+        }
+        assert!(!in_group(None, root_group.name().to_str().unwrap()));
+    }
+
+    #[test]
+    fn var_cmd_output() {
+        assert_eq!(var_cmd("echo a; echo; echo", None, false).unwrap(), "a");
+        assert_eq!(var_cmd("echo a; echo", None, true).unwrap(), "a\n\n");
+        // only newlines are trimmed
+        assert_eq!(var_cmd("printf ' a \t'", None, false).unwrap(), " a \t");
+        // it runs as nobody, with only some variables
+        assert_eq!(
+            var_cmd("echo $USER $LOGNAME $(id -u) ${TERM:-none}", None, false).unwrap(),
+            format!(
+                "nobody nobody {} none",
+                users::get_user_by_name("nobody").unwrap().uid()
+            )
+        );
+    }
+
+    #[test]
+    fn var_cmd_failure_includes_stderr() {
+        let error = var_cmd("echo out; echo ' oops ' >&2; exit 3", None, false).unwrap_err();
+        assert!(
+            matches!(&error, ToRunCmdError::VarCmdFailed(exec, status, stderr)
+                if exec == "/bin/sh" && status.contains('3') && stderr == "oops"),
+            "{error:?}"
+        );
+        assert_eq!(
+            error.to_string(),
+            "var: command \"/bin/sh\" failed (exit status: 3): oops"
+        );
+    }
+
+    #[test]
+    fn var_cmd_timeout() {
+        let start = Instant::now();
+        let error = var_cmd("sleep 30", Some(1), false).unwrap_err();
+        assert!(matches!(error, ToRunCmdError::VarCmdTimeout(_, t) if t == Duration::from_secs(1)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // what the command started in the background is killed with it
+        let start = Instant::now();
+        assert_eq!(var_cmd("sleep 30 & echo a", Some(10), false).unwrap(), "a");
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // a process which left the group and keeps the pipes open isn't waited for
+        let start = Instant::now();
+        let error = var_cmd("setsid sleep 3 & echo a", Some(1), false).unwrap_err();
+        assert!(
+            matches!(error, ToRunCmdError::VarCmdTimeout(..)),
+            "{error:?}"
+        );
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn legacy_replaces_the_longest_name() {
+        let vars = vars(&[("%TMPHOME", "/h"), ("%TMPHOMEDIR", "/hd")]);
+        assert_eq!(
+            replace_variables_in_str_given_vars("%TMPHOMEDIR/a:%TMPHOME/b:%TMPHOM", &vars),
+            "/hd/a:/h/b:%TMPHOM"
+        );
+    }
+
+    #[test]
+    fn legacy_finds_names_after_partial_matches() {
+        let vars = vars(&[("ab", "X"), ("%Ü", "ö")]);
+        assert_eq!(replace_variables_in_str_given_vars("aab", &vars), "aX");
+        assert_eq!(replace_variables_in_str_given_vars("ä%%Üß", &vars), "ä%öß");
+    }
+
+    #[test]
+    fn legacy_doesnt_replace_in_values() {
+        let vars = vars(&[("%A", "%B"), ("%B", "b")]);
+        assert_eq!(replace_variables_in_str_given_vars("%A %B", &vars), "%B b");
+    }
+
+    #[test]
+    fn braced_uses_whole_names() {
+        let vars = vars(&[("TMPHOME", "/h"), ("TMPHOMEDIR", "/hd")]);
+        let (out, errors) = braced("${TMPHOMEDIR}/a:${TMPHOME}/b:$TMPHOME", &vars);
+        assert_eq!(out, "/hd/a:/h/b:$TMPHOME");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn braced_escapes() {
+        let vars = vars(&[("A", "a")]);
+        let (out, errors) = braced("$$ $${A} $$${A} a$ $", &vars);
+        assert_eq!(out, "$ ${A} $a a$ $");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn braced_defaults() {
+        let vars = vars(&[("A", "a"), ("EMPTY", "")]);
+        for (val, expected) in [
+            ("${A:-x}", "a"),
+            ("${EMPTY:-x}", "x"),
+            ("${UNSET:-x}", "x"),
+            ("${UNSET:-}", ""),
+            ("${UNSET:-${A}b}", "ab"),
+            ("${UNSET:-${EMPTY:-${A:-x}}}", "a"),
+            ("${UNSET:-{x}}", "{x}"),
+            ("${UNSET:-$$}", "$"),
+        ] {
+            let (out, errors) = braced(val, &vars);
+            assert_eq!(out, expected, "{val}");
+            assert!(errors.is_empty(), "{val}");
+        }
+    }
+
+    #[test]
+    fn braced_errors() {
+        let (_, errors) = braced("${A}", &[]);
+        assert!(matches!(&errors[..], [ToRunCmdError::VarUndefined(name)] if name == "A"));
+        let (_, errors) = braced("${UNSET:-${A}}", &[]);
+        assert!(matches!(&errors[..], [ToRunCmdError::VarUndefined(name)] if name == "A"));
+        for val in ["a${A", "${A:-${B}", "${"] {
+            let (_, errors) = braced(val, &vars(&[("A", "a")]));
+            assert!(
+                matches!(&errors[..], [ToRunCmdError::VarUnterminated(v)] if v == val),
+                "{val}: {errors:?}"
+            );
+        }
+    }
+}
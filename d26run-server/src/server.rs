@@ -3,28 +3,121 @@ use std::{
     fs,
     io::{BufRead, BufReader, Read, Write},
     os::{linux::fs::MetadataExt, unix::net::UnixStream},
-    sync::{atomic::AtomicBool, mpsc, Arc},
-    time::Duration,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    config::Config,
-    run::{Runner, ToRunCmdInfo},
-    Liner, NewlineRemover, DIR_ALLOWS,
+    approvals::{Approvals, Decision},
+    audit::{AuditLog, Record},
+    config::{self, Config},
+    diagnostics,
+    logging::{info, trace, warn},
+    metrics::{self, Metrics},
+    pam,
+    run::{RunCmdBuilder, Runner, Singleton, ToRunCmdError, ToRunCmdInfo},
+    sessions::{KillResult, NotStarted, Sessions},
+    settings::{self, Settings},
+    systemd, Liner, NewlineRemover,
 };
 
+/// state shared by all connections
+pub struct ServerState {
+    pub settings: Settings,
+    pub sessions: Arc<Sessions>,
+    /// run requests waiting for an admin (`require-approval`)
+    pub approvals: Approvals,
+    pub please_reload: AtomicBool,
+    /// None if no audit-log is configured
+    pub audit: Option<Arc<AuditLog>>,
+    pub metrics: Metrics,
+    /// the currently loaded configs, and when they were loaded
+    config: Mutex<(Arc<Config>, Instant)>,
+}
+impl ServerState {
+    /// also loads the configs
+    pub fn new(settings: Settings, audit: Option<Arc<AuditLog>>) -> Self {
+        let config = Arc::new(config::init(&settings));
+        let metrics = Metrics::default();
+        metrics.add("d26run_config_errors_total", &[], config.skipped as u64);
+        Self {
+            settings,
+            sessions: Default::default(),
+            approvals: Default::default(),
+            please_reload: AtomicBool::new(false),
+            audit,
+            metrics,
+            config: Mutex::new((config, Instant::now())),
+        }
+    }
+    /// Returns the current configs. If a reload was requested (and the last one was long enough ago), reloads them first.
+    pub fn config(&self) -> Arc<Config> {
+        let mut config = self.config.lock().unwrap();
+        if self.please_reload.load(Ordering::Relaxed)
+            && config.1.elapsed() > self.settings.reload_interval
+        {
+            systemd::notify("RELOADING=1");
+            self.please_reload.store(false, Ordering::Relaxed);
+            *config = (Arc::new(config::init(&self.settings)), Instant::now());
+            self.metrics.inc("d26run_config_reloads_total", &[]);
+            self.metrics
+                .add("d26run_config_errors_total", &[], config.0.skipped as u64);
+            systemd::notify("READY=1");
+        }
+        Arc::clone(&config.0)
+    }
+    /// the configs as they are, without reloading them (for reading metrics)
+    pub fn loaded_config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.lock().unwrap().0)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum RunMode {
+    Detach,
+    Wait,
+    ForwardOutput,
+    ForwardOutputInput,
+}
+impl RunMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "detach" => Self::Detach,
+            "wait" => Self::Wait,
+            "forward-output" => Self::ForwardOutput,
+            "forward-output-input" => Self::ForwardOutputInput,
+            _ => return None,
+        })
+    }
+    /// (detach, forward_output, forward_input)
+    fn flags(self) -> (bool, bool, bool) {
+        match self {
+            Self::Detach => (true, false, false),
+            Self::Wait => (false, false, false),
+            Self::ForwardOutput => (false, true, false),
+            Self::ForwardOutputInput => (false, true, true),
+        }
+    }
+}
+
+/// `socket` is the index of the socket (in `Settings::sockets`) the client connected to.
 pub fn handle_con(
     stream: UnixStream,
     id: u128,
     config: Arc<Config>,
-    please_reload: Arc<AtomicBool>,
+    state: Arc<ServerState>,
+    socket: usize,
 ) {
-    match handle_con_internal(stream, id, config, please_reload) {
+    match handle_con_internal(stream, id, config, state, socket) {
         Ok(()) => {
-            eprintln!("[INFO] disconnected [{id}]. (dc)");
+            info!(con = id; "disconnected.");
         }
         Err(_) => {
-            eprintln!("[INFO] disconnected [{id}] (error).");
+            info!(con = id; "disconnected (error).");
         }
     }
 }
@@ -32,8 +125,13 @@ fn handle_con_internal(
     stream: UnixStream,
     id: u128,
     config: Arc<Config>,
-    please_reload: Arc<AtomicBool>,
+    state: Arc<ServerState>,
+    socket: usize,
 ) -> Result<(), std::io::Error> {
+    let policy = &state.settings.sockets[socket].policy;
+    let peer = stream.peer_cred().ok();
+    let peer_uid = peer.map(|cred| cred.uid);
+    let peer_pid = peer.and_then(|cred| cred.pid);
     let mut stream = BufReader::new(stream);
     writeln!(stream.get_mut(), "{id}")?;
     let mut line = String::new();
@@ -49,15 +147,35 @@ fn handle_con_internal(
         } else {
             (line.as_str(), String::new())
         };
-        match (command.trim(), args.trim_end_newline()) {
+        let command = command.trim();
+        // set-var only prepares a run, the vars are recorded as part of the run
+        let mut record = Record::new(state.audit.as_ref().filter(|_| command != "set-var"));
+        record.set("con", id);
+        record.set("uid", opt(peer_uid));
+        record.set("pid", opt(peer.and_then(|cred| cred.pid)));
+        record.set("socket", &state.settings.sockets[socket].path);
+        record.set("command", command);
+        if settings::ALL_COMMANDS.contains(&command) && !policy.allows_command(command) {
+            warn!(con = id; "'{command}' is not permitted on this socket.");
+            record.set("result", "not_permitted");
+            writeln!(stream.get_mut(), "{command} error_not_permitted")?;
+            line.clear();
+            continue;
+        }
+        match (command, args.trim_end_newline()) {
             // ("open", file) => {}
             ("list-configs", _) => {
+                let visible: Vec<_> = config
+                    .run_cmds
+                    .iter()
+                    .filter(|(name, _)| policy.allows_config(name))
+                    .collect();
                 writeln!(
                     stream.get_mut(),
                     "listing configs; count: {}",
-                    config.run_cmds.len()
+                    visible.len()
                 )?;
-                for (name, cfg) in config.run_cmds.iter() {
+                for (name, cfg) in visible {
                     writeln!(stream.get_mut(), "{name}")?;
                     writeln!(
                         stream.get_mut(),
@@ -69,20 +187,165 @@ fn handle_con_internal(
                     )?;
                 }
             }
+            ("metrics", _) => {
+                let text = metrics::render(&state);
+                writeln!(stream.get_mut(), "metrics; lines: {}", text.lines().count())?;
+                write!(stream.get_mut(), "{text}")?;
+            }
             ("reload-configs", _) => {
-                please_reload.store(true, std::sync::atomic::Ordering::Relaxed);
+                state.please_reload.store(true, Ordering::Relaxed);
+                record.set("result", "requested");
                 writeln!(stream.get_mut(), "reload-configs requested")?;
             }
+            ("list-pending", _) => {
+                let mut pending = vec![];
+                state.approvals.for_each(|p| {
+                    pending.push(format!("{} {} {} {}", p.id, p.config, opt(p.uid), p.con_id));
+                });
+                writeln!(
+                    stream.get_mut(),
+                    "listing pending; count: {}",
+                    pending.len()
+                )?;
+                for p in pending {
+                    writeln!(stream.get_mut(), "{p}")?;
+                }
+            }
+            (command @ ("approve" | "deny"), request) => {
+                record.set("request", request);
+                let approve = command == "approve";
+                if request
+                    .parse()
+                    .is_ok_and(|request| state.approvals.decide(request, approve))
+                {
+                    info!(con = id, request = request; "{}.", if approve { "approved" } else { "denied" });
+                    record.set("result", "ok");
+                    writeln!(stream.get_mut(), "{command} ok")?;
+                } else {
+                    record.set("result", "unknown");
+                    writeln!(stream.get_mut(), "{command} unknown")?;
+                }
+            }
+            ("list-sessions", _) => {
+                let mut sessions = vec![];
+                state.sessions.for_each(|s| {
+                    sessions.push(format!(
+                        "{} {} {} {} {}",
+                        s.id,
+                        s.config,
+                        opt(s.uid),
+                        opt(s.pid),
+                        s.con_id
+                    ));
+                });
+                writeln!(
+                    stream.get_mut(),
+                    "listing sessions; count: {}",
+                    sessions.len()
+                )?;
+                for session in sessions {
+                    writeln!(stream.get_mut(), "{session}")?;
+                }
+            }
+            ("kill-session", session) => {
+                record.set("session", session);
+                match session.parse() {
+                    Ok(session) => match state.sessions.kill(session) {
+                        KillResult::Killed => {
+                            info!(con = id, session = session; "killed session.");
+                            record.set("result", "ok");
+                            writeln!(stream.get_mut(), "kill-session ok")?
+                        }
+                        KillResult::UnknownSession => {
+                            record.set("result", "unknown");
+                            writeln!(stream.get_mut(), "kill-session unknown")?
+                        }
+                        KillResult::NotStarted => {
+                            record.set("result", "not_started");
+                            writeln!(stream.get_mut(), "kill-session not_started")?
+                        }
+                        KillResult::Failed(e) => {
+                            record.set("result", "error");
+                            writeln!(
+                                stream.get_mut(),
+                                "kill-session error {}",
+                                e.to_string().replace('\n', "\\n")
+                            )?
+                        }
+                    },
+                    Err(_) => {
+                        record.set("result", "unknown");
+                        writeln!(stream.get_mut(), "kill-session unknown")?
+                    }
+                }
+            }
+            ("test-config", name) => {
+                // load the file again, it might have changed since the last reload
+                let mut runcmd = RunCmdBuilder::default();
+                record.set("config", name);
+                // the name is used as a path, it mustn't lead out of the configs-dir
+                if !config::is_config_name(name) || !policy.allows_config(name) {
+                    record.set("result", "unknown");
+                    writeln!(stream.get_mut(), "test-config unknown")?;
+                } else {
+                    let diagnostics = config::runcmd_from_rel_file(
+                        &state.settings.configs_dir,
+                        name,
+                        &mut runcmd,
+                    );
+                    // one message per line, the client prints them as they are
+                    let mut messages: Vec<String> = diagnostics
+                        .iter()
+                        .flat_map(|d| {
+                            d.to_string()
+                                .lines()
+                                .map(|l| l.to_owned())
+                                // separate them
+                                .chain([String::new()])
+                                .collect::<Vec<_>>()
+                        })
+                        .collect();
+                    let result = if diagnostics::has_errors(&diagnostics) {
+                        "error_parse"
+                    } else if runcmd.include_only {
+                        // fragments don't have to be complete
+                        "include_only"
+                    } else {
+                        let (non_fatal, fatal) = runcmd.verify(&ToRunCmdInfo {
+                            con_id: id,
+                            var_syntax: state.settings.default_var_syntax,
+                            client_uid: None,
+                            client_pid: None,
+                        });
+                        let fatal = fatal.err().unwrap_or_default();
+                        let result = if fatal.is_empty() { "ok" } else { "fatal" };
+                        messages.extend(fatal.into_iter().map(|e| format!("fatal: {e}")));
+                        messages.extend(non_fatal.into_iter().map(|e| format!("non-fatal: {e}")));
+                        result
+                    };
+                    record.set("result", result);
+                    writeln!(
+                        stream.get_mut(),
+                        "test-config {result}; count: {}",
+                        messages.len()
+                    )?;
+                    for message in messages {
+                        writeln!(stream.get_mut(), "{message}")?;
+                    }
+                }
+            }
             ("set-var", right) => {
                 if let Some((varname, value)) = right.split_once(' ') {
                     vars.insert(varname.to_owned(), value.to_owned());
                 }
             }
             ("run", runcfg) => 'run: {
-                let mut forward_output = false;
-                let mut detach = false;
-                let mut forward_input = false;
+                // the vars only apply to this run, however it ends
+                let vars = std::mem::take(&mut vars);
+                let (mut detach, mut forward_output, mut forward_input) =
+                    state.settings.default_run_mode.flags();
                 let runcfg = if let Some((args, runcfg)) = runcfg.split_once(' ') {
+                    record.set("args", args);
                     for arg in args.split(',') {
                         let (arg, val) = if let Some((arg, val)) = arg.split_once('=') {
                             (arg, Some(val))
@@ -92,17 +355,28 @@ fn handle_con_internal(
                         match arg {
                             "mode" => {
                                 if let Some(val) = val {
-                                    (detach, forward_output, forward_input) = match val {
-                                                    "detach" => (true, false, false),
-                                                    "wait" => (false, false, false),
-                                                    "forward-output" => (false, true, false),
-                                                    "forward-output-input" => (false, true, true),
-                                                    _ => break 'run writeln!(
-                                                        stream.get_mut(),
-                                                        "run error_arg_value_invalid {arg} {val} // try detach, wait, forward-output or forward-output-input. wait is default."
-                                                    )?,
-                                                }
+                                    (detach, forward_output, forward_input) = match RunMode::parse(
+                                        val,
+                                    ) {
+                                        Some(mode) => mode.flags(),
+                                        None => {
+                                            record.set("result", "invalid_args");
+                                            state.metrics.inc(
+                                                "d26run_runs_denied_total",
+                                                &[("config", "-"), ("reason", "invalid_args")],
+                                            );
+                                            break 'run writeln!(
+                                                            stream.get_mut(),
+                                                            "run error_arg_value_invalid {arg} {val} // try detach, wait, forward-output or forward-output-input. the default is set in server.conf."
+                                                        )?;
+                                        }
+                                    }
                                 } else {
+                                    record.set("result", "invalid_args");
+                                    state.metrics.inc(
+                                        "d26run_runs_denied_total",
+                                        &[("config", "-"), ("reason", "invalid_args")],
+                                    );
                                     break 'run writeln!(
                                         stream.get_mut(),
                                         "run error_arg_no_value {arg}"
@@ -110,10 +384,15 @@ fn handle_con_internal(
                                 }
                             }
                             _ => {
+                                record.set("result", "invalid_args");
+                                state.metrics.inc(
+                                    "d26run_runs_denied_total",
+                                    &[("config", "-"), ("reason", "invalid_args")],
+                                );
                                 break 'run writeln!(
                                     stream.get_mut(),
                                     "run error_invalid_arg {arg}"
-                                )?
+                                )?;
                             }
                         }
                     }
@@ -121,9 +400,27 @@ fn handle_con_internal(
                 } else {
                     runcfg
                 };
-                if let Some(cfg) = config.run_cmds.get(runcfg) {
+                record.set("config", runcfg);
+                let mut sorted_vars: Vec<_> = vars.iter().collect();
+                sorted_vars.sort();
+                for (name, val) in sorted_vars {
+                    record.set(format!("var.{name}"), val);
+                }
+                if let Some(cfg) = config
+                    .run_cmds
+                    .get(runcfg)
+                    .filter(|_| policy.allows_config(runcfg))
+                {
+                    if cfg.allow.is_none() && cfg.pam_service.is_none() {
+                        record.set("auth", "undefined_allow");
+                        state.metrics.inc(
+                            "d26run_runs_denied_total",
+                            &[("config", runcfg), ("reason", "auth")],
+                        );
+                        break 'run writeln!(stream.get_mut(), "auth deny error_undefined_allow")?;
+                    }
                     if let Some(allow) = &cfg.allow {
-                        let allow_src = format!("{DIR_ALLOWS}{allow}");
+                        let allow_src = Path::new(&state.settings.allow_dir).join(allow);
                         auth_id += 1;
                         let auth_file = format!("/tmp/d26run-auth-{id}-{auth_id}");
                         let ok = || {
@@ -139,262 +436,383 @@ fn handle_con_internal(
                             std::io::Result::Ok(())
                         };
                         let init_client_dir = ok();
-                        if init_client_dir.is_ok() {
-                            writeln!(stream.get_mut(), "auth wait {auth_file}")?;
-                            if stream.line().as_str() == "auth done" {
-                                match fs::File::open(&auth_file) {
-                                    Ok(file) => {
-                                        let file_as_string = file
-                                            .bytes()
-                                            .take(5)
-                                            .collect::<Result<Vec<_>, _>>()
-                                            .ok()
-                                            .and_then(|b| String::from_utf8(b).ok());
-                                        _ = fs::remove_file(&auth_file);
-                                        if file_as_string.is_some_and(|v| v.trim() == "auth") {
-                                            writeln!(stream.get_mut(), "auth accept")?;
-                                            let info = ToRunCmdInfo { con_id: id };
-                                            match cfg.to_runcmd(&vars, &info) {
-                                                Ok(runcmd) => {
-                                                    writeln!(stream.get_mut(), "run start")?;
-                                                    stream.get_mut().flush()?;
-                                                    let mut r = Runner::new(runcmd);
-                                                    r.start();
-                                                    if detach {
-                                                        std::thread::spawn(move || r.wait());
-                                                    } else {
-                                                        if let Some(child) = &mut r.child_process {
-                                                            if !forward_output {
-                                                                r.wait();
-                                                            } else {
-                                                                /// If the thread returns Ok(()), the returned receiver was dropped or the reader reached EOF.
-                                                                fn thread_get_stdout<
-                                                                    S: Read + Send + 'static,
-                                                                >(
-                                                                    s: S,
-                                                                ) -> (
-                                                                    std::thread::JoinHandle<
-                                                                        Result<(), std::io::Error>,
-                                                                    >,
-                                                                    mpsc::Receiver<u8>,
-                                                                )
-                                                                {
-                                                                    let (so, out) = mpsc::channel();
-                                                                    (
-                                                                        std::thread::spawn(
-                                                                            move || {
-                                                                                let mut s =
-                                                                                    BufReader::new(
-                                                                                        s,
-                                                                                    );
-                                                                                let mut b = [0u8];
-                                                                                loop {
-                                                                                    if s.read(
-                                                                                        &mut b,
-                                                                                    )? == 0
-                                                                                    {
-                                                                                        break;
-                                                                                    };
-                                                                                    if so
-                                                                                        .send(b[0])
-                                                                                        .is_err()
-                                                                                    {
-                                                                                        break;
-                                                                                    }
-                                                                                }
-                                                                                Ok(())
-                                                                            },
-                                                                        ),
-                                                                        out,
-                                                                    )
-                                                                }
-                                                                let mut stdout = child
-                                                                    .stdout
-                                                                    .take()
-                                                                    .map(|s| thread_get_stdout(s));
-                                                                let mut stderr = child
-                                                                    .stderr
-                                                                    .take()
-                                                                    .map(|s| thread_get_stdout(s));
-                                                                if forward_input {
-                                                                    stream
-                                                                        .get_mut()
-                                                                        .set_read_timeout(Some(
-                                                                            Duration::from_secs_f32(
-                                                                                0.1,
-                                                                            ),
-                                                                        ))?;
-                                                                }
-                                                                loop {
-                                                                    let mut sent_anything = false;
-                                                                    let stdout_finished =
-                                                                        if let Some((t, r)) =
-                                                                            &mut stdout
-                                                                        {
-                                                                            let fin =
-                                                                                t.is_finished();
-                                                                            let mut buf =
-                                                                                Vec::new();
-                                                                            while let Ok(r) =
-                                                                                r.try_recv()
-                                                                            {
-                                                                                buf.push(r);
-                                                                                if buf.len() >= 120
-                                                                                {
-                                                                                    break;
-                                                                                }
-                                                                            }
-                                                                            if buf.len() > 1 {
-                                                                                let b =
-                                                                                    buf.len() as u8;
-                                                                                stream
-                                                                                    .get_mut()
-                                                                                    .write_all(
-                                                                                        &[b],
-                                                                                    )?;
-                                                                                stream
-                                                                                    .get_mut()
-                                                                                    .write_all(
-                                                                                        &buf,
-                                                                                    )?;
-                                                                                sent_anything =
-                                                                                    true;
-                                                                            }
-                                                                            fin
-                                                                        } else {
-                                                                            true
-                                                                        };
-                                                                    let stderr_finished =
-                                                                        if let Some((t, r)) =
-                                                                            &mut stderr
-                                                                        {
-                                                                            let fin =
-                                                                                t.is_finished();
-                                                                            let mut buf =
-                                                                                Vec::new();
-                                                                            while let Ok(r) =
-                                                                                r.try_recv()
-                                                                            {
-                                                                                buf.push(r);
-                                                                                if buf.len() >= 120
-                                                                                {
-                                                                                    break;
-                                                                                }
-                                                                            }
-                                                                            if buf.len() > 1 {
-                                                                                // 1st bit = 1 => stderr
-                                                                                let b = 128
-                                                                                    | buf.len()
-                                                                                        as u8;
-                                                                                stream
-                                                                                    .get_mut()
-                                                                                    .write_all(
-                                                                                        &[b],
-                                                                                    )?;
-                                                                                stream
-                                                                                    .get_mut()
-                                                                                    .write_all(
-                                                                                        &buf,
-                                                                                    )?;
-                                                                                sent_anything =
-                                                                                    true;
-                                                                            }
-                                                                            fin
-                                                                        } else {
-                                                                            true
-                                                                        };
-                                                                    if forward_input {
-                                                                        if let Some(stdin) =
-                                                                            &mut child.stdin
-                                                                        {
-                                                                            let mut w = false;
-                                                                            loop {
-                                                                                let mut b = [0];
-                                                                                if let Ok(1) =
-                                                                                    stream.read(
-                                                                                        &mut b,
-                                                                                    )
-                                                                                {
-                                                                                    _ = stdin
-                                                                                        .write_all(
-                                                                                            &b,
-                                                                                        );
-                                                                                    w = true;
-                                                                                } else {
-                                                                                    break;
-                                                                                }
-                                                                            }
-                                                                            if w {
-                                                                                eprintln!("wrote some bytes to child's stdin.");
-                                                                                _ = stdin.flush();
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                    if sent_anything {
-                                                                        stream.get_mut().flush()?;
-                                                                    }
-                                                                    if stdout_finished
-                                                                        && stderr_finished
-                                                                        && child.try_wait().is_ok()
-                                                                        && sent_anything == false
-                                                                    {
-                                                                        r.wait();
-                                                                        break;
-                                                                    }
-                                                                }
-                                                            }
+                        if let Err(e) = init_client_dir {
+                            warn!(con = id; "could_not_copy_auth_file: {:?}", e);
+                            record.set("auth", "could_not_copy_auth_file");
+                            state.metrics.inc(
+                                "d26run_runs_denied_total",
+                                &[("config", runcfg), ("reason", "auth")],
+                            );
+                            break 'run writeln!(
+                                stream.get_mut(),
+                                "auth fail could_not_copy_auth_file"
+                            )?;
+                        }
+                        writeln!(stream.get_mut(), "auth wait {auth_file}")?;
+                        if stream.line().as_str() != "auth done" {
+                            _ = fs::remove_file(&auth_file);
+                            record.set("auth", "unexpected_response");
+                            auth_failed(&state, runcfg);
+                            break 'run writeln!(
+                                stream.get_mut(),
+                                "unexpected_response auth done"
+                            )?;
+                        }
+                        match fs::File::open(&auth_file) {
+                            Ok(file) => {
+                                let file_as_string = BufReader::new(file)
+                                    .bytes()
+                                    .take(5)
+                                    .collect::<Result<Vec<_>, _>>()
+                                    .ok()
+                                    .and_then(|b| String::from_utf8(b).ok());
+                                _ = fs::remove_file(&auth_file);
+                                if !file_as_string.is_some_and(|v| v.trim() == "auth") {
+                                    record.set("auth", "deny");
+                                    auth_failed(&state, runcfg);
+                                    break 'run writeln!(stream.get_mut(), "auth deny failed")?;
+                                }
+                            }
+                            Err(e) => {
+                                _ = fs::remove_file(&auth_file);
+                                record.set("auth", "error");
+                                auth_failed(&state, runcfg);
+                                break 'run writeln!(
+                                    stream.get_mut(),
+                                    "auth deny error {}",
+                                    e.to_string().replace('\n', "\\n")
+                                )?;
+                            }
+                        }
+                    }
+                    if let Some(service) = &cfg.pam_service {
+                        record.set("pam", service);
+                        let Some(user) = peer_uid.and_then(users::get_user_by_uid) else {
+                            record.set("auth", "pam_unknown_user");
+                            auth_failed(&state, runcfg);
+                            break 'run writeln!(stream.get_mut(), "auth deny pam unknown_user")?;
+                        };
+                        let result =
+                            pam::authenticate(service, &user.name().to_string_lossy(), |message| {
+                                let (kind, text) = match message {
+                                    pam::Message::Secret(text) => ("pam-prompt echo-off", text),
+                                    pam::Message::Visible(text) => ("pam-prompt echo-on", text),
+                                    pam::Message::Error(text) => ("pam-error", text),
+                                    pam::Message::Info(text) => ("pam-info", text),
+                                };
+                                writeln!(stream.get_mut(), "{kind} {}", text.replace('\n', "\\n"))
+                                    .ok()?;
+                                if kind.starts_with("pam-prompt") {
+                                    stream
+                                        .line()
+                                        .strip_prefix("pam-response ")
+                                        .map(|v| v.to_owned())
+                                } else {
+                                    Some(String::new())
+                                }
+                            });
+                        if let Err(e) = result {
+                            info!(con = id; "pam authentication for '{runcfg}' failed: {e}");
+                            record.set("auth", "pam_deny");
+                            record.set("pam_error", &e);
+                            auth_failed(&state, runcfg);
+                            break 'run writeln!(
+                                stream.get_mut(),
+                                "auth deny pam {}",
+                                e.to_string().replace('\n', "\\n")
+                            )?;
+                        }
+                    }
+                    record.set("auth", "accept");
+                    writeln!(stream.get_mut(), "auth accept")?;
+                    if let Some(timeout) = cfg.require_approval {
+                        let request = state.approvals.request(id, runcfg, peer_uid);
+                        record.set("request", request);
+                        writeln!(stream.get_mut(), "run pending {request}")?;
+                        stream.get_mut().flush()?;
+                        let denied = match state.approvals.wait(request, timeout) {
+                            Decision::Approved => {
+                                record.set("approval", "approved");
+                                None
+                            }
+                            Decision::Denied => Some("denied"),
+                            Decision::TimedOut => Some("timeout"),
+                        };
+                        if let Some(reason) = denied {
+                            info!(con = id, request = request; "not running '{runcfg}': approval {reason}.");
+                            record.set("approval", reason);
+                            state.metrics.inc(
+                                "d26run_runs_denied_total",
+                                &[("config", runcfg), ("reason", "approval")],
+                            );
+                            break 'run writeln!(stream.get_mut(), "run error_approval_{reason}")?;
+                        }
+                    }
+                    let session = match state.sessions.try_start(
+                        &state.settings,
+                        id,
+                        runcfg,
+                        cfg,
+                        peer_uid,
+                    ) {
+                        Ok(v) => v,
+                        Err(NotStarted::SingletonRunning(existing)) => {
+                            record.set("session", existing);
+                            match cfg.singleton {
+                                Some(Singleton::Attach) => {
+                                    info!(con = id, session = existing; "attaching to the running session of '{runcfg}'.");
+                                    record.set("result", "attached");
+                                    writeln!(stream.get_mut(), "run attached {existing}")?;
+                                    stream.get_mut().flush()?;
+                                    if !detach {
+                                        state.sessions.wait_for(existing);
+                                    }
+                                }
+                                Some(Singleton::Reuse) => {
+                                    match cfg.to_runcmd_without_prep(
+                                        &vars,
+                                        &ToRunCmdInfo {
+                                            con_id: id,
+                                            var_syntax: state.settings.default_var_syntax,
+                                            client_uid: peer_uid,
+                                            client_pid: peer_pid,
+                                        },
+                                    ) {
+                                        Ok(runcmd) => {
+                                            info!(con = id, session = existing; "reusing the running session of '{runcfg}'.");
+                                            record.set("result", "reused");
+                                            writeln!(stream.get_mut(), "run reused {existing}")?;
+                                            stream.get_mut().flush()?;
+                                            for cmd in runcmd.command_reuse {
+                                                Runner::new_prep_or_clean(cmd).start().wait();
+                                            }
+                                        }
+                                        Err(err) => {
+                                            record.set("result", "invalid_config");
+                                            break 'run write_config_errors(stream.get_mut(), err)?;
+                                        }
+                                    }
+                                }
+                                _ => {
+                                    record.set("result", "singleton_running");
+                                    state.metrics.inc(
+                                        "d26run_runs_denied_total",
+                                        &[("config", runcfg), ("reason", "singleton")],
+                                    );
+                                    break 'run writeln!(
+                                        stream.get_mut(),
+                                        "run error_singleton_running {existing}"
+                                    )?;
+                                }
+                            }
+                            stream.get_mut().write_all(&[0])?;
+                            stream.get_mut().flush()?;
+                            break 'run;
+                        }
+                        Err(NotStarted::LimitReached(limit)) => {
+                            warn!(con = id; "not running '{runcfg}': {limit} reached.");
+                            record.set("result", "limit_reached");
+                            state.metrics.inc(
+                                "d26run_runs_denied_total",
+                                &[("config", runcfg), ("reason", "limit")],
+                            );
+                            record.set("limit", &limit);
+                            break 'run writeln!(
+                                stream.get_mut(),
+                                "run error_limit_reached {limit}"
+                            )?;
+                        }
+                    };
+                    let info = ToRunCmdInfo {
+                        con_id: id,
+                        var_syntax: state.settings.default_var_syntax,
+                        client_uid: peer_uid,
+                        client_pid: peer_pid,
+                    };
+                    match cfg.to_runcmd(&vars, &info) {
+                        Ok(runcmd) => {
+                            record.set("runcmd", &runcmd);
+                            writeln!(stream.get_mut(), "run start")?;
+                            stream.get_mut().flush()?;
+                            let mut r = Runner::new(runcmd);
+                            let started = Instant::now();
+                            r.start();
+                            if let Some(child) = &r.child_process {
+                                session.set_pid(child.id());
+                                record.set("result", "started");
+                                record.set("session", session.id());
+                                record.set("child_pid", child.id());
+                                state
+                                    .metrics
+                                    .inc("d26run_runs_started_total", &[("config", runcfg)]);
+                            } else {
+                                record.set("result", "spawn_failed");
+                                state.metrics.inc(
+                                    "d26run_runs_failed_total",
+                                    &[("config", runcfg), ("reason", "spawn_failed")],
+                                );
+                            }
+                            if detach {
+                                let state = Arc::clone(&state);
+                                let runcfg = runcfg.to_owned();
+                                std::thread::spawn(move || {
+                                    let status = r.wait();
+                                    record.set_exit(status);
+                                    if r.child_process.is_some() {
+                                        state.metrics.run_finished(
+                                            &runcfg,
+                                            status,
+                                            started.elapsed(),
+                                        );
+                                    }
+                                    drop(record);
+                                    drop(session);
+                                });
+                            } else {
+                                if let Some(child) = &mut r.child_process {
+                                    if !forward_output {
+                                        let status = r.wait();
+                                        record.set_exit(status);
+                                        state.metrics.run_finished(
+                                            runcfg,
+                                            status,
+                                            started.elapsed(),
+                                        );
+                                    } else {
+                                        /// If the thread returns Ok(()), the returned receiver was dropped or the reader reached EOF.
+                                        fn thread_get_stdout<S: Read + Send + 'static>(
+                                            s: S,
+                                        ) -> (
+                                            std::thread::JoinHandle<Result<(), std::io::Error>>,
+                                            mpsc::Receiver<u8>,
+                                        ) {
+                                            let (so, out) = mpsc::channel();
+                                            (
+                                                std::thread::spawn(move || {
+                                                    let mut s = BufReader::new(s);
+                                                    let mut b = [0u8];
+                                                    loop {
+                                                        if s.read(&mut b)? == 0 {
+                                                            break;
+                                                        };
+                                                        if so.send(b[0]).is_err() {
+                                                            break;
                                                         }
                                                     }
-                                                    stream.get_mut().write(&[0])?;
-                                                    stream.get_mut().flush()?;
+                                                    Ok(())
+                                                }),
+                                                out,
+                                            )
+                                        }
+                                        let mut stdout = child.stdout.take().map(thread_get_stdout);
+                                        let mut stderr = child.stderr.take().map(thread_get_stdout);
+                                        if forward_input {
+                                            stream.get_mut().set_read_timeout(Some(
+                                                Duration::from_secs_f32(0.1),
+                                            ))?;
+                                        }
+                                        loop {
+                                            let mut sent_anything = false;
+                                            let stdout_finished = if let Some((t, r)) = &mut stdout
+                                            {
+                                                let fin = t.is_finished();
+                                                let mut buf = Vec::new();
+                                                while let Ok(r) = r.try_recv() {
+                                                    buf.push(r);
+                                                    if buf.len() >= 120 {
+                                                        break;
+                                                    }
                                                 }
-                                                Err(err) => {
-                                                    writeln!(
-                                                        stream.get_mut(),
-                                                        "run error_invalid_config: {}",
-                                                        err.len()
-                                                    )?;
-                                                    for err in err {
-                                                        let text = err.to_string();
-                                                        let lines: Vec<_> = text.lines().collect();
-                                                        writeln!(
-                                                            stream.get_mut(),
-                                                            "{}",
-                                                            lines.len()
-                                                        )?;
-                                                        for line in lines {
-                                                            writeln!(stream.get_mut(), "{}", line)?;
+                                                if buf.len() > 1 {
+                                                    let b = buf.len() as u8;
+                                                    stream.get_mut().write_all(&[b])?;
+                                                    stream.get_mut().write_all(&buf)?;
+                                                    sent_anything = true;
+                                                }
+                                                fin
+                                            } else {
+                                                true
+                                            };
+                                            let stderr_finished = if let Some((t, r)) = &mut stderr
+                                            {
+                                                let fin = t.is_finished();
+                                                let mut buf = Vec::new();
+                                                while let Ok(r) = r.try_recv() {
+                                                    buf.push(r);
+                                                    if buf.len() >= 120 {
+                                                        break;
+                                                    }
+                                                }
+                                                if buf.len() > 1 {
+                                                    // 1st bit = 1 => stderr
+                                                    let b = 128 | buf.len() as u8;
+                                                    stream.get_mut().write_all(&[b])?;
+                                                    stream.get_mut().write_all(&buf)?;
+                                                    sent_anything = true;
+                                                }
+                                                fin
+                                            } else {
+                                                true
+                                            };
+                                            if forward_input {
+                                                if let Some(stdin) = &mut child.stdin {
+                                                    let mut w = false;
+                                                    loop {
+                                                        let mut b = [0];
+                                                        if let Ok(1) = stream.read(&mut b) {
+                                                            _ = stdin.write_all(&b);
+                                                            w = true;
+                                                        } else {
+                                                            break;
                                                         }
                                                     }
+                                                    if w {
+                                                        trace!(con = id; "wrote some bytes to child's stdin.");
+                                                        _ = stdin.flush();
+                                                    }
                                                 }
                                             }
-                                        } else {
-                                            writeln!(stream.get_mut(), "auth deny failed")?
+                                            if sent_anything {
+                                                stream.get_mut().flush()?;
+                                            }
+                                            if stdout_finished
+                                                && stderr_finished
+                                                && child.try_wait().is_ok()
+                                                && !sent_anything
+                                            {
+                                                let status = r.wait();
+                                                record.set_exit(status);
+                                                state.metrics.run_finished(
+                                                    runcfg,
+                                                    status,
+                                                    started.elapsed(),
+                                                );
+                                                break;
+                                            }
                                         }
                                     }
-                                    Err(e) => {
-                                        _ = fs::remove_file(&auth_file);
-                                        writeln!(
-                                            stream.get_mut(),
-                                            "auth deny error {}",
-                                            e.to_string().replace('\n', "\\n")
-                                        )?;
-                                    }
                                 }
-                            } else {
-                                writeln!(stream.get_mut(), "unexpected_response auth done")?;
                             }
-                        } else {
-                            eprintln!("could_not_copy_auth_file: {:?}", init_client_dir);
-                            writeln!(stream.get_mut(), "auth fail could_not_copy_auth_file")?;
+                            stream.get_mut().write_all(&[0])?;
+                            stream.get_mut().flush()?;
+                        }
+                        Err(err) => {
+                            record.set("result", "invalid_config");
+                            state.metrics.inc(
+                                "d26run_runs_failed_total",
+                                &[("config", runcfg), ("reason", "invalid_config")],
+                            );
+                            write_config_errors(stream.get_mut(), err)?;
                         }
-                    } else {
-                        writeln!(stream.get_mut(), "auth deny error_undefined_allow")?;
                     }
                 } else {
+                    record.set("result", "unknown");
+                    state.metrics.inc(
+                        "d26run_runs_denied_total",
+                        &[("config", "-"), ("reason", "unknown")],
+                    );
                     writeln!(stream.get_mut(), "run unknown")?;
                 }
-                vars.clear();
             }
             _ => (),
         }
@@ -402,3 +820,30 @@ fn handle_con_internal(
     }
     Ok::<_, std::io::Error>(())
 }
+
+/// `-` for None
+pub fn opt(v: Option<impl std::fmt::Display>) -> String {
+    v.map(|v| v.to_string()).unwrap_or("-".to_owned())
+}
+
+/// `run error_invalid_config: <count>`, then, for each error, the number of lines and the lines
+fn write_config_errors(stream: &mut UnixStream, err: Vec<ToRunCmdError>) -> std::io::Result<()> {
+    writeln!(stream, "run error_invalid_config: {}", err.len())?;
+    for err in err {
+        let text = err.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        writeln!(stream, "{}", lines.len())?;
+        for line in lines {
+            writeln!(stream, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+fn auth_failed(state: &ServerState, config: &str) {
+    state.metrics.inc("d26run_auth_failures_total", &[]);
+    state.metrics.inc(
+        "d26run_runs_denied_total",
+        &[("config", config), ("reason", "auth")],
+    );
+}
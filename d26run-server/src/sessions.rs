@@ -0,0 +1,412 @@
+//! keeps track of running sessions (a run that was started and hasn't finished yet)
+//! and open connections, so the limits from server.conf and the configs can be enforced.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{logging::debug, run::RunCmdBuilder, settings::Settings};
+
+pub struct Session {
+    pub id: u64,
+    /// the id of the connection which started this session
+    pub con_id: u128,
+    pub config: String,
+    /// uid of the client, if known
+    pub uid: Option<u32>,
+    /// pid of the main command, once it was spawned
+    pub pid: Option<u32>,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    running: Mutex<Vec<Session>>,
+    /// notified whenever a session ends
+    ended: Condvar,
+    next_id: AtomicU64,
+    /// client uid -> number of open connections
+    connections: Mutex<HashMap<u32, usize>>,
+    /// client uid -> when its recent sessions were started
+    recent_runs: Mutex<HashMap<u32, VecDeque<Instant>>>,
+}
+
+pub enum NotStarted {
+    LimitReached(LimitReached),
+    /// the config is a singleton, and this session of it is still running
+    SingletonRunning(u64),
+}
+#[derive(Debug)]
+pub enum LimitReached {
+    Sessions,
+    SessionsPerUser,
+    Instances,
+    InstancesPerUser,
+    Connections,
+    RunsPerMinute,
+}
+pub enum KillResult {
+    Killed,
+    UnknownSession,
+    /// the session exists, but its command wasn't spawned (yet)
+    NotStarted,
+    Failed(std::io::Error),
+}
+
+impl std::fmt::Display for LimitReached {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sessions => write!(f, "max-sessions"),
+            Self::SessionsPerUser => write!(f, "max-sessions-per-user"),
+            Self::Instances => write!(f, "max-instances"),
+            Self::InstancesPerUser => write!(f, "max-per-user"),
+            Self::Connections => write!(f, "max-connections"),
+            Self::RunsPerMinute => write!(f, "max-runs-per-minute"),
+        }
+    }
+}
+
+impl Sessions {
+    /// Registers a new session of `config` (named `name`), unless that would exceed a limit
+    /// or the config is a singleton which is already running.
+    /// The session is removed once the returned guard is dropped.
+    pub fn try_start(
+        self: &Arc<Self>,
+        settings: &Settings,
+        con_id: u128,
+        name: &str,
+        config: &RunCmdBuilder,
+        uid: Option<u32>,
+    ) -> Result<SessionGuard, NotStarted> {
+        let mut running = self.running.lock().unwrap();
+        if config.singleton.is_some() {
+            if let Some(s) = running
+                .iter()
+                .find(|s| s.config == name && (!config.singleton_per_user || s.uid == uid))
+            {
+                return Err(NotStarted::SingletonRunning(s.id));
+            }
+        }
+        let limit = |limit| Err(NotStarted::LimitReached(limit));
+        if settings
+            .max_sessions
+            .is_some_and(|max| running.len() >= max)
+        {
+            return limit(LimitReached::Sessions);
+        }
+        if let (Some(max), Some(uid)) = (settings.max_sessions_per_user, uid) {
+            if running.iter().filter(|s| s.uid == Some(uid)).count() >= max {
+                return limit(LimitReached::SessionsPerUser);
+            }
+        }
+        let instances = running.iter().filter(|s| s.config == name);
+        if config
+            .max_instances
+            .is_some_and(|max| instances.clone().count() >= max)
+        {
+            return limit(LimitReached::Instances);
+        }
+        if let (Some(max), Some(uid)) = (config.max_per_user, uid) {
+            if instances.filter(|s| s.uid == Some(uid)).count() >= max {
+                return limit(LimitReached::InstancesPerUser);
+            }
+        }
+        if let (Some(max), Some(uid)) = (settings.max_runs_per_minute, uid) {
+            let mut recent_runs = self.recent_runs.lock().unwrap();
+            let runs = recent_runs.entry(uid).or_default();
+            while runs
+                .front()
+                .is_some_and(|t| t.elapsed() > Duration::from_secs(60))
+            {
+                runs.pop_front();
+            }
+            if runs.len() >= max {
+                return limit(LimitReached::RunsPerMinute);
+            }
+            runs.push_back(Instant::now());
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        debug!(con = con_id, session = id; "session started ({name}).");
+        running.push(Session {
+            id,
+            con_id,
+            config: name.to_owned(),
+            uid,
+            pid: None,
+        });
+        Ok(SessionGuard {
+            sessions: Arc::clone(self),
+            id,
+        })
+    }
+    /// Registers a new connection, unless the client (`uid`) already has `max-connections` open connections.
+    /// The connection is removed once the returned guard is dropped.
+    pub fn try_connect(
+        self: &Arc<Self>,
+        settings: &Settings,
+        uid: Option<u32>,
+    ) -> Result<ConnectionGuard, LimitReached> {
+        if let Some(uid) = uid {
+            let mut connections = self.connections.lock().unwrap();
+            let count = connections.entry(uid).or_default();
+            if settings.max_connections.is_some_and(|max| *count >= max) {
+                return Err(LimitReached::Connections);
+            }
+            *count += 1;
+        }
+        Ok(ConnectionGuard {
+            sessions: Arc::clone(self),
+            uid,
+        })
+    }
+    /// blocks until the session with this id has ended
+    pub fn wait_for(&self, id: u64) {
+        let mut running = self.running.lock().unwrap();
+        while running.iter().any(|s| s.id == id) {
+            running = self.ended.wait(running).unwrap();
+        }
+    }
+    /// calls `f` for each running session
+    pub fn for_each(&self, mut f: impl FnMut(&Session)) {
+        for session in self.running.lock().unwrap().iter() {
+            f(session);
+        }
+    }
+    /// Sends SIGTERM to the session's main command.
+    pub fn kill(&self, id: u64) -> KillResult {
+        let running = self.running.lock().unwrap();
+        match running.iter().find(|s| s.id == id) {
+            None => KillResult::UnknownSession,
+            Some(Session { pid: None, .. }) => KillResult::NotStarted,
+            Some(Session { pid: Some(pid), .. }) => {
+                if unsafe { libc::kill(*pid as libc::pid_t, libc::SIGTERM) } == 0 {
+                    KillResult::Killed
+                } else {
+                    KillResult::Failed(std::io::Error::last_os_error())
+                }
+            }
+        }
+    }
+}
+
+pub struct SessionGuard {
+    sessions: Arc<Sessions>,
+    id: u64,
+}
+impl SessionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn set_pid(&self, pid: u32) {
+        let mut running = self.sessions.running.lock().unwrap();
+        if let Some(session) = running.iter_mut().find(|s| s.id == self.id) {
+            session.pid = Some(pid);
+        }
+    }
+}
+pub struct ConnectionGuard {
+    sessions: Arc<Sessions>,
+    uid: Option<u32>,
+}
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(uid) = self.uid {
+            let mut connections = self.sessions.connections.lock().unwrap();
+            if let Some(count) = connections.get_mut(&uid) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&uid);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut running = self.sessions.running.lock().unwrap();
+        if let Some(i) = running.iter().position(|s| s.id == self.id) {
+            let session = running.remove(i);
+            self.sessions.ended.notify_all();
+            debug!(
+                con = session.con_id, session = session.id;
+                "session ended ({}).", session.config
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::run::Singleton;
+
+    fn start(
+        sessions: &Arc<Sessions>,
+        settings: &Settings,
+        name: &str,
+        config: &RunCmdBuilder,
+        uid: u32,
+    ) -> Result<SessionGuard, String> {
+        sessions
+            .try_start(settings, 0, name, config, Some(uid))
+            .map_err(|e| match e {
+                NotStarted::LimitReached(limit) => limit.to_string(),
+                NotStarted::SingletonRunning(id) => format!("singleton {id}"),
+            })
+    }
+
+    #[test]
+    fn session_limits_count_running_sessions() {
+        let sessions = Arc::new(Sessions::default());
+        let settings = Settings {
+            max_sessions: Some(3),
+            max_sessions_per_user: Some(2),
+            ..Default::default()
+        };
+        let cfg = RunCmdBuilder::default();
+        let a = start(&sessions, &settings, "a", &cfg, 1000).unwrap();
+        let b = start(&sessions, &settings, "b", &cfg, 1000).unwrap();
+        assert_eq!(
+            start(&sessions, &settings, "a", &cfg, 1000).err().unwrap(),
+            "max-sessions-per-user"
+        );
+        let c = start(&sessions, &settings, "a", &cfg, 1001).unwrap();
+        assert_eq!(
+            start(&sessions, &settings, "a", &cfg, 1002).err().unwrap(),
+            "max-sessions"
+        );
+        // ended sessions don't count
+        drop(a);
+        let a = start(&sessions, &settings, "a", &cfg, 1002).unwrap();
+        drop(b);
+        assert!(start(&sessions, &settings, "b", &cfg, 1000).is_ok());
+        drop((a, c));
+        let mut count = 0;
+        sessions.for_each(|_| count += 1);
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn instance_limits_count_sessions_of_the_config() {
+        let sessions = Arc::new(Sessions::default());
+        let settings = Settings::default();
+        let cfg = RunCmdBuilder {
+            max_instances: Some(2),
+            max_per_user: Some(1),
+            ..Default::default()
+        };
+        let _a = start(&sessions, &settings, "a", &cfg, 1000).unwrap();
+        assert_eq!(
+            start(&sessions, &settings, "a", &cfg, 1000).err().unwrap(),
+            "max-per-user"
+        );
+        let _b = start(&sessions, &settings, "a", &cfg, 1001).unwrap();
+        assert_eq!(
+            start(&sessions, &settings, "a", &cfg, 1002).err().unwrap(),
+            "max-instances"
+        );
+        // other configs have their own count
+        assert!(start(&sessions, &settings, "b", &cfg, 1000).is_ok());
+    }
+
+    #[test]
+    fn runs_per_minute_count_started_sessions() {
+        let sessions = Arc::new(Sessions::default());
+        let settings = Settings {
+            max_runs_per_minute: Some(2),
+            ..Default::default()
+        };
+        let cfg = RunCmdBuilder::default();
+        // unlike the other limits, this one still counts sessions which have ended
+        drop(start(&sessions, &settings, "a", &cfg, 1000).unwrap());
+        drop(start(&sessions, &settings, "a", &cfg, 1000).unwrap());
+        assert_eq!(
+            start(&sessions, &settings, "a", &cfg, 1000).err().unwrap(),
+            "max-runs-per-minute"
+        );
+        assert!(start(&sessions, &settings, "a", &cfg, 1001).is_ok());
+    }
+
+    #[test]
+    fn connection_limit() {
+        let sessions = Arc::new(Sessions::default());
+        let settings = Settings {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let con = sessions.try_connect(&settings, Some(1000)).ok().unwrap();
+        assert!(matches!(
+            sessions.try_connect(&settings, Some(1000)),
+            Err(LimitReached::Connections)
+        ));
+        assert!(sessions.try_connect(&settings, Some(1001)).is_ok());
+        // the uid isn't known, so it can't be limited
+        assert!(sessions.try_connect(&settings, None).is_ok());
+        drop(con);
+        assert!(sessions.try_connect(&settings, Some(1000)).is_ok());
+    }
+
+    #[test]
+    fn singletons() {
+        let sessions = Arc::new(Sessions::default());
+        let settings = Settings {
+            max_sessions: Some(1),
+            ..Default::default()
+        };
+        // whether the server refuses, attaches or reuses, it needs the running session
+        for singleton in [Singleton::Refuse, Singleton::Attach, Singleton::Reuse] {
+            let cfg = RunCmdBuilder {
+                singleton: Some(singleton),
+                ..Default::default()
+            };
+            let running = start(&sessions, &settings, "a", &cfg, 1000).unwrap();
+            // not max-sessions, the running session is the one the client gets
+            assert_eq!(
+                start(&sessions, &settings, "a", &cfg, 1001).err().unwrap(),
+                format!("singleton {}", running.id())
+            );
+        }
+        let cfg = RunCmdBuilder {
+            singleton: Some(Singleton::Refuse),
+            singleton_per_user: true,
+            ..Default::default()
+        };
+        let settings = Settings::default();
+        let running = start(&sessions, &settings, "a", &cfg, 1000).unwrap();
+        assert_eq!(
+            start(&sessions, &settings, "a", &cfg, 1000).err().unwrap(),
+            format!("singleton {}", running.id())
+        );
+        assert!(start(&sessions, &settings, "a", &cfg, 1001).is_ok());
+    }
+
+    #[test]
+    fn attaching_waits_for_the_session_to_end() {
+        let sessions = Arc::new(Sessions::default());
+        let cfg = RunCmdBuilder::default();
+        let running = start(&sessions, &Settings::default(), "a", &cfg, 1000).unwrap();
+        let id = running.id();
+        let ended = Arc::new(AtomicU64::new(0));
+        let waiter = {
+            let (sessions, ended) = (Arc::clone(&sessions), Arc::clone(&ended));
+            thread::spawn(move || {
+                sessions.wait_for(id);
+                ended.load(Ordering::SeqCst)
+            })
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        ended.store(1, Ordering::SeqCst);
+        drop(running);
+        assert_eq!(waiter.join().unwrap(), 1);
+        // the session doesn't exist anymore, so this doesn't block
+        sessions.wait_for(id);
+    }
+}
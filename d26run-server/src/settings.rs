@@ -0,0 +1,362 @@
+//! server-wide settings, read from `/etc/d26run/server.conf` (or the file passed via `--config`).
+//! uses the same line-based syntax as the configs (`name value`, `#` comments).
+
+use std::{fs, time::Duration};
+
+use crate::{
+    logging::{Level, Target},
+    run::VarSyntax,
+    server::RunMode,
+};
+
+pub const SETTINGS_FILE: &str = "/etc/d26run/server.conf";
+
+pub struct Settings {
+    // paths
+    pub configs_dir: String,
+    pub allow_dir: String,
+    /// the first socket is configured by the top-level `socket-*` statements,
+    /// more can be added using `listen <path>` ... `end` blocks.
+    pub sockets: Vec<SocketSettings>,
+    // limits
+    /// how often `reload-configs` can actually cause a reload
+    pub reload_interval: Duration,
+    /// max. number of sessions running at the same time
+    pub max_sessions: Option<usize>,
+    /// max. number of sessions running at the same time, per client uid
+    pub max_sessions_per_user: Option<usize>,
+    /// max. number of open connections, per client uid
+    pub max_connections: Option<usize>,
+    /// max. number of sessions started within a minute, per client uid
+    pub max_runs_per_minute: Option<usize>,
+    // logging
+    pub log_level: Level,
+    pub log_target: Target,
+    /// if set, every request is recorded in this file (see `audit.rs`)
+    pub audit_log: Option<String>,
+    /// if set, connecting to this socket returns the metrics (prometheus text format)
+    pub metrics_socket: Option<String>,
+    // defaults
+    /// used if a client doesn't specify a mode in its `run` request
+    pub default_run_mode: RunMode,
+    /// used by configs without a `var-syntax` statement
+    pub default_var_syntax: VarSyntax,
+}
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            configs_dir: "/etc/d26run/configs/".to_owned(),
+            allow_dir: "/etc/d26run/allow/".to_owned(),
+            sockets: vec![SocketSettings::new("/run/d26run/socket".to_owned())],
+            reload_interval: Duration::from_secs(15),
+            max_sessions: None,
+            max_sessions_per_user: None,
+            max_connections: None,
+            max_runs_per_minute: None,
+            log_level: Level::Info,
+            log_target: Target::Stderr,
+            audit_log: None,
+            metrics_socket: None,
+            default_run_mode: RunMode::Wait,
+            default_var_syntax: VarSyntax::Legacy,
+        }
+    }
+}
+
+pub struct SocketSettings {
+    pub path: String,
+    /// uid the socket will be chowned to (None = keep, which is the server's uid, usually root)
+    pub owner: Option<u32>,
+    /// gid the socket will be chowned to (None = keep)
+    pub group: Option<u32>,
+    pub mode: u32,
+    pub policy: SocketPolicy,
+}
+impl SocketSettings {
+    fn new(path: String) -> Self {
+        Self {
+            path,
+            owner: None,
+            group: None,
+            mode: 0o666,
+            policy: SocketPolicy::default(),
+        }
+    }
+}
+
+/// what a client connected to a certain socket is allowed to do
+pub struct SocketPolicy {
+    /// server commands (like `run` or `reload-configs`) which can be used through this socket
+    pub commands: Vec<String>,
+    /// configs which are visible (`list-configs`) and can be used (`run`, `test-config`) through this socket.
+    /// a trailing `*` matches any suffix. None means all configs.
+    pub configs: Option<Vec<String>>,
+}
+impl Default for SocketPolicy {
+    fn default() -> Self {
+        Self {
+            commands: DEFAULT_COMMANDS.iter().map(|v| v.to_string()).collect(),
+            configs: None,
+        }
+    }
+}
+impl SocketPolicy {
+    pub fn allows_command(&self, command: &str) -> bool {
+        self.commands.iter().any(|c| c == command)
+    }
+    pub fn allows_config(&self, name: &str) -> bool {
+        match &self.configs {
+            None => true,
+            Some(configs) => configs.iter().any(|pat| match pat.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pat,
+            }),
+        }
+    }
+}
+
+/// the commands available on a socket unless its `commands` say otherwise
+const DEFAULT_COMMANDS: &[&str] = &["list-configs", "run", "reload-configs"];
+/// all commands which can be used in `commands`
+pub const ALL_COMMANDS: &[&str] = &[
+    "list-configs",
+    "run",
+    "reload-configs",
+    "list-sessions",
+    "kill-session",
+    "test-config",
+    "metrics",
+    "list-pending",
+    "approve",
+    "deny",
+];
+
+/// Loads the settings from a file. If the file doesn't exist, the defaults are used,
+/// unless `must_exist` is true (the file was explicitly specified).
+/// All errors are collected so they can be reported at once.
+pub fn load(path: &str, must_exist: bool) -> Result<Settings, Vec<SettingsError>> {
+    let mut settings = Settings::default();
+    let file = match fs::read_to_string(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !must_exist => return Ok(settings),
+        Err(e) => return Err(vec![SettingsError::IoError(e)]),
+    };
+    let mut errors = vec![];
+    // set while inside a `listen` block
+    let mut listen: Option<SocketSettings> = None;
+    for (line_nr, line) in file.lines().enumerate() {
+        let line_nr = line_nr + 1;
+        let (left, right) = if let Some((left, right)) = line.split_once(' ') {
+            (left, right)
+        } else {
+            (line, "")
+        };
+        let invalid =
+            |what: &str| SettingsError::InvalidValue(line_nr, what.to_owned(), right.to_owned());
+        if let Some(socket) = &mut listen {
+            // inside a `listen` block
+            match left {
+                s if s.is_empty() || s.starts_with('#') || s.starts_with("//") => (),
+                "end" => settings.sockets.extend(listen.take()),
+                "owner" | "group" | "mode" | "commands" | "configs" => {
+                    if let Err(e) = socket_statement(socket, left, right, line_nr) {
+                        errors.push(e);
+                    }
+                }
+                v => errors.push(SettingsError::UnknownStatement(line_nr, v.to_owned())),
+            }
+            continue;
+        }
+        match left {
+            s if s.is_empty() || s.starts_with('#') || s.starts_with("//") => (),
+            "configs-dir" => settings.configs_dir = right.to_owned(),
+            "allow-dir" => settings.allow_dir = right.to_owned(),
+            "socket-path" => settings.sockets[0].path = right.to_owned(),
+            "socket-owner" | "socket-group" | "socket-mode" | "socket-commands"
+            | "socket-configs" => {
+                if let Err(e) = socket_statement(
+                    &mut settings.sockets[0],
+                    &left["socket-".len()..],
+                    right,
+                    line_nr,
+                ) {
+                    errors.push(e);
+                }
+            }
+            "listen" => {
+                if settings.sockets.iter().any(|s| s.path == right) {
+                    errors.push(invalid("path (there already is a socket with that path)"));
+                }
+                listen = Some(SocketSettings::new(right.to_owned()));
+            }
+            "reload-interval" => match right.parse() {
+                Ok(secs) => settings.reload_interval = Duration::from_secs(secs),
+                Err(_) => errors.push(invalid("number of seconds")),
+            },
+            "max-sessions" => match right.parse() {
+                Ok(max) => settings.max_sessions = Some(max),
+                Err(_) => errors.push(invalid("number")),
+            },
+            "max-sessions-per-user" => match right.parse() {
+                Ok(max) => settings.max_sessions_per_user = Some(max),
+                Err(_) => errors.push(invalid("number")),
+            },
+            "max-connections" => match right.parse() {
+                Ok(max) => settings.max_connections = Some(max),
+                Err(_) => errors.push(invalid("number")),
+            },
+            "max-runs-per-minute" => match right.parse() {
+                Ok(max) => settings.max_runs_per_minute = Some(max),
+                Err(_) => errors.push(invalid("number")),
+            },
+            "log-level" => match Level::parse(right) {
+                Some(level) => settings.log_level = level,
+                None => errors.push(invalid("log level (error, warn, info, debug or trace)")),
+            },
+            "log-target" => match right.split_once(' ') {
+                None if right == "stderr" => settings.log_target = Target::Stderr,
+                None if right == "syslog" => settings.log_target = Target::Syslog,
+                None if right == "journald" => settings.log_target = Target::Journald,
+                Some(("file", path)) => settings.log_target = Target::File(path.to_owned()),
+                _ => errors.push(invalid(
+                    "log target (stderr, file <path>, syslog or journald)",
+                )),
+            },
+            "audit-log" => settings.audit_log = Some(right.to_owned()),
+            "metrics-socket" => settings.metrics_socket = Some(right.to_owned()),
+            "default-run-mode" => match RunMode::parse(right) {
+                Some(mode) => settings.default_run_mode = mode,
+                None => errors.push(invalid(
+                    "run mode (detach, wait, forward-output or forward-output-input)",
+                )),
+            },
+            "default-var-syntax" => match VarSyntax::parse(right) {
+                Some(syntax) => settings.default_var_syntax = syntax,
+                None => errors.push(invalid("var syntax (legacy or braced)")),
+            },
+            v => errors.push(SettingsError::UnknownStatement(line_nr, v.to_owned())),
+        }
+    }
+    if let Some(socket) = listen {
+        errors.push(SettingsError::MissingEnd(socket.path));
+    }
+    if errors.is_empty() {
+        Ok(settings)
+    } else {
+        Err(errors)
+    }
+}
+
+/// statements which configure a socket (`owner`, `group`, `mode`, `commands`, `configs`)
+fn socket_statement(
+    socket: &mut SocketSettings,
+    statement: &str,
+    right: &str,
+    line_nr: usize,
+) -> Result<(), SettingsError> {
+    let invalid =
+        |what: &str| SettingsError::InvalidValue(line_nr, what.to_owned(), right.to_owned());
+    match statement {
+        "owner" => {
+            socket.owner = Some(match right.parse() {
+                Ok(uid) => uid,
+                Err(_) => match users::get_user_by_name(right) {
+                    Some(user) => user.uid(),
+                    None => return Err(SettingsError::UnknownUser(line_nr, right.to_owned())),
+                },
+            })
+        }
+        "group" => {
+            socket.group = Some(match right.parse() {
+                Ok(gid) => gid,
+                Err(_) => match users::get_group_by_name(right) {
+                    Some(group) => group.gid(),
+                    None => return Err(SettingsError::UnknownGroup(line_nr, right.to_owned())),
+                },
+            })
+        }
+        "mode" => match u32::from_str_radix(right, 8) {
+            Ok(mode) if mode <= 0o777 => socket.mode = mode,
+            _ => return Err(invalid("mode (expected octal, like 0660)")),
+        },
+        "commands" => {
+            socket.policy.commands = if right == "all" {
+                ALL_COMMANDS.iter().map(|v| v.to_string()).collect()
+            } else {
+                let commands: Vec<_> = right.split_whitespace().map(|v| v.to_owned()).collect();
+                if let Some(c) = commands
+                    .iter()
+                    .find(|c| !ALL_COMMANDS.contains(&c.as_str()))
+                {
+                    return Err(SettingsError::InvalidValue(
+                        line_nr,
+                        format!("one of {}, or all", ALL_COMMANDS.join(", ")),
+                        c.to_owned(),
+                    ));
+                }
+                commands
+            }
+        }
+        "configs" => {
+            socket.policy.configs = if right == "*" {
+                None
+            } else {
+                Some(right.split_whitespace().map(|v| v.to_owned()).collect())
+            }
+        }
+        _ => unreachable!("not a socket statement"),
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    IoError(std::io::Error),
+    UnknownStatement(usize, String),
+    UnknownUser(usize, String),
+    UnknownGroup(usize, String),
+    /// line, what was expected, value
+    InvalidValue(usize, String, String),
+    /// a `listen` block (for this path) wasn't closed
+    MissingEnd(String),
+}
+impl std::fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "IoError: {e}"),
+            Self::UnknownStatement(l, e) => write!(f, "line {l}: Unknown Statement: '{e}'"),
+            Self::UnknownUser(l, e) => write!(f, "line {l}: unknown user '{e}'"),
+            Self::UnknownGroup(l, e) => write!(f, "line {l}: unknown group '{e}'"),
+            Self::InvalidValue(l, expected, e) => {
+                write!(f, "line {l}: invalid value '{e}', expected {expected}")
+            }
+            Self::MissingEnd(path) => write!(f, "missing 'end' after 'listen {path}'"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_str(text: &str) -> Result<Settings, Vec<SettingsError>> {
+        let path =
+            std::env::temp_dir().join(format!("d26run-settings-test-{}", std::process::id()));
+        fs::write(&path, text).unwrap();
+        let settings = load(path.to_str().unwrap(), true);
+        fs::remove_file(&path).unwrap();
+        settings
+    }
+
+    #[test]
+    fn default_var_syntax_is_global() {
+        let settings = load_str("default-var-syntax braced\nlisten /run/d26run/b\nend\n").unwrap();
+        assert!(matches!(settings.default_var_syntax, VarSyntax::Braced));
+        let errors = load_str("listen /run/d26run/b\ndefault-var-syntax braced\nend\n")
+            .err()
+            .unwrap();
+        assert!(
+            matches!(&errors[..], [SettingsError::UnknownStatement(2, s)] if s == "default-var-syntax")
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! SIGTERM/SIGINT (stop) and SIGHUP (reload) are handled on a dedicated thread using sigwait,
+//! so the handler can do whatever it wants (no async-signal-safety restrictions).
+
+pub enum Signal {
+    Stop,
+    Reload,
+}
+
+/// Blocks the handled signals and spawns a thread which waits for them.
+/// Must be called before any other threads are spawned, because only threads
+/// created after this inherit the signal mask. Child processes inherit it too, see `unblock`.
+pub fn spawn_handler<F: FnMut(Signal) + Send + 'static>(mut handler: F) {
+    let set = handled();
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+    }
+    std::thread::spawn(move || loop {
+        let mut sig = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            continue;
+        }
+        handler(match sig {
+            libc::SIGHUP => Signal::Reload,
+            _ => Signal::Stop,
+        });
+    });
+}
+
+/// Unblocks the handled signals. Used in child processes (before exec),
+/// otherwise they couldn't be stopped using SIGTERM. Async-signal-safe.
+pub fn unblock() {
+    let set = handled();
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, std::ptr::null_mut());
+    }
+}
+
+fn handled() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
@@ -0,0 +1,74 @@
+//! socket activation and readiness notification, see sd_listen_fds(3) and sd_notify(3).
+//! this only implements the (simple) protocols, libsystemd is not required.
+
+use std::{
+    ffi::OsStr,
+    os::{
+        fd::{FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+    },
+};
+
+use crate::logging::warn;
+
+/// the first fd passed by the service manager, the others follow.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the listening sockets passed via `LISTEN_FDS` (if they were meant for this process).
+/// The environment variables are removed so that they won't be inherited by anything we spawn.
+pub fn listen_fds() -> Vec<UnixListener> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    let (Some(pid), Some(fds)) = (pid, fds) else {
+        return vec![];
+    };
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        warn!("LISTEN_PID is set, but not to our pid - ignoring LISTEN_FDS.");
+        return vec![];
+    }
+    let fds: RawFd = match fds.parse() {
+        Ok(v) => v,
+        Err(_) => {
+            warn!("couldn't parse LISTEN_FDS '{fds}' - ignoring it.");
+            return vec![];
+        }
+    };
+    let mut out = Vec::with_capacity(fds.max(0) as usize);
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } != 0
+            || stat.st_mode & libc::S_IFMT != libc::S_IFSOCK
+        {
+            warn!("fd {fd} passed via LISTEN_FDS is not a socket, skipping it.");
+            continue;
+        }
+        // don't leak the socket to child processes
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        out.push(unsafe { UnixListener::from_raw_fd(fd) });
+    }
+    out
+}
+
+/// Sends a state (like `READY=1`) to the service manager. Does nothing if `NOTIFY_SOCKET` isn't set.
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let path = path.as_bytes();
+    let addr = if let Some(abstract_name) = path.strip_prefix(b"@") {
+        SocketAddr::from_abstract_name(abstract_name)
+    } else {
+        SocketAddr::from_pathname(OsStr::from_bytes(path))
+    };
+    let sent = addr.and_then(|addr| UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr));
+    if let Err(e) = sent {
+        warn!("couldn't notify service manager ({state}): {e}");
+    }
+}